@@ -1,27 +1,19 @@
+// A CUDA driver handle. The driver owns the object; the handle is a plain copyable pointer,
+// never released on drop, and whether it may cross threads is declared per type.
 macro_rules! cuda_type {
     ($wrapper_name: ident, $inner_type:ty) => {
         #[allow(non_snake_case)]
         pub mod $wrapper_name {
-            use std::{
-                cell::UnsafeCell,
-                ops::{Deref, DerefMut},
-                rc::Rc,
-            };
-            
-            #[derive(Debug, Clone)]
-            struct Inner(Box<$inner_type>);
-
-            #[derive(Debug, Clone)]
-            pub struct $wrapper_name {
-                inner: Rc<UnsafeCell<Inner>>,
-            }
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct $wrapper_name(*mut $inner_type);
 
             impl $wrapper_name {
                 pub fn new(inner: *mut $inner_type) -> $wrapper_name {
-                    let inner = Rc::new(UnsafeCell::new(Inner(unsafe { Box::from_raw(inner) })));
-                    $wrapper_name {
-                        inner,
-                    }
+                    $wrapper_name(inner)
+                }
+
+                pub fn as_ptr(&self) -> *mut $inner_type {
+                    self.0
                 }
             }
 
@@ -30,25 +22,8 @@ macro_rules! cuda_type {
                     $wrapper_name::new(value)
                 }
             }
-
-            impl Deref for $wrapper_name {
-                type Target = $inner_type;
-                fn deref(&self) -> &Self::Target {
-                    unsafe { &*self.inner.get() }.0.as_ref()
-                }
-            }
-            
-            impl DerefMut for $wrapper_name {
-                fn deref_mut(&mut self) -> &mut Self::Target {
-                    unsafe { &mut *self.inner.get() }.0.as_mut()
-                }
-            }
-
-            unsafe impl Send for $wrapper_name {}
-            unsafe impl Send for Inner {}
-
         }
-    }
+    };
 }
 
-pub(crate)  use cuda_type;
+pub(crate) use cuda_type;
@@ -2,4 +2,13 @@ use crate::macros::cuda_type;
 
 cuda_type!(CuCtx, cuda_sys::CUctx_st);
 cuda_type!(CuEvent, cuda_sys::CUevent_st);
-cuda_type!(CuStream, cuda_sys::CUstream_st);
\ No newline at end of file
+cuda_type!(CuStream, cuda_sys::CUstream_st);
+
+// The driver API is thread safe: a context can be made current on any thread, and streams
+// and events can be recorded, waited on and queried from several threads at once.
+unsafe impl Send for CuCtx::CuCtx {}
+unsafe impl Sync for CuCtx::CuCtx {}
+unsafe impl Send for CuEvent::CuEvent {}
+unsafe impl Sync for CuEvent::CuEvent {}
+unsafe impl Send for CuStream::CuStream {}
+unsafe impl Sync for CuStream::CuStream {}
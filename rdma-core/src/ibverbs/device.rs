@@ -0,0 +1,305 @@
+use std::{
+    ffi::{c_char, CStr},
+    fmt,
+};
+
+use rdma_core_sys::{
+    ibv_device, ibv_device_attr, ibv_gid, ibv_port_attr, IBV_ATOMIC_GLOB, IBV_ATOMIC_HCA,
+    IBV_LINK_LAYER_ETHERNET, IBV_LINK_LAYER_INFINIBAND, IBV_PORT_ACTIVE, IBV_PORT_ACTIVE_DEFER,
+    IBV_PORT_ARMED, IBV_PORT_DOWN, IBV_PORT_INIT,
+};
+
+use crate::{macros::rdma_call, RdmaErrors, Result};
+
+use super::{IbvContext, IbvDeviceList};
+
+/// A device from [`ibv_get_device_list`]; it keeps the list it came from alive.
+#[derive(Debug, Clone)]
+pub struct IbvDevice {
+    _list: IbvDeviceList,
+    device: *mut ibv_device,
+    name: String,
+    guid: u64,
+}
+
+// the list entries are read-only once ibv_get_device_list returns
+unsafe impl Send for IbvDevice {}
+unsafe impl Sync for IbvDevice {}
+
+impl IbvDevice {
+    /// The kernel device name, e.g. `mlx5_0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The node GUID in host byte order.
+    pub fn guid(&self) -> u64 {
+        self.guid
+    }
+
+    pub fn as_ptr(&self) -> *mut ibv_device {
+        self.device
+    }
+}
+
+/// The RDMA devices present on this host.
+pub fn ibv_get_device_list() -> Result<Vec<IbvDevice>> {
+    let mut num_devices = 0;
+    let devices = unsafe { rdma_core_sys::ibv_get_device_list(&mut num_devices) };
+    let list = unsafe { IbvDeviceList::from_raw(devices, None) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_get_device_list".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })?;
+
+    (0..num_devices as usize)
+        .map(|i| {
+            let device = unsafe { *devices.add(i) };
+            let name = unsafe { c_string(rdma_core_sys::ibv_get_device_name(device)) };
+            let guid = u64::from_be(unsafe { rdma_core_sys::ibv_get_device_guid(device) });
+            Ok(IbvDevice {
+                _list: list.clone(),
+                device,
+                name,
+                guid,
+            })
+        })
+        .collect()
+}
+
+pub fn ibv_open_device(device: &IbvDevice) -> Result<IbvContext> {
+    let context = unsafe { rdma_core_sys::ibv_open_device(device.as_ptr()) };
+    // an open context no longer needs the device list
+    unsafe { IbvContext::from_raw(context, None) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_open_device".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+pub fn ibv_query_device(context: &IbvContext) -> Result<DeviceAttr> {
+    let mut attr = ibv_device_attr::default();
+    rdma_call!(
+        ibv_query_device,
+        rdma_core_sys::ibv_query_device(context.as_ptr(), &mut attr),
+        DeviceAttr::from(&attr)
+    )
+}
+
+/// Ports are numbered from 1 to [`DeviceAttr::phys_port_cnt`].
+pub fn ibv_query_port(context: &IbvContext, port_num: u8) -> Result<PortAttr> {
+    let mut attr = ibv_port_attr::default();
+    rdma_call!(
+        ibv_query_port,
+        rdma_core_sys::ibv_query_port(context.as_ptr(), port_num, &mut attr as *mut _ as *mut _),
+        PortAttr::from(&attr)
+    )
+}
+
+pub fn ibv_query_gid(context: &IbvContext, port_num: u8, index: i32) -> Result<Gid> {
+    let mut gid = ibv_gid::default();
+    rdma_call!(
+        ibv_query_gid,
+        rdma_core_sys::ibv_query_gid(context.as_ptr(), port_num, index, &mut gid),
+        Gid(unsafe { gid.raw })
+    )
+}
+
+/// The populated entries of a port's GID table with their indices.
+pub fn ibv_query_gid_table(context: &IbvContext, port_num: u8) -> Result<Vec<(i32, Gid)>> {
+    let port = ibv_query_port(context, port_num)?;
+    let mut table = Vec::new();
+    for index in 0..port.gid_tbl_len as i32 {
+        let gid = ibv_query_gid(context, port_num, index)?;
+        if !gid.is_zero() {
+            table.push((index, gid));
+        }
+    }
+    Ok(table)
+}
+
+unsafe fn c_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicCap {
+    None,
+    /// Atomic among the QPs of this device.
+    Hca,
+    /// Atomic with the CPU and other devices as well.
+    Glob,
+}
+
+/// The limits and identity of a device from `ibv_query_device`.
+#[derive(Debug, Clone)]
+pub struct DeviceAttr {
+    pub fw_ver: String,
+    pub node_guid: u64,
+    pub sys_image_guid: u64,
+    pub max_mr_size: u64,
+    pub page_size_cap: u64,
+    pub vendor_id: u32,
+    pub vendor_part_id: u32,
+    pub hw_ver: u32,
+    pub max_qp: u32,
+    pub max_qp_wr: u32,
+    pub max_sge: u32,
+    pub max_sge_rd: u32,
+    pub max_cq: u32,
+    pub max_cqe: u32,
+    pub max_mr: u32,
+    pub max_pd: u32,
+    pub max_qp_rd_atom: u32,
+    pub max_qp_init_rd_atom: u32,
+    pub max_srq: u32,
+    pub max_srq_wr: u32,
+    pub max_srq_sge: u32,
+    pub atomic_cap: AtomicCap,
+    pub phys_port_cnt: u8,
+}
+
+impl From<&ibv_device_attr> for DeviceAttr {
+    fn from(attr: &ibv_device_attr) -> Self {
+        DeviceAttr {
+            fw_ver: unsafe { c_string(attr.fw_ver.as_ptr()) },
+            node_guid: u64::from_be(attr.node_guid),
+            sys_image_guid: u64::from_be(attr.sys_image_guid),
+            max_mr_size: attr.max_mr_size,
+            page_size_cap: attr.page_size_cap,
+            vendor_id: attr.vendor_id,
+            vendor_part_id: attr.vendor_part_id,
+            hw_ver: attr.hw_ver,
+            max_qp: attr.max_qp as u32,
+            max_qp_wr: attr.max_qp_wr as u32,
+            max_sge: attr.max_sge as u32,
+            max_sge_rd: attr.max_sge_rd as u32,
+            max_cq: attr.max_cq as u32,
+            max_cqe: attr.max_cqe as u32,
+            max_mr: attr.max_mr as u32,
+            max_pd: attr.max_pd as u32,
+            max_qp_rd_atom: attr.max_qp_rd_atom as u32,
+            max_qp_init_rd_atom: attr.max_qp_init_rd_atom as u32,
+            max_srq: attr.max_srq as u32,
+            max_srq_wr: attr.max_srq_wr as u32,
+            max_srq_sge: attr.max_srq_sge as u32,
+            atomic_cap: match attr.atomic_cap {
+                IBV_ATOMIC_HCA => AtomicCap::Hca,
+                IBV_ATOMIC_GLOB => AtomicCap::Glob,
+                _ => AtomicCap::None,
+            },
+            phys_port_cnt: attr.phys_port_cnt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Down,
+    Init,
+    Armed,
+    Active,
+    ActiveDefer,
+    Unknown(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkLayer {
+    InfiniBand,
+    /// RoCE.
+    Ethernet,
+    Unspecified,
+}
+
+/// A path MTU as IB encodes it: 1 is 256 bytes up to 5 for 4096.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mtu(pub u32);
+
+impl Mtu {
+    /// The MTU in bytes, 0 for an invalid encoding.
+    pub fn bytes(&self) -> usize {
+        match self.0 {
+            1..=5 => 128 << self.0,
+            _ => 0,
+        }
+    }
+}
+
+/// The state and addressing of a port from `ibv_query_port`.
+#[derive(Debug, Clone)]
+pub struct PortAttr {
+    pub state: PortState,
+    pub max_mtu: Mtu,
+    pub active_mtu: Mtu,
+    pub link_layer: LinkLayer,
+    pub gid_tbl_len: u32,
+    pub max_msg_sz: u32,
+    pub lid: u16,
+    pub sm_lid: u16,
+    pub active_width: u8,
+    pub active_speed: u8,
+    pub port_cap_flags: u32,
+}
+
+impl From<&ibv_port_attr> for PortAttr {
+    fn from(attr: &ibv_port_attr) -> Self {
+        PortAttr {
+            state: match attr.state {
+                IBV_PORT_DOWN => PortState::Down,
+                IBV_PORT_INIT => PortState::Init,
+                IBV_PORT_ARMED => PortState::Armed,
+                IBV_PORT_ACTIVE => PortState::Active,
+                IBV_PORT_ACTIVE_DEFER => PortState::ActiveDefer,
+                state => PortState::Unknown(state),
+            },
+            max_mtu: Mtu(attr.max_mtu),
+            active_mtu: Mtu(attr.active_mtu),
+            link_layer: match attr.link_layer as u32 {
+                IBV_LINK_LAYER_INFINIBAND => LinkLayer::InfiniBand,
+                IBV_LINK_LAYER_ETHERNET => LinkLayer::Ethernet,
+                _ => LinkLayer::Unspecified,
+            },
+            gid_tbl_len: attr.gid_tbl_len as u32,
+            max_msg_sz: attr.max_msg_sz,
+            lid: attr.lid,
+            sm_lid: attr.sm_lid,
+            active_width: attr.active_width,
+            active_speed: attr.active_speed,
+            port_cap_flags: attr.port_cap_flags,
+        }
+    }
+}
+
+/// A GID table entry, in network byte order.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Gid(pub [u8; 16]);
+
+impl Gid {
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+
+    pub fn subnet_prefix(&self) -> u64 {
+        u64::from_be_bytes(self.0[..8].try_into().unwrap())
+    }
+
+    pub fn interface_id(&self) -> u64 {
+        u64::from_be_bytes(self.0[8..].try_into().unwrap())
+    }
+}
+
+// formatted like an IPv6 address, which is what a RoCE GID is
+impl fmt::Display for Gid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        std::net::Ipv6Addr::from(self.0).fmt(f)
+    }
+}
+
+impl fmt::Debug for Gid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gid({})", self)
+    }
+}
@@ -1,10 +1,22 @@
+mod device;
+mod ud;
 mod verbs;
 mod types;
 
 pub use verbs::{
-    ibv_modify_qp, ibv_poll_cq, ibv_post_recv, ibv_post_send, ibv_query_qp, ibv_reg_mr, ibv_dereg_mr
+    ibv_ack_cq_events, ibv_create_ah, ibv_create_comp_channel, ibv_create_cq, ibv_create_qp,
+    ibv_create_srq, ibv_get_cq_event,
+    ibv_modify_qp, ibv_poll_cq, ibv_post_recv, ibv_post_send, ibv_post_srq_recv, ibv_query_qp,
+    ibv_reg_mr, ibv_reg_mr_read_only, ibv_dereg_mr, ibv_req_notify_cq,
 };
 
+pub use device::{
+    ibv_get_device_list, ibv_open_device, ibv_query_device, ibv_query_gid, ibv_query_gid_table,
+    ibv_query_port, AtomicCap, DeviceAttr, Gid, IbvDevice, LinkLayer, Mtu, PortAttr, PortState,
+};
+
+pub use ud::{ibv_init_ud_qp, ud_ah_attr, ud_send_wr, GRH_SIZE};
+
 pub use types::{
-    IbvPd::IbvPd, IbvQpInitAttr::IbvQpInitAttr, IbvMr::IbvMr, IbvQp::IbvQp,
+    IbvAh::IbvAh, IbvCompChannel::IbvCompChannel, IbvContext::IbvContext, IbvCq::IbvCq, IbvDeviceList::IbvDeviceList, IbvPd::IbvPd, IbvQpAttr::IbvQpAttr, IbvQpInitAttr::IbvQpInitAttr, IbvMr::IbvMr, IbvQp::IbvQp, IbvSrq::IbvSrq,
 };
\ No newline at end of file
@@ -1,8 +1,73 @@
-use crate::rdma_type;
+use crate::{rdma_handle, rdma_type};
 
-rdma_type!(IbvPd, rdma_core_sys::ibv_pd);
-rdma_type!(IbvMr, rdma_core_sys::ibv_mr);
+rdma_handle!(
+    IbvDeviceList,
+    *mut rdma_core_sys::ibv_device,
+    rdma_core_sys::ibv_free_device_list
+);
+rdma_handle!(
+    IbvContext,
+    rdma_core_sys::ibv_context,
+    rdma_core_sys::ibv_close_device
+);
+rdma_handle!(IbvPd, rdma_core_sys::ibv_pd, rdma_core_sys::ibv_dealloc_pd);
+rdma_handle!(IbvMr, rdma_core_sys::ibv_mr, rdma_core_sys::ibv_dereg_mr);
+rdma_handle!(
+    IbvCompChannel,
+    rdma_core_sys::ibv_comp_channel,
+    rdma_core_sys::ibv_destroy_comp_channel
+);
+rdma_handle!(IbvCq, rdma_core_sys::ibv_cq, rdma_core_sys::ibv_destroy_cq);
+rdma_handle!(IbvSrq, rdma_core_sys::ibv_srq, rdma_core_sys::ibv_destroy_srq);
+rdma_handle!(IbvAh, rdma_core_sys::ibv_ah, rdma_core_sys::ibv_destroy_ah);
 
-rdma_type!(IbvQp, rdma_core_sys::ibv_qp);
+rdma_handle!(IbvQp, rdma_core_sys::ibv_qp, rdma_core_sys::ibv_destroy_qp);
 rdma_type!(IbvQpAttr, rdma_core_sys::ibv_qp_attr);
 rdma_type!(IbvQpInitAttr, rdma_core_sys::ibv_qp_init_attr);
+
+// only read once ibv_get_device_list returns
+unsafe impl Send for IbvDeviceList::IbvDeviceList {}
+unsafe impl Sync for IbvDeviceList::IbvDeviceList {}
+
+// the verbs called on a context (queries, allocating PDs and CQs) are thread safe
+unsafe impl Send for IbvContext::IbvContext {}
+unsafe impl Sync for IbvContext::IbvContext {}
+
+// registering/deregistering MRs on a PD is thread safe, and a PD is never modified after
+// allocation
+unsafe impl Send for IbvPd::IbvPd {}
+unsafe impl Sync for IbvPd::IbvPd {}
+
+// lkey/rkey/addr are fixed at registration; only the last owner deregisters it
+unsafe impl Send for IbvMr::IbvMr {}
+unsafe impl Sync for IbvMr::IbvMr {}
+
+// a channel is only read through its fd, by whoever polls the CQs reporting to it
+unsafe impl Send for IbvCompChannel::IbvCompChannel {}
+unsafe impl Sync for IbvCompChannel::IbvCompChannel {}
+
+// polling and arming a CQ from two threads at once races on the channel events; the handle
+// only moves the CQ between threads, callers keep a single poller
+unsafe impl Send for IbvCq::IbvCq {}
+unsafe impl Sync for IbvCq::IbvCq {}
+
+// ibv_post_srq_recv locks the SRQ inside the provider, like ibv_post_recv does a QP's
+unsafe impl Send for IbvSrq::IbvSrq {}
+unsafe impl Sync for IbvSrq::IbvSrq {}
+
+// an AH is never modified after creation, and any number of sends may reference it at once
+unsafe impl Send for IbvAh::IbvAh {}
+unsafe impl Sync for IbvAh::IbvAh {}
+
+// ibv_post_send/ibv_post_recv lock the send/receive queue inside the provider, so posting
+// from several threads at once is safe. Modifying the QP state is not, and is only done
+// while a connection is being set up.
+unsafe impl Send for IbvQp::IbvQp {}
+unsafe impl Sync for IbvQp::IbvQp {}
+
+impl IbvPd::IbvPd {
+    /// The device context the PD was allocated on, kept open for as long as the PD is.
+    pub fn context(&self) -> Option<IbvContext::IbvContext> {
+        unsafe { IbvContext::IbvContext::borrowed(self.context, self.keep_alive()) }
+    }
+}
@@ -0,0 +1,80 @@
+use std::mem::size_of;
+
+use rdma_core_sys::{
+    ibv_ah_attr, ibv_gid, ibv_global_route, ibv_grh, ibv_qp_attr, ibv_send_wr,
+    ibv_send_wr__bindgen_ty_2__bindgen_ty_3, IBV_QPS_INIT, IBV_QPS_RTR, IBV_QPS_RTS,
+    IBV_QP_PKEY_INDEX, IBV_QP_PORT, IBV_QP_QKEY, IBV_QP_SQ_PSN, IBV_QP_STATE, IBV_WR_SEND,
+};
+
+use crate::Result;
+
+use super::{ibv_modify_qp, Gid, IbvAh, IbvQp};
+
+/// What a UD receive buffer has to leave free ahead of the payload: the GRH lands there when
+/// the datagram carried one, and the space is consumed either way. On RoCE v2 over IPv4 its
+/// last 20 bytes hold the IPv4 header instead.
+pub const GRH_SIZE: usize = size_of::<ibv_grh>();
+
+/// Takes a new UD QP from RESET to RTS on `port_num`. It only accepts datagrams sent with
+/// `qkey`.
+pub fn ibv_init_ud_qp(qp: &IbvQp, port_num: u8, qkey: u32) -> Result<()> {
+    let mut attr = ibv_qp_attr {
+        qp_state: IBV_QPS_INIT,
+        pkey_index: 0,
+        port_num,
+        qkey,
+        ..Default::default()
+    };
+    ibv_modify_qp(
+        qp.as_ptr(),
+        &mut attr,
+        (IBV_QP_STATE | IBV_QP_PKEY_INDEX | IBV_QP_PORT | IBV_QP_QKEY) as i32,
+    )?;
+
+    attr.qp_state = IBV_QPS_RTR;
+    ibv_modify_qp(qp.as_ptr(), &mut attr, IBV_QP_STATE as i32)?;
+
+    attr.qp_state = IBV_QPS_RTS;
+    attr.sq_psn = 0;
+    ibv_modify_qp(
+        qp.as_ptr(),
+        &mut attr,
+        (IBV_QP_STATE | IBV_QP_SQ_PSN) as i32,
+    )
+}
+
+/// Address handle attributes for the port `dlid` on the local `port_num`. With `global`, the
+/// datagram carries a GRH to `dgid` from the local GID at `sgid_index`, which RoCE always needs
+/// and InfiniBand needs to leave the subnet.
+pub fn ud_ah_attr(port_num: u8, dlid: u16, global: Option<(Gid, u8)>) -> ibv_ah_attr {
+    let mut attr = ibv_ah_attr {
+        dlid,
+        port_num,
+        ..Default::default()
+    };
+    if let Some((dgid, sgid_index)) = global {
+        attr.is_global = 1;
+        attr.grh = ibv_global_route {
+            dgid: ibv_gid { raw: dgid.0 },
+            sgid_index,
+            hop_limit: 64,
+            ..Default::default()
+        };
+    }
+    attr
+}
+
+/// A SEND to the UD QP `remote_qpn` through `ah`. The caller fills in the scatter list, `wr_id`
+/// and send flags, and keeps `ah` alive until the send completes.
+pub fn ud_send_wr(ah: &IbvAh, remote_qpn: u32, remote_qkey: u32) -> ibv_send_wr {
+    let mut wr = ibv_send_wr {
+        opcode: IBV_WR_SEND,
+        ..Default::default()
+    };
+    wr.wr.ud = ibv_send_wr__bindgen_ty_2__bindgen_ty_3 {
+        ah: ah.as_ptr(),
+        remote_qpn,
+        remote_qkey,
+    };
+    wr
+}
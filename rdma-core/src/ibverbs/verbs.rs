@@ -1,32 +1,46 @@
-use std::{ffi::c_void, ops::DerefMut, ptr::null_mut};
+use std::{any::Any, ffi::c_void, ops::DerefMut, ptr::null_mut, sync::Arc};
 
 use rdma_core_sys::{
-    ibv_cq, ibv_pd, ibv_qp, ibv_qp_attr, ibv_qp_init_attr, ibv_recv_wr, ibv_send_wr, ibv_wc,
+    ibv_ah_attr, ibv_comp_channel, ibv_cq, ibv_qp, ibv_qp_attr, ibv_qp_init_attr, ibv_recv_wr,
+    ibv_send_wr, ibv_srq, ibv_srq_attr, ibv_srq_init_attr, ibv_wc, IBV_ACCESS_LOCAL_WRITE,
+    IBV_ACCESS_REMOTE_ATOMIC, IBV_ACCESS_REMOTE_WRITE,
 };
 
 use crate::{macros::rdma_call, RdmaErrors, Result};
 
-use super::IbvMr;
+use super::{IbvAh, IbvCompChannel, IbvCq, IbvMr, IbvPd, IbvQp, IbvQpInitAttr, IbvSrq};
 
 pub fn ibv_poll_cq(cq: *mut ibv_cq, num_entries: i32, wc: &mut ibv_wc) -> Result<i32> {
-    let entries = unsafe {
-        let ops = (*(*cq).context).ops;
-        let poll_cq = ops
-            .poll_cq
-            .ok_or(RdmaErrors::OpsNotFound("ibv_poll_cq".to_string()))?;
-        loop {
-            let ret = poll_cq(cq, num_entries, wc);
-            if ret != 0 {
-                break ret;
-            }
-        }
-    };
+    let poll_cq = unsafe { (*(*cq).context).ops.poll_cq }
+        .ok_or(RdmaErrors::OpsNotFound("ibv_poll_cq".to_string()))?;
 
-    return if entries > 0 {
+    let entries = unsafe { poll_cq(cq, num_entries, wc) };
+    if entries >= 0 {
         Ok(entries)
     } else {
         Err(RdmaErrors::OpsFailed("ibv_poll_cq".to_string(), entries))
-    };
+    }
+}
+
+pub fn ibv_req_notify_cq(cq: *mut ibv_cq, solicited_only: bool) -> Result<()> {
+    let req_notify_cq = unsafe { (*(*cq).context).ops.req_notify_cq }
+        .ok_or(RdmaErrors::OpsNotFound("ibv_req_notify_cq".to_string()))?;
+
+    rdma_call!(ibv_req_notify_cq, req_notify_cq(cq, solicited_only as i32))
+}
+
+pub fn ibv_get_cq_event(channel: *mut ibv_comp_channel) -> Result<*mut ibv_cq> {
+    let mut cq = null_mut();
+    let mut cq_context = null_mut();
+    rdma_call!(
+        ibv_get_cq_event,
+        rdma_core_sys::ibv_get_cq_event(channel, &mut cq, &mut cq_context),
+        cq
+    )
+}
+
+pub fn ibv_ack_cq_events(cq: *mut ibv_cq, nevents: u32) {
+    unsafe { rdma_core_sys::ibv_ack_cq_events(cq, nevents) }
 }
 
 pub fn ibv_post_recv(
@@ -40,6 +54,17 @@ pub fn ibv_post_recv(
     rdma_call!(ibv_post_recv, post_recv(qp, wr, bad))
 }
 
+pub fn ibv_post_srq_recv(
+    srq: *mut ibv_srq,
+    wr: *mut ibv_recv_wr,
+    bad: *mut *mut ibv_recv_wr,
+) -> Result<()> {
+    let post_srq_recv = unsafe { (*(*srq).context).ops.post_srq_recv }
+        .ok_or(RdmaErrors::OpsNotFound("ibv_post_srq_recv".to_string()))?;
+
+    rdma_call!(ibv_post_srq_recv, post_srq_recv(srq, wr, bad))
+}
+
 pub fn ibv_post_send(
     qp: *mut ibv_qp,
     wr: *mut ibv_send_wr,
@@ -57,7 +82,10 @@ pub fn ibv_query_qp(
     attr_mask: i32,
     init_attr: Option<*mut ibv_qp_init_attr>,
 ) -> Result<()> {
-    let init_attr = init_attr.unwrap_or(&mut ibv_qp_init_attr::default());
+    // the call writes the init attributes even when the caller does not want them, so they
+    // need a place that outlives it
+    let mut unwanted = ibv_qp_init_attr::default();
+    let init_attr = init_attr.unwrap_or(&mut unwanted);
 
     rdma_call!(
         ibv_query_qp,
@@ -72,20 +100,112 @@ pub fn ibv_modify_qp(qp: *mut ibv_qp, attr: *mut ibv_qp_attr, attr_mask: i32) ->
     )
 }
 
-pub fn ibv_reg_mr(pd: *mut ibv_pd, buffer: &mut [u8], access: i32) -> Result<IbvMr> {
+pub fn ibv_reg_mr(pd: &IbvPd, buffer: &mut [u8], access: i32) -> Result<IbvMr> {
     let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
-    let mr = unsafe { rdma_core_sys::ibv_reg_mr(pd, buffer_ptr, buffer.len(), access) };
-    if mr != null_mut() {
-        Ok(mr.into())
-    } else {
-        Err(RdmaErrors::OpsFailed("ibv_reg_mr".to_string(), -1))
-    }
+    let mr = unsafe { rdma_core_sys::ibv_reg_mr(pd.as_ptr(), buffer_ptr, buffer.len(), access) };
+    // the MR keeps its PD alive
+    unsafe { IbvMr::from_raw(mr, Some(pd.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_reg_mr".to_string(), unsafe { *libc::__errno_location() })
+    })
 }
 
-pub fn ibv_dereg_mr(mr: &mut IbvMr) -> Result<()> {
-    let mr = mr.deref_mut() as *mut _;
-    if mr == null_mut() {
-        return Ok(());
+/// Registers memory the HCA only reads from, e.g. for the peer's RDMA reads, so it can be
+/// shared rather than exclusively borrowed. `access` must not allow any writes.
+pub fn ibv_reg_mr_read_only(pd: &IbvPd, buffer: &[u8], access: i32) -> Result<IbvMr> {
+    let writes = IBV_ACCESS_LOCAL_WRITE | IBV_ACCESS_REMOTE_WRITE | IBV_ACCESS_REMOTE_ATOMIC;
+    if access as u32 & writes != 0 {
+        return Err(RdmaErrors::OpsFailed(
+            "ibv_reg_mr_read_only".to_string(),
+            libc::EINVAL,
+        ));
     }
-    rdma_call!(ibv_dereg_mr, rdma_core_sys::ibv_dereg_mr(mr))
+    let buffer_ptr = buffer.as_ptr() as *mut c_void;
+    let mr = unsafe { rdma_core_sys::ibv_reg_mr(pd.as_ptr(), buffer_ptr, buffer.len(), access) };
+    unsafe { IbvMr::from_raw(mr, Some(pd.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_reg_mr_read_only".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+/// Deregisters `mr` now rather than when its last clone is dropped, reporting any failure.
+pub fn ibv_dereg_mr(mr: IbvMr) -> Result<()> {
+    // the PD outlives the deregistration
+    mr.release_with(|mr| rdma_call!(ibv_dereg_mr, rdma_core_sys::ibv_dereg_mr(mr)))
+        // still shared, the last clone deregisters it
+        .unwrap_or(Ok(()))
+}
+
+/// A completion channel on the device `pd` was allocated on; it keeps the PD alive.
+pub fn ibv_create_comp_channel(pd: &IbvPd) -> Result<IbvCompChannel> {
+    let channel = unsafe { rdma_core_sys::ibv_create_comp_channel(pd.context) };
+    unsafe { IbvCompChannel::from_raw(channel, Some(pd.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_create_comp_channel".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+/// A CQ of at least `cqe` entries whose events arrive on `channel`, which it keeps alive.
+pub fn ibv_create_cq(channel: &IbvCompChannel, cqe: i32) -> Result<IbvCq> {
+    let cq = unsafe {
+        rdma_core_sys::ibv_create_cq(channel.context, cqe, null_mut(), channel.as_ptr(), 0)
+    };
+    unsafe { IbvCq::from_raw(cq, Some(channel.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_create_cq".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+/// An SRQ on `pd` holding up to `max_wr` receives of `max_sge` entries each. QPs created with
+/// it take their receives from it instead of a receive queue of their own.
+pub fn ibv_create_srq(pd: &IbvPd, max_wr: u32, max_sge: u32) -> Result<IbvSrq> {
+    let mut init_attr = ibv_srq_init_attr {
+        attr: ibv_srq_attr {
+            max_wr,
+            max_sge,
+            srq_limit: 0,
+        },
+        ..Default::default()
+    };
+    let srq = unsafe { rdma_core_sys::ibv_create_srq(pd.as_ptr(), &mut init_attr) };
+    unsafe { IbvSrq::from_raw(srq, Some(pd.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_create_srq".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+/// A QP on `pd` completing to `send_cq` and `recv_cq`, for QPs not created through rdma_cm.
+/// The CQs in `init_attr` are set from the arguments, and the QP keeps them and the PD alive.
+pub fn ibv_create_qp(
+    pd: &IbvPd,
+    send_cq: &IbvCq,
+    recv_cq: &IbvCq,
+    init_attr: &mut IbvQpInitAttr,
+) -> Result<IbvQp> {
+    init_attr.send_cq = send_cq.as_ptr();
+    init_attr.recv_cq = recv_cq.as_ptr();
+    let qp = unsafe { rdma_core_sys::ibv_create_qp(pd.as_ptr(), init_attr.deref_mut()) };
+    // the same atomically counted link every handle keeps to its parent
+    #[allow(clippy::arc_with_non_send_sync)]
+    let parent: Arc<dyn Any> =
+        Arc::new((pd.keep_alive(), send_cq.keep_alive(), recv_cq.keep_alive()));
+    unsafe { IbvQp::from_raw(qp, Some(parent)) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_create_qp".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
+}
+
+/// An address handle on `pd` for sending datagrams to the destination in `attr`.
+pub fn ibv_create_ah(pd: &IbvPd, attr: &ibv_ah_attr) -> Result<IbvAh> {
+    let mut attr = *attr;
+    let ah = unsafe { rdma_core_sys::ibv_create_ah(pd.as_ptr(), &mut attr) };
+    unsafe { IbvAh::from_raw(ah, Some(pd.keep_alive())) }.ok_or_else(|| {
+        RdmaErrors::OpsFailed("ibv_create_ah".to_string(), unsafe {
+            *libc::__errno_location()
+        })
+    })
 }
@@ -6,4 +6,4 @@ pub mod ibverbs;
 pub mod rdma;
 
 pub use errors::{RdmaErrors, Result};
-pub(crate) use macros::{rdma_call, rdma_type};
+pub(crate) use macros::{rdma_call, rdma_handle, rdma_type};
@@ -2,4 +2,4 @@ mod funcs;
 mod types;
 
 pub(crate) use funcs::rdma_call;
-pub(crate) use types::rdma_type;
+pub(crate) use types::{rdma_handle, rdma_type};
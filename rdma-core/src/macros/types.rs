@@ -1,62 +1,163 @@
+// plain attribute structs filled in on the Rust side and passed to rdma-core by pointer;
+// objects allocated by rdma-core go through rdma_handle! instead
 macro_rules! rdma_type {
     ($wrapper_name: ident, $inner_type:ty) => {
+        #[allow(non_snake_case)]
+        pub mod $wrapper_name {
+            use std::ops::{Deref, DerefMut};
+
+            #[derive(Debug, Default, Clone)]
+            pub struct $wrapper_name(Box<$inner_type>);
+
+            impl Deref for $wrapper_name {
+                type Target = $inner_type;
+                fn deref(&self) -> &Self::Target {
+                    &self.0
+                }
+            }
+
+            impl DerefMut for $wrapper_name {
+                fn deref_mut(&mut self) -> &mut Self::Target {
+                    &mut self.0
+                }
+            }
+
+            // each wrapper owns its copy of the attributes; the pointers inside them (CQs,
+            // SRQs, ...) are plain values that are never dereferenced through it
+            unsafe impl Send for $wrapper_name {}
+            unsafe impl Sync for $wrapper_name {}
+        }
+    };
+}
+
+pub(crate)  use rdma_type;
+
+macro_rules! rdma_handle {
+    ($wrapper_name: ident, $inner_type:ty, $destroy:path) => {
         #[allow(non_snake_case)]
         pub mod $wrapper_name {
             use std::{
-                cell::UnsafeCell,
-                ops::{Deref, DerefMut},
-                rc::Rc,
+                any::Any,
+                ops::Deref,
+                ptr::NonNull,
+                sync::Arc,
             };
-            
-            #[derive(Default)]
-            struct Inner(Box<$inner_type>);
 
-            #[derive(Debug, Clone)]
-            pub struct $wrapper_name {
-                inner: Rc<UnsafeCell<Inner>>,
+            // refcounted atomically: the parent links are cloned and dropped from whichever
+            // runtime worker ends up owning a handle
+            struct Raw {
+                ptr: NonNull<$inner_type>,
+                owned: bool,
+                // a handle this one was created from, kept alive until this one is released
+                parent: Option<Arc<dyn Any>>,
             }
 
-            impl $wrapper_name {
-                pub fn new(inner: *mut $inner_type) -> $wrapper_name {
-                    let inner = Rc::new(UnsafeCell::new(Inner(unsafe { Box::from_raw(inner) })));
-                    $wrapper_name {
-                        inner,
+            impl Drop for Raw {
+                fn drop(&mut self) {
+                    if self.owned {
+                        let _ = unsafe { $destroy(self.ptr.as_ptr()) };
                     }
                 }
             }
 
-            impl From<*mut $inner_type> for $wrapper_name {
-                fn from(value: *mut $inner_type) -> Self {
-                    $wrapper_name::new(value)
-                }
+            /// An rdma-core object released with
+            #[doc = concat!("`", stringify!($destroy), "`")]
+            /// once the last clone of this handle and of every handle created from it is dropped.
+            ///
+            /// Clones share the object, so it is only ever handed out by `&` or as a raw pointer.
+            /// Whether it may cross threads depends on the object and is declared next to each
+            /// `rdma_handle!` use.
+            #[derive(Clone)]
+            pub struct $wrapper_name {
+                raw: Arc<Raw>,
             }
 
-            impl Deref for $wrapper_name {
-                type Target = $inner_type;
-                fn deref(&self) -> &Self::Target {
-                    unsafe { &*self.inner.get() }.0.as_ref()
+            impl $wrapper_name {
+                /// Takes ownership of `ptr`; `parent` is kept alive for as long as the handle.
+                ///
+                /// # Safety
+                /// `ptr` must come from the rdma-core call that pairs with the destructor and must
+                /// not be released anywhere else.
+                pub unsafe fn from_raw(
+                    ptr: *mut $inner_type,
+                    parent: Option<Arc<dyn Any>>,
+                ) -> Option<$wrapper_name> {
+                    Self::wrap(ptr, true, parent)
                 }
-            }
-            
-            impl DerefMut for $wrapper_name {
-                fn deref_mut(&mut self) -> &mut Self::Target {
-                    unsafe { &mut *self.inner.get() }.0.as_mut()
+
+                /// Wraps `ptr` without ever releasing it, for objects owned by `parent`.
+                ///
+                /// # Safety
+                /// `ptr` must stay valid for as long as `parent` does.
+                pub unsafe fn borrowed(
+                    ptr: *mut $inner_type,
+                    parent: Arc<dyn Any>,
+                ) -> Option<$wrapper_name> {
+                    Self::wrap(ptr, false, Some(parent))
+                }
+
+                fn wrap(
+                    ptr: *mut $inner_type,
+                    owned: bool,
+                    parent: Option<Arc<dyn Any>>,
+                ) -> Option<$wrapper_name> {
+                    let ptr = NonNull::new(ptr)?;
+                    Some($wrapper_name {
+                        raw: Arc::new(Raw {
+                            ptr,
+                            owned,
+                            parent,
+                        }),
+                    })
+                }
+
+                /// Releases the object now through `release` instead of on drop, unless another
+                /// clone or a child handle still holds it. What the handle keeps alive is only
+                /// let go once `release` has returned, since the object may need it until then.
+                pub fn release_with<R>(
+                    self,
+                    release: impl FnOnce(*mut $inner_type) -> R,
+                ) -> Option<R> {
+                    let mut raw = Arc::try_unwrap(self.raw).ok()?;
+                    raw.owned = false;
+                    let released = release(raw.ptr.as_ptr());
+                    drop(raw);
+                    Some(released)
+                }
+
+                pub fn as_ptr(&self) -> *mut $inner_type {
+                    self.raw.ptr.as_ptr()
+                }
+
+                #[allow(dead_code)]
+                pub(crate) fn keep_alive(&self) -> Arc<dyn Any> {
+                    self.raw.clone()
+                }
+
+                /// What this handle keeps alive, for handles that depend on the same objects.
+                #[allow(dead_code)]
+                pub(crate) fn parent(&self) -> Option<Arc<dyn Any>> {
+                    self.raw.parent.clone()
                 }
             }
 
-            impl Default for $wrapper_name {
-                fn default() -> $wrapper_name {
-                    $wrapper_name{
-                        inner: Rc::new(UnsafeCell::new(Default::default()))
-                    }
+            impl std::fmt::Debug for $wrapper_name {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    f.debug_tuple(stringify!($wrapper_name))
+                        .field(&self.raw.ptr)
+                        .finish()
                 }
             }
 
-            unsafe impl Send for $wrapper_name {}
-            unsafe impl Send for Inner {}
+            impl Deref for $wrapper_name {
+                type Target = $inner_type;
+                fn deref(&self) -> &Self::Target {
+                    unsafe { self.raw.ptr.as_ref() }
+                }
+            }
 
         }
-    }
+    };
 }
 
-pub(crate)  use rdma_type;
+pub(crate) use rdma_handle;
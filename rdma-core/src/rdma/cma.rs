@@ -1,16 +1,18 @@
 use std::{
     ffi::CString,
-    ops::{Deref, DerefMut},
-    ptr::null_mut,
+    ops::DerefMut,
+    ptr::{null, null_mut},
 };
 
+use rdma_core_sys::rdma_addrinfo;
+
 use crate::{
     ibverbs::{IbvPd, IbvQpInitAttr},
     rdma::{RdmaAddrInfo, RdmaCmId, RdmaConnParam},
     rdma_call, RdmaErrors, Result,
 };
 
-pub fn rdma_getaddrinfo(node: &str, service: &str, hints: &RdmaAddrInfo) -> Result<RdmaAddrInfo> {
+pub fn rdma_getaddrinfo(node: &str, service: &str, hints: &rdma_addrinfo) -> Result<RdmaAddrInfo> {
     let mut addr_info = null_mut();
     let node = CString::new(node).map_err(|_| RdmaErrors::InvalidAddress(node.to_string()))?;
     let service =
@@ -18,38 +20,37 @@ pub fn rdma_getaddrinfo(node: &str, service: &str, hints: &RdmaAddrInfo) -> Resu
 
     rdma_call!(
         rdma_getaddrinfo,
-        rdma_core_sys::rdma_getaddrinfo(
-            node.as_ptr(),
-            service.as_ptr(),
-            hints.deref(),
-            &mut addr_info
-        ),
-        addr_info.into()
-    )
+        rdma_core_sys::rdma_getaddrinfo(node.as_ptr(), service.as_ptr(), hints, &mut addr_info)
+    )?;
+    unsafe { RdmaAddrInfo::from_raw(addr_info, None) }
+        .ok_or_else(|| RdmaErrors::OpsFailed("rdma_getaddrinfo".to_string(), libc::EINVAL))
 }
 
 pub fn rdma_create_ep(
-    addr_info: &mut RdmaAddrInfo,
-    pd: Option<&mut IbvPd>,
+    addr_info: &RdmaAddrInfo,
+    pd: Option<&IbvPd>,
     qp_init_attr: Option<&mut IbvQpInitAttr>,
 ) -> Result<RdmaCmId> {
     let mut listen_id = null_mut();
-    let pd = pd.map(|v| v.deref_mut() as *mut _).unwrap_or(null_mut());
+    // a QP created on a caller supplied PD keeps that PD alive
+    let parent = pd.map(|v| v.keep_alive());
+    let pd = pd.map(|v| v.as_ptr()).unwrap_or(null_mut());
     let qp_init_attr = qp_init_attr
         .map(|v| v.deref_mut() as *mut _)
         .unwrap_or(null_mut());
 
     rdma_call!(
         rdma_create_ep,
-        rdma_core_sys::rdma_create_ep(&mut listen_id, addr_info.deref_mut(), pd, qp_init_attr),
-        listen_id.into()
-    )
+        rdma_core_sys::rdma_create_ep(&mut listen_id, addr_info.as_ptr(), pd, qp_init_attr)
+    )?;
+    unsafe { RdmaCmId::from_raw(listen_id, parent) }
+        .ok_or_else(|| RdmaErrors::OpsFailed("rdma_create_ep".to_string(), libc::EINVAL))
 }
 
 pub fn rdma_listen(id: &mut RdmaCmId, backlog: i32) -> Result<()> {
     rdma_call!(
         rdma_listen,
-        rdma_core_sys::rdma_listen(id.deref_mut(), backlog)
+        rdma_core_sys::rdma_listen(id.as_ptr(), backlog)
     )
 }
 
@@ -57,9 +58,13 @@ pub fn rdma_get_request(listen: &mut RdmaCmId) -> Result<RdmaCmId> {
     let mut id = null_mut();
     rdma_call!(
         rdma_get_request,
-        rdma_core_sys::rdma_get_request(listen.deref_mut(), &mut id),
-        id.into()
-    )
+        rdma_core_sys::rdma_get_request(listen.as_ptr(), &mut id)
+    )?;
+    // a sync listener migrates each request to its own channel, so the new id does not
+    // depend on the listener; its QP is created on the listener's PD, though, so it keeps
+    // whatever PD the listener was given alive
+    unsafe { RdmaCmId::from_raw(id, listen.parent()) }
+        .ok_or_else(|| RdmaErrors::OpsFailed("rdma_get_request".to_string(), libc::EINVAL))
 }
 
 pub fn rdma_accept(id: &mut RdmaCmId, conn_param: Option<&mut RdmaConnParam>) -> Result<()> {
@@ -69,7 +74,18 @@ pub fn rdma_accept(id: &mut RdmaCmId, conn_param: Option<&mut RdmaConnParam>) ->
 
     rdma_call!(
         rdma_accept,
-        rdma_core_sys::rdma_accept(id.deref_mut(), conn_param)
+        rdma_core_sys::rdma_accept(id.as_ptr(), conn_param)
+    )
+}
+
+pub fn rdma_reject(id: &mut RdmaCmId, private_data: Option<&[u8]>) -> Result<()> {
+    let (data, len) = private_data
+        .map(|v| (v.as_ptr() as *const _, v.len() as u8))
+        .unwrap_or((null(), 0));
+
+    rdma_call!(
+        rdma_reject,
+        rdma_core_sys::rdma_reject(id.as_ptr(), data, len)
     )
 }
 
@@ -80,14 +96,14 @@ pub fn rdma_connect(id: &mut RdmaCmId, conn_param: Option<&mut RdmaConnParam>) -
 
     rdma_call!(
         rdma_connect,
-        rdma_core_sys::rdma_connect(id.deref_mut(), conn_param)
+        rdma_core_sys::rdma_connect(id.as_ptr(), conn_param)
     )
 }
 
 pub fn rdma_disconnect(id: &mut RdmaCmId) -> Result<()> {
     rdma_call!(
         rdma_disconnect,
-        rdma_core_sys::rdma_disconnect(id.deref_mut())
+        rdma_core_sys::rdma_disconnect(id.as_ptr())
     )
 }
 
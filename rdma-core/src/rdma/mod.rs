@@ -4,9 +4,12 @@ mod verbs;
 
 pub use cma::{
     rdma_accept, rdma_connect, rdma_create_ep, rdma_disconnect, rdma_get_request, rdma_getaddrinfo,
-    rdma_listen,
+    rdma_listen, rdma_reject,
 };
 
-pub use verbs::{rdma_post_recv, rdma_post_send, rdma_post_read, rdma_post_write, rdma_post_write_with_opcode};
+pub use verbs::{
+    rdma_post_atomic_cmp_swp, rdma_post_atomic_fetch_add, rdma_post_recv, rdma_post_send,
+    rdma_post_read, rdma_post_write, rdma_post_write_with_opcode,
+};
 
 pub use types::{RdmaAddrInfo::RdmaAddrInfo, RdmaCmId::RdmaCmId, RdmaConnParam::RdmaConnParam};
@@ -1,5 +1,79 @@
-use crate::rdma_type;
+use crate::{
+    ibverbs::{Gid, IbvContext, IbvPd, IbvQp},
+    rdma_handle, rdma_type, RdmaErrors, Result,
+};
 
-rdma_type!(RdmaAddrInfo, rdma_core_sys::rdma_addrinfo);
-rdma_type!(RdmaCmId, rdma_core_sys::rdma_cm_id);
+rdma_handle!(
+    RdmaAddrInfo,
+    rdma_core_sys::rdma_addrinfo,
+    rdma_core_sys::rdma_freeaddrinfo
+);
+rdma_handle!(
+    RdmaCmId,
+    rdma_core_sys::rdma_cm_id,
+    rdma_core_sys::rdma_destroy_ep
+);
 rdma_type!(RdmaConnParam, rdma_core_sys::rdma_conn_param);
+
+// a read-only list once rdma_getaddrinfo returns
+unsafe impl Send for RdmaAddrInfo::RdmaAddrInfo {}
+unsafe impl Sync for RdmaAddrInfo::RdmaAddrInfo {}
+
+// The connection management calls (rdma_listen/get_request/accept/connect/disconnect) wait on
+// the id's own event channel and must not run concurrently on one id. Clones share the id, so
+// `&mut` alone does not rule that out across threads and the id is deliberately not Sync;
+// whatever has to be shared posts through the id's QP instead (see `qp`), which is.
+unsafe impl Send for RdmaCmId::RdmaCmId {}
+
+impl RdmaCmId::RdmaCmId {
+    /// The PD the id's QP was created on; the id stays alive while the PD or any MR
+    /// registered on it is.
+    pub fn pd(&self) -> Option<IbvPd> {
+        unsafe { IbvPd::borrowed(self.pd, self.keep_alive()) }
+    }
+
+    /// The device context the id is bound to, once it is bound or resolved.
+    pub fn context(&self) -> Option<IbvContext> {
+        unsafe { IbvContext::borrowed(self.verbs, self.keep_alive()) }
+    }
+
+    /// Allocates a new PD on the device the id is bound to, for QPs of other ids to share; the
+    /// PD keeps this id, and with it the device, alive.
+    pub fn alloc_pd(&self) -> Result<IbvPd> {
+        if self.verbs.is_null() {
+            return Err(RdmaErrors::OpsFailed("ibv_alloc_pd".to_string(), libc::ENODEV));
+        }
+        let pd = unsafe { rdma_core_sys::ibv_alloc_pd(self.verbs) };
+        unsafe { IbvPd::from_raw(pd, Some(self.keep_alive())) }.ok_or_else(|| {
+            RdmaErrors::OpsFailed("ibv_alloc_pd".to_string(), unsafe { *libc::__errno_location() })
+        })
+    }
+
+    /// The QP created along with the id; it is destroyed by `rdma_destroy_ep`, not on its own.
+    pub fn qp(&self) -> Option<IbvQp> {
+        unsafe { IbvQp::borrowed(self.qp, self.keep_alive()) }
+    }
+
+    /// The local GID rdma_cm picked for the id's address once it is bound or resolved.
+    pub fn sgid(&self) -> Gid {
+        Gid(unsafe { self.route.addr.addr.ibaddr.sgid.raw })
+    }
+
+    /// The private data of the last connection event on a sync id: the request after
+    /// `rdma_get_request`, the accept or reject after `rdma_connect`. The event is acked by the
+    /// next call on the id, so this must be read before then.
+    pub fn event_private_data(&self) -> Option<Vec<u8>> {
+        let event = unsafe { self.event.as_ref()? };
+        let conn = unsafe { event.param.conn };
+        if conn.private_data.is_null() || conn.private_data_len == 0 {
+            return None;
+        }
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                conn.private_data as *const u8,
+                conn.private_data_len as usize,
+            )
+        };
+        Some(data.to_vec())
+    }
+}
@@ -1,4 +1,4 @@
-use rdma_core_sys::{htonl, ibv_recv_wr, ibv_send_wr, ibv_sge, IBV_WR_RDMA_READ, IBV_WR_RDMA_WRITE, IBV_WR_RDMA_WRITE_WITH_IMM, IBV_WR_SEND};
+use rdma_core_sys::{htonl, ibv_recv_wr, ibv_send_wr, ibv_send_wr__bindgen_ty_2, ibv_send_wr__bindgen_ty_2__bindgen_ty_2, ibv_sge, IBV_WR_ATOMIC_CMP_AND_SWP, IBV_WR_ATOMIC_FETCH_AND_ADD, IBV_WR_RDMA_READ, IBV_WR_RDMA_WRITE, IBV_WR_RDMA_WRITE_WITH_IMM, IBV_WR_SEND};
 use std::ptr::{self, null_mut};
 
 use crate::ibverbs::{ibv_post_recv, ibv_post_send, IbvMr};
@@ -207,3 +207,79 @@ pub fn rdma_post_readv<Context>(
 
     ibv_post_send(id.qp, &mut wr, &mut bad)
 }
+
+/// Atomically adds `add` to the 8-byte aligned word at `remote_addr`, which must be registered
+/// with `IBV_ACCESS_REMOTE_ATOMIC`. The value it held before lands in the 8 bytes at `addr`.
+#[allow(clippy::too_many_arguments)]
+pub fn rdma_post_atomic_fetch_add<Context>(
+    id: &mut RdmaCmId,
+    context: Option<&mut Context>,
+    addr: u64,
+    mr: Option<&mut IbvMr>,
+    flags: u32,
+    remote_addr: u64,
+    rkey: u32,
+    add: u64,
+) -> Result<()> {
+    let atomic = ibv_send_wr__bindgen_ty_2__bindgen_ty_2 {
+        remote_addr,
+        compare_add: add,
+        swap: 0,
+        rkey,
+    };
+    rdma_post_atomic(id, context, addr, mr, flags, IBV_WR_ATOMIC_FETCH_AND_ADD, atomic)
+}
+
+/// Atomically replaces the 8-byte aligned word at `remote_addr` with `swap` if it equals
+/// `compare`. The value it held before lands in the 8 bytes at `addr` either way, so the swap
+/// happened if that is `compare`.
+#[allow(clippy::too_many_arguments)]
+pub fn rdma_post_atomic_cmp_swp<Context>(
+    id: &mut RdmaCmId,
+    context: Option<&mut Context>,
+    addr: u64,
+    mr: Option<&mut IbvMr>,
+    flags: u32,
+    remote_addr: u64,
+    rkey: u32,
+    compare: u64,
+    swap: u64,
+) -> Result<()> {
+    let atomic = ibv_send_wr__bindgen_ty_2__bindgen_ty_2 {
+        remote_addr,
+        compare_add: compare,
+        swap,
+        rkey,
+    };
+    rdma_post_atomic(id, context, addr, mr, flags, IBV_WR_ATOMIC_CMP_AND_SWP, atomic)
+}
+
+fn rdma_post_atomic<Context>(
+    id: &mut RdmaCmId,
+    context: Option<&mut Context>,
+    addr: u64,
+    mr: Option<&mut IbvMr>,
+    flags: u32,
+    opcode: u32,
+    atomic: ibv_send_wr__bindgen_ty_2__bindgen_ty_2,
+) -> Result<()> {
+    // atomics always operate on 8 bytes
+    let mut sge = ibv_sge {
+        addr,
+        length: 8,
+        lkey: mr.map(|mr| mr.lkey).unwrap_or(0),
+    };
+    let mut wr = ibv_send_wr {
+        wr_id: context.map(|v| v as *mut _).unwrap_or(null_mut()) as u64,
+        sg_list: &mut sge,
+        num_sge: 1,
+        opcode,
+        send_flags: flags,
+        wr: ibv_send_wr__bindgen_ty_2 { atomic },
+        ..Default::default()
+    };
+
+    let mut bad: *mut ibv_send_wr = &mut ibv_send_wr::default();
+
+    ibv_post_send(id.qp, &mut wr, &mut bad)
+}
@@ -0,0 +1,48 @@
+from rdma_transport import VllmRdmaClient as RdmaClient, TensorBlocks, TensorBlock
+import logging
+import sys
+import torch
+import argparse
+import asyncio
+
+FORMAT = '%(levelname)s %(name)s %(asctime)-15s %(filename)s:%(lineno)d %(message)s'
+logging.basicConfig(stream=sys.stdout,format=FORMAT, level=logging.DEBUG)
+
+async def main():
+    parser = argparse.ArgumentParser(description="asyncio version of test_client.py")
+
+    parser.add_argument("--server_addr", type=str, help="")
+    parser.add_argument("--gpu_ordinal", type=int, help="")
+    parser.add_argument("--backend", type=str, default="rdma", help="rdma or tcp")
+
+    args = parser.parse_args()
+
+    torch.cuda.init()
+
+    size = 1024 * 1024
+    tensors = torch.empty(size, dtype=torch.int8)
+
+    local_buffers = TensorBlocks()
+    local_tensor_block = TensorBlock(tensors.data_ptr(), 0, size)
+    local_buffers.add(local_tensor_block)
+
+    dt = RdmaClient(args.gpu_ordinal, local_buffers, args.backend)
+
+    remote_tensor_blocks = await dt.connect_async(args.server_addr)
+    remote_tb_base_ptr = remote_tensor_blocks.get_base_ptrs()[0]
+
+    # pull the remote buffer page by page, all pages in flight at once
+    page_size = size // 16
+    await asyncio.gather(*[
+        dt.recv_async(TensorBlock(tensors.data_ptr(), i * page_size, page_size),
+                      TensorBlock(remote_tb_base_ptr, i * page_size, page_size))
+        for i in range(16)
+    ])
+
+    # the server's wait_complete(b"req-0") resolves once this lands
+    await dt.complete_async(b"req-0")
+    await dt.shutdown()
+
+
+if __name__ == "__main__":
+    asyncio.run(main())
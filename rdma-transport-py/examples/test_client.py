@@ -17,6 +17,7 @@ def main():
     parser.add_argument("--server_addr", type=str, help="")
     parser.add_argument("--msg", type=str, help="")
     parser.add_argument("--gpu_ordinal", type=int, help="")
+    parser.add_argument("--backend", type=str, default="rdma", help="rdma or tcp")
     
     # Parse the arguments
     args = parser.parse_args()
@@ -36,7 +37,7 @@ def main():
     local_tensor_block = TensorBlock(tensors.data_ptr(), 0, size)
     local_buffers.add(local_tensor_block)
 
-    dt = RdmaClient(gpu_ordinal, local_buffers)
+    dt = RdmaClient(gpu_ordinal, local_buffers, args.backend)
 
     remote_tensor_blocks = dt.connect(server_addr)
     remote_tb_base_ptr = remote_tensor_blocks.get_base_ptrs()[0];
@@ -44,12 +45,23 @@ def main():
 
     for i in range(10):
         # dt.send(local_tensor_block, remote_tensor_block)
-        dt.recv(local_tensor_block, remote_tensor_block)
+        dt.recv(local_tensor_block, remote_tensor_block).result()
         time.sleep(1)
 
-    # time.sleep(1)
-    dt.shutdown()
-    time.sleep(5)
+    # the same transfer as one batch of pages
+    page_size = size // 16
+    pages = [
+        (TensorBlock(tensors.data_ptr(), i * page_size, page_size),
+         TensorBlock(remote_tb_base_ptr, i * page_size, page_size))
+        for i in range(16)
+    ]
+    # dt.send_many(pages)
+    handle = dt.recv_many(pages)
+    while not handle.done():
+        time.sleep(0.01)
+    handle.result()
+
+    dt.shutdown().result(timeout=5)
 
 
 if __name__ == "__main__":
@@ -17,6 +17,7 @@ async def main():
     # Add arguments
     parser.add_argument("--server_addr", type=str, help="")
     parser.add_argument("--gpu_ordinal", type=int, help="")
+    parser.add_argument("--backend", type=str, default="rdma", help="rdma or tcp")
 
     # Parse the arguments
     args = parser.parse_args()
@@ -33,7 +34,7 @@ async def main():
     buffer = TensorBlock(tensors.data_ptr(), 0, size)
     local_buffers.add(buffer)
 
-    dt = RdmaServer(server_addr, gpu_ordinal, local_buffers)
+    dt = RdmaServer(server_addr, gpu_ordinal, local_buffers, args.backend)
     dt.listen()
 
     def signal_handler(sig, frame):
@@ -47,8 +48,12 @@ async def main():
     # Register the signal handler for SIGINT
     signal.signal(signal.SIGINT, signal_handler)
 
+    # resolves once test_async_client.py reports its request complete
+    await dt.wait_complete(b"req-0")
+    print("req-0 complete")
+
     while True:
-        time.sleep(1)
+        await asyncio.sleep(1)
 
 
 if __name__ == "__main__":
@@ -1,5 +1,8 @@
 use pyo3::{exceptions::PyIOError, prelude::*};
-use rdma_transport::cuda::{self, cuda_init_ctx, cuda_set_current_ctx, CudaMemBuffer};
+use rdma_transport::{
+    cuda::{self, cuda_init_ctx, cuda_set_current_ctx, CudaMemBuffer},
+    rdma,
+};
 
 /// Formats the sum of two numbers as string.
 #[pyfunction]
@@ -66,7 +69,8 @@ fn cuda_device_to_host(device_buffer: &CudaMemBufferPy, size: usize) -> PyResult
 #[pyfunction]
 fn cuda_mem_free(device_buffer: &CudaMemBufferPy) -> PyResult<()> {
     let device_buffer = device_buffer.into();
-    cuda::cuda_mem_free(&device_buffer).map_err(|e| PyIOError::new_err(e.to_string()))
+    // drops any cached registration of the buffer along with it
+    rdma::free_gpu_membuffer(&device_buffer).map_err(|e| PyIOError::new_err(e.to_string()))
 }
 
 /// A Python module implemented in Rust.
@@ -0,0 +1,30 @@
+// create_exception! checks the `gil-refs` feature, which this crate does not declare
+#![allow(unexpected_cfgs)]
+
+use pyo3::{create_exception, exceptions::PyException, PyErr};
+use rdma_transport::TransportErrors;
+
+create_exception!(rdma_transport, TransportError, PyException);
+create_exception!(rdma_transport, RdmaError, TransportError);
+create_exception!(rdma_transport, CudaError, TransportError);
+create_exception!(rdma_transport, HandshakeError, TransportError);
+
+pub fn into_py_err(e: TransportErrors) -> PyErr {
+    match e {
+        TransportErrors::RdmaErrors(e) => RdmaError::new_err(e.to_string()),
+        TransportErrors::CudaErrors(e) => CudaError::new_err(e.to_string()),
+        e @ TransportErrors::HandshakeRejected(_) => HandshakeError::new_err(e.to_string()),
+        e => TransportError::new_err(e.to_string()),
+    }
+}
+
+/// [`into_py_err`] with `context` ahead of the message, raising the same exception type.
+pub fn into_py_err_in(e: &TransportErrors, context: &str) -> PyErr {
+    let message = format!("{}: {}", context, e);
+    match e {
+        TransportErrors::RdmaErrors(_) => RdmaError::new_err(message),
+        TransportErrors::CudaErrors(_) => CudaError::new_err(message),
+        TransportErrors::HandshakeRejected(_) => HandshakeError::new_err(message),
+        _ => TransportError::new_err(message),
+    }
+}
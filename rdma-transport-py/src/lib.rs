@@ -1,7 +1,9 @@
 use pyo3::prelude::*;
 // use general::{Message, RdmaClient, RdmaServer};
-use vllm::{TensorBlock, TensorBlocks, VllmRdmaClient, VllmRdmaServer};
+use errors::{CudaError, HandshakeError, RdmaError, TransportError};
+use vllm::{TensorBlock, TensorBlocks, TransferHandle, VllmRdmaClient, VllmRdmaServer};
 
+mod errors;
 // mod general;
 mod vllm;
 
@@ -25,5 +27,10 @@ fn rdma_transport_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<TensorBlocks>()?;
     m.add_class::<VllmRdmaClient>()?;
     m.add_class::<VllmRdmaServer>()?;
+    m.add_class::<TransferHandle>()?;
+    m.add("TransportError", m.py().get_type_bound::<TransportError>())?;
+    m.add("RdmaError", m.py().get_type_bound::<RdmaError>())?;
+    m.add("CudaError", m.py().get_type_bound::<CudaError>())?;
+    m.add("HandshakeError", m.py().get_type_bound::<HandshakeError>())?;
     Ok(())
 }
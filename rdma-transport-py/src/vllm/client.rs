@@ -1,31 +1,54 @@
-use log::{error, info};
+use log::info;
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use rdma_transport::rdma::{self, Notification};
+use rdma_transport::rdma::{Notification, QpOptions};
+use rdma_transport::transport::{
+    Backend, LoopbackTransport, RdmaTransport, TcpTransport, Transport,
+};
+use rdma_transport::{GPUMemBuffer, Result, TransportErrors};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::ops::DerefMut;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Instant;
 use tokio::runtime;
-use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
 
-use super::{CompletionReqs, TensorBlock, TensorBlocks};
+use super::handle::{Completer, TransferHandle};
+use super::{addr_for_gpu, qp_options, CompletionReqs, TensorBlock, TensorBlocks};
+use crate::errors::{into_py_err, into_py_err_in, TransportError};
 
 pub enum Command {
     // Client send only works for push mode
     Send {
         local_tensor_block: TensorBlock,
         remote_tensor_block: TensorBlock,
+        done: Completer,
     },
     // Client Recv only works for pull mode
     Recv {
         local_tensor_block: TensorBlock,
         remote_tensor_block: TensorBlock,
+        done: Completer,
+    },
+    // (local, remote) pairs moved as one batch
+    SendMany {
+        tensor_blocks: Vec<(TensorBlock, TensorBlock)>,
+        done: Completer,
+    },
+    RecvMany {
+        tensor_blocks: Vec<(TensorBlock, TensorBlock)>,
+        done: Completer,
     },
     Complete {
         req_id: Vec<u8>,
+        done: Completer,
+    },
+    Disconnect {
+        done: Completer,
     },
-    Disconnect(),
 }
 
 #[pyclass]
@@ -33,196 +56,458 @@ pub struct VllmRdmaClient {
     sender: Option<Sender<Command>>,
     gpu_ordinal: i32,
     local_buffer: TensorBlocks,
+    backend: Backend,
+    qp_options: QpOptions,
     completion_reqs: Option<Arc<RwLock<CompletionReqs>>>,
 }
 
 #[pymethods]
 impl VllmRdmaClient {
+    /// `rdma_device` names the RDMA device to connect from, or is "auto" for the one closest to
+    /// the GPU; the route to the server picks it when not given. The keyword arguments after it
+    /// tune the RDMA backends' QPs, see `QpOptions`.
     #[new]
-    fn new(gpu_ordinal: i32, local_buffer: TensorBlocks) -> Self {
-        VllmRdmaClient {
+    #[pyo3(signature = (
+        gpu_ordinal,
+        local_buffer,
+        backend="rdma",
+        rdma_device=None,
+        queue_depth=None,
+        recv_credits=None,
+        retry_count=None,
+        rnr_retry_count=None,
+        min_rnr_timer=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        gpu_ordinal: i32,
+        local_buffer: TensorBlocks,
+        backend: &str,
+        rdma_device: Option<&str>,
+        queue_depth: Option<u32>,
+        recv_credits: Option<u32>,
+        retry_count: Option<u8>,
+        rnr_retry_count: Option<u8>,
+        min_rnr_timer: Option<u8>,
+    ) -> PyResult<Self> {
+        let backend = backend
+            .parse::<Backend>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let mut qp_options = qp_options(
+            queue_depth,
+            recv_credits,
+            retry_count,
+            rnr_retry_count,
+            min_rnr_timer,
+        );
+        if let (Backend::Rdma | Backend::RdmaSrq, Some(rdma_device)) = (backend, rdma_device) {
+            qp_options.local_addr = Some(addr_for_gpu(gpu_ordinal, rdma_device)?);
+        }
+        Ok(VllmRdmaClient {
             sender: None,
             local_buffer,
             gpu_ordinal,
+            backend,
+            qp_options,
             completion_reqs: None,
-        }
+        })
+    }
+
+    fn connect(&mut self, py: Python<'_>, server_addr: String) -> PyResult<TensorBlocks> {
+        let conn_rx = self.start(server_addr)?;
+        // self.buffer = Some((gpu_buffer.get_base_ptr(), gpu_buffer.get_size()));
+        // csy: We can associate a cuda event to this buffer, or each buffer.
+        // info!("client gpu_buffer: {:?}", gpu_buffer);
+        connected(py.allow_threads(move || conn_rx.blocking_recv()))
+    }
+
+    async fn connect_async(slf: Py<Self>, server_addr: String) -> PyResult<TensorBlocks> {
+        let conn_rx = Python::with_gil(|py| slf.borrow_mut(py).start(server_addr))?;
+        connected(conn_rx.await)
+    }
+
+    fn send(
+        &self,
+        local_tensor_block: TensorBlock,
+        remote_tensor_block: TensorBlock,
+    ) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::Send {
+            local_tensor_block,
+            remote_tensor_block,
+            done,
+        })
+    }
+
+    fn recv(
+        &self,
+        local_tensor_block: TensorBlock,
+        remote_tensor_block: TensorBlock,
+    ) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::Recv {
+            local_tensor_block,
+            remote_tensor_block,
+            done,
+        })
+    }
+
+    fn send_many(
+        &self,
+        tensor_blocks: Vec<(TensorBlock, TensorBlock)>,
+    ) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::SendMany {
+            tensor_blocks,
+            done,
+        })
+    }
+
+    fn recv_many(
+        &self,
+        tensor_blocks: Vec<(TensorBlock, TensorBlock)>,
+    ) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::RecvMany {
+            tensor_blocks,
+            done,
+        })
+    }
+
+    fn complete(&self, req_id: Vec<u8>) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::Complete { req_id, done })
     }
 
-    fn connect(&mut self, server_addr: String) -> TensorBlocks {
-        let server_addr = match server_addr.parse::<SocketAddr>() {
-            Ok(sock_addr) => sock_addr,
-            Err(e) => {
-                error!("parse socket address failed: {:?}", e);
-                panic!();
+    async fn send_async(
+        &self,
+        local_tensor_block: TensorBlock,
+        remote_tensor_block: TensorBlock,
+    ) -> PyResult<()> {
+        self.send(local_tensor_block, remote_tensor_block)?
+            .wait()
+            .await
+    }
+
+    async fn recv_async(
+        &self,
+        local_tensor_block: TensorBlock,
+        remote_tensor_block: TensorBlock,
+    ) -> PyResult<()> {
+        self.recv(local_tensor_block, remote_tensor_block)?
+            .wait()
+            .await
+    }
+
+    async fn complete_async(&self, req_id: Vec<u8>) -> PyResult<()> {
+        self.complete(req_id)?.wait().await
+    }
+
+    fn is_complete(&self, req_id: Vec<u8>) -> bool {
+        if let Some(completion_reqs) = &self.completion_reqs {
+            match completion_reqs.try_read() {
+                Ok(reqs) => reqs.is_req_complete(&req_id),
+                Err(_) => false,
             }
-        };
+        } else {
+            false
+        }
+    }
 
-        let (tx, mut rx) = mpsc::channel(1024 * 1024 * 1024);
+    fn shutdown(&self) -> PyResult<TransferHandle> {
+        self.submit(|done| Command::Disconnect { done })
+    }
+}
+
+impl VllmRdmaClient {
+    fn start(&mut self, server_addr: String) -> PyResult<oneshot::Receiver<Result<TensorBlocks>>> {
+        let server_addr = server_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| PyValueError::new_err(format!("invalid server address: {}", e)))?;
+
+        let (tx, rx) = mpsc::channel(1024 * 1024 * 1024);
         self.sender = Some(tx);
         let completion_reqs = Arc::new(RwLock::new(CompletionReqs::new(1024)));
         self.completion_reqs = Some(completion_reqs.clone());
-        let mut cm_id = rdma::client_init(server_addr).unwrap();
         let gpu_ordinal = self.gpu_ordinal;
         let gpu_buffers = self.local_buffer.iter().map(Into::into).collect();
-        let (cpu_conn, (mut cpu_mr, mut cpu_buffer), mut local_gpu_buffers, remote_gpu_buffers) =
-            rdma::connect(&mut cm_id, gpu_ordinal, gpu_buffers).unwrap();
-        // self.buffer = Some((gpu_buffer.get_base_ptr(), gpu_buffer.get_size()));
-        // csy: We can associate a cuda event to this buffer, or each buffer.
-        // info!("client gpu_buffer: {:?}", gpu_buffer);
-        let tensor_blocks = remote_gpu_buffers
-            .values()
-            .map(Into::into)
-            .collect::<Vec<TensorBlock>>()
-            .into();
+        let backend = self.backend;
+        let qp_options = self.qp_options;
+        let (conn_tx, conn_rx) = oneshot::channel();
 
         let _ = thread::spawn(move || {
-            let rt = runtime::Builder::new_current_thread().build().unwrap();
+            let rt = match runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let _ = conn_tx.send(Err(e.into()));
+                    return;
+                }
+            };
             rt.block_on(async {
-                while let Some(cmd) = rx.recv().await {
-                    match cmd {
-                        Command::Complete { req_id } => {
-                            let notification = Notification {
-                                done: 0,
-                                req_id: Some(req_id.clone()),
-                            };
-
-                            let metadata_size = bincode::serialized_size(&notification).unwrap();
-                            bincode::serialize_into(cpu_buffer.deref_mut(), &notification).unwrap();
-
-                            let mut reqs = completion_reqs.write().unwrap();
-                            reqs.add_req(&req_id);
-                            if reqs.is_full() {
-                                reqs.remove_first();
-                            }
-                            if let Err(e) = rdma::write_metadata(
-                                &mut cm_id,
-                                &cpu_conn,
-                                &mut cpu_mr,
-                                &mut cpu_buffer,
-                                0,
-                                metadata_size as u16,
-                            )
-                            .await
-                            {
-                                error!("write complete message error {:?}", e);
-                            }
-                        }
-                        Command::Send {
-                            local_tensor_block,
-                            remote_tensor_block,
-                        } if local_tensor_block.get_size() > 0 => {
-                            // csy: We can wait on this event here or use cuLaunchHostFunc to enqueue the write routine
-                            let conn = remote_gpu_buffers
-                                .get(&remote_tensor_block.get_base_ptr())
-                                .unwrap();
-                            let (gpu_mr, _) = local_gpu_buffers
-                                .get_mut(&local_tensor_block.get_base_ptr())
-                                .unwrap();
-                            if let Err(e) = rdma::write(
-                                &mut cm_id,
-                                conn,
-                                gpu_mr,
-                                local_tensor_block.get_base_ptr() + local_tensor_block.get_offset(),
-                                conn.get_base_ptr() + remote_tensor_block.get_offset(),
-                                local_tensor_block.get_size(),
-                            )
-                            .await
-                            {
-                                error!("write data error {:?}", e);
-                            }
-                        }
-                        Command::Recv {
-                            local_tensor_block,
-                            remote_tensor_block,
-                        } if local_tensor_block.get_size() > 0 => {
-                            // csy: We can wait on this event here or use cuLaunchHostFunc to enqueue the write routine
-                            let conn = remote_gpu_buffers
-                                .get(&remote_tensor_block.get_base_ptr())
-                                .unwrap();
-                            let (gpu_mr, _) = local_gpu_buffers
-                                .get_mut(&local_tensor_block.get_base_ptr())
-                                .unwrap();
-                            if let Err(e) = rdma::read(
-                                &mut cm_id,
-                                conn,
-                                gpu_mr,
-                                local_tensor_block.get_base_ptr() + local_tensor_block.get_offset(),
-                                conn.get_base_ptr() + remote_tensor_block.get_offset(),
-                                local_tensor_block.get_size(),
-                            )
-                            .await
-                            {
-                                error!("write data error {:?}", e);
-                            }
-                        }
-                        Command::Disconnect() => {
-                            info!("disconnect");
-                            rdma::client_disconnect(
-                                &mut cm_id,
-                                &cpu_conn,
-                                &mut cpu_mr,
-                                &mut cpu_buffer,
-                            )
-                            .await
-                            .unwrap();
-                            break;
-                        }
-                        _ => {}
+                match backend {
+                    // the SRQ only changes the server side
+                    Backend::Rdma | Backend::RdmaSrq => {
+                        run::<RdmaTransport>(
+                            server_addr,
+                            qp_options,
+                            gpu_ordinal,
+                            gpu_buffers,
+                            rx,
+                            completion_reqs,
+                            conn_tx,
+                        )
+                        .await
+                    }
+                    Backend::Tcp => {
+                        run::<TcpTransport>(
+                            server_addr,
+                            (),
+                            gpu_ordinal,
+                            gpu_buffers,
+                            rx,
+                            completion_reqs,
+                            conn_tx,
+                        )
+                        .await
+                    }
+                    Backend::Loopback => {
+                        run::<LoopbackTransport>(
+                            server_addr,
+                            (),
+                            gpu_ordinal,
+                            gpu_buffers,
+                            rx,
+                            completion_reqs,
+                            conn_tx,
+                        )
+                        .await
                     }
                 }
             });
             info!("runtime end at {:?}", Instant::now());
         });
 
-        return tensor_blocks;
+        Ok(conn_rx)
     }
 
-    fn send(&self, local_tensor_block: TensorBlock, remote_tensor_block: TensorBlock) {
-        if let Some(sender) = &self.sender {
-            if let Err(e) = sender.try_send(Command::Send {
+    fn submit(&self, cmd: impl FnOnce(Completer) -> Command) -> PyResult<TransferHandle> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| TransportError::new_err("client is not connected"))?;
+        let (handle, done) = TransferHandle::new();
+        sender
+            .try_send(cmd(done))
+            .map_err(|e| TransportError::new_err(format!("submit command failed: {}", e)))?;
+        Ok(handle)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run<T: Transport>(
+    server_addr: SocketAddr,
+    options: T::Options,
+    gpu_ordinal: i32,
+    gpu_buffers: Vec<GPUMemBuffer>,
+    mut rx: Receiver<Command>,
+    completion_reqs: Arc<RwLock<CompletionReqs>>,
+    conn_tx: oneshot::Sender<Result<TensorBlocks>>,
+) {
+    let mut conn = match T::connect(server_addr, gpu_ordinal, gpu_buffers, options).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            let _ = conn_tx.send(Err(e));
+            return;
+        }
+    };
+    let tensor_blocks = conn
+        .remote_buffers()
+        .values()
+        .map(Into::into)
+        .collect::<Vec<TensorBlock>>()
+        .into();
+    let _ = conn_tx.send(Ok(tensor_blocks));
+
+    // transfers are posted without waiting on them and resolve their handles as they finish;
+    // a request is only reported complete once every transfer posted before it has finished
+    let mut inflight = Inflight::default();
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            Command::Complete { req_id, done } => {
+                let notification = Notification {
+                    done: 0,
+                    req_id: Some(req_id.clone()),
+                };
+
+                // the handles of failed transfers already carry their errors; the request is
+                // failed too instead of being reported complete without its data
+                if let Err((transfer, e)) = settle(&mut inflight).await {
+                    let request = String::from_utf8_lossy(&req_id);
+                    let context = format!(
+                        "request {:?} not completed, its {} failed",
+                        request, transfer
+                    );
+                    done.fail(into_py_err_in(&e, &context));
+                    continue;
+                }
+                {
+                    let mut reqs = completion_reqs.write().unwrap();
+                    reqs.add_req(&req_id);
+                    if reqs.is_full() {
+                        reqs.remove_first();
+                    }
+                }
+                done.complete(conn.notify(&notification).await);
+            }
+            Command::Send {
                 local_tensor_block,
                 remote_tensor_block,
-            }) {
-                error!("send data msg error {:?}", e);
+                done,
+            } => {
+                if local_tensor_block.get_size() == 0 {
+                    done.complete(Ok(()));
+                    continue;
+                }
+                let remote = remote_tensor_block.get_base_ptr() + remote_tensor_block.get_offset();
+                let what = format!(
+                    "write of {} bytes to {:#x}",
+                    local_tensor_block.get_size(),
+                    remote
+                );
+                // csy: We can wait on this event here or use cuLaunchHostFunc to enqueue the write routine
+                let posted = conn
+                    .post_write(
+                        local_tensor_block.get_base_ptr() + local_tensor_block.get_offset(),
+                        remote,
+                        local_tensor_block.get_size(),
+                    )
+                    .await;
+                track(&mut inflight, what, posted, done);
             }
-        }
-    }
-
-    fn recv(&self, local_tensor_block: TensorBlock, remote_tensor_block: TensorBlock) {
-        if let Some(sender) = &self.sender {
-            if let Err(e) = sender.try_send(Command::Recv {
+            Command::Recv {
                 local_tensor_block,
                 remote_tensor_block,
-            }) {
-                error!("send data msg error {:?}", e);
+                done,
+            } => {
+                if local_tensor_block.get_size() == 0 {
+                    done.complete(Ok(()));
+                    continue;
+                }
+                let remote = remote_tensor_block.get_base_ptr() + remote_tensor_block.get_offset();
+                let what = format!(
+                    "read of {} bytes from {:#x}",
+                    local_tensor_block.get_size(),
+                    remote
+                );
+                // csy: We can wait on this event here or use cuLaunchHostFunc to enqueue the write routine
+                let posted = conn
+                    .post_read(
+                        local_tensor_block.get_base_ptr() + local_tensor_block.get_offset(),
+                        remote,
+                        local_tensor_block.get_size(),
+                    )
+                    .await;
+                track(&mut inflight, what, posted, done);
+            }
+            Command::SendMany {
+                tensor_blocks,
+                done,
+            } => {
+                let what = format!("write of {} blocks", tensor_blocks.len());
+                let posted = conn.post_write_many(&blocks(&tensor_blocks)).await;
+                track(&mut inflight, what, posted, done);
+            }
+            Command::RecvMany {
+                tensor_blocks,
+                done,
+            } => {
+                let what = format!("read of {} blocks", tensor_blocks.len());
+                let posted = conn.post_read_many(&blocks(&tensor_blocks)).await;
+                track(&mut inflight, what, posted, done);
+            }
+            Command::Disconnect { done } => {
+                info!("disconnect");
+                let _ = settle(&mut inflight).await;
+                done.complete(conn.disconnect().await);
+                break;
             }
         }
     }
+    let _ = settle(&mut inflight).await;
+}
 
-    fn complete(&self, req_id: Vec<u8>) {
-        if let Some(sender) = &self.sender {
-            if let Err(e) = sender.try_send(Command::Complete { req_id }) {
-                error!("send complete msg error {:?}", e);
-            }
-        }
+fn connected(
+    result: std::result::Result<Result<TensorBlocks>, oneshot::error::RecvError>,
+) -> PyResult<TensorBlocks> {
+    match result {
+        Ok(result) => result.map_err(into_py_err),
+        Err(_) => Err(TransportError::new_err(
+            "client runtime exited before connecting",
+        )),
     }
+}
 
-    fn is_complete(&self, req_id: Vec<u8>) -> bool {
-        if let Some(completion_reqs) = &self.completion_reqs {
-            match completion_reqs.try_read() {
-                Ok(reqs) => reqs.is_req_complete(&req_id),
-                Err(_) => false,
-            }
-        } else {
-            false
+// a transfer that went wrong, described for the request it holds up, and its error
+type Failure = (String, TransportErrors);
+
+// The transfers posted since the last `complete`: those still running, each yielding its
+// failure if it has one, and the first that failed to post at all.
+#[derive(Default)]
+struct Inflight {
+    running: JoinSet<Option<Failure>>,
+    failed: Option<Failure>,
+}
+
+// hands a posted transfer, `what`, to a task that resolves its handle once it finishes; one
+// that failed to post resolves its handle straight away
+fn track<F>(inflight: &mut Inflight, what: String, posted: Result<F>, done: Completer)
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    match posted {
+        Ok(transfer) => {
+            inflight.running.spawn(async move {
+                match transfer.await {
+                    Ok(()) => {
+                        done.complete(Ok(()));
+                        None
+                    }
+                    Err(e) => {
+                        done.fail(into_py_err_in(&e, &what));
+                        Some((what, e))
+                    }
+                }
+            });
+        }
+        Err(e) => {
+            done.fail(into_py_err_in(&e, &what));
+            inflight.failed = inflight.failed.take().or(Some((what, e)));
         }
     }
+}
 
-    fn shutdown(&self) {
-        if let Some(sender) = &self.sender {
-            if let Err(e) = sender.try_send(Command::Disconnect()) {
-                error!("shutdown error {:?}", e);
-            }
-        }
+// waits for every transfer in flight and fails with the first of them that failed
+async fn settle(inflight: &mut Inflight) -> std::result::Result<(), Failure> {
+    let mut first = inflight.failed.take();
+    while let Some(joined) = inflight.running.join_next().await {
+        let failure = joined.unwrap_or_else(|e| {
+            Some((
+                "transfer".to_string(),
+                TransportErrors::OpsFailed("transfer".to_string(), e.to_string()),
+            ))
+        });
+        first = first.or(failure);
     }
+    match first {
+        Some(failure) => Err(failure),
+        None => Ok(()),
+    }
+}
+
+fn blocks(tensor_blocks: &[(TensorBlock, TensorBlock)]) -> Vec<(u64, u64, u32)> {
+    tensor_blocks
+        .iter()
+        .map(|(local, remote)| {
+            (
+                local.get_base_ptr() + local.get_offset(),
+                remote.get_base_ptr() + remote.get_offset(),
+                local.get_size(),
+            )
+        })
+        .collect()
 }
@@ -0,0 +1,132 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use pyo3::exceptions::PyTimeoutError;
+use pyo3::prelude::*;
+use tokio::sync::Notify;
+
+use crate::errors::{into_py_err, TransportError};
+
+#[derive(Default)]
+struct State {
+    outcome: Mutex<Option<PyResult<()>>>,
+    cond: Condvar,
+    notify: Notify,
+}
+
+impl State {
+    fn outcome(&self, py: Python<'_>) -> PyResult<()> {
+        match &*self.outcome.lock().unwrap() {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => Err(e.clone_ref(py)),
+            None => Err(TransportError::new_err("transfer still in flight")),
+        }
+    }
+}
+
+/// Resolves a [`TransferHandle`] from the runtime thread.
+///
+/// Dropping it unresolved fails the handle, so a torn down connection never leaves
+/// Python waiting forever.
+pub struct Completer(Arc<State>);
+
+impl Completer {
+    pub fn complete(self, result: rdma_transport::Result<()>) {
+        self.resolve(result.map_err(into_py_err));
+    }
+
+    pub fn fail(self, error: PyErr) {
+        self.resolve(Err(error));
+    }
+
+    fn resolve(&self, result: PyResult<()>) {
+        let mut outcome = self.0.outcome.lock().unwrap();
+        if outcome.is_none() {
+            *outcome = Some(result);
+            self.0.cond.notify_all();
+            self.0.notify.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        self.resolve(Err(TransportError::new_err(
+            "connection closed before the transfer completed",
+        )));
+    }
+}
+
+/// Tracks one submitted operation.
+///
+/// Poll it with `done()`, block on it with `result(timeout)`, or `await` it. A failed
+/// operation raises `TransportError`, `RdmaError` or `CudaError`.
+#[pyclass]
+pub struct TransferHandle {
+    state: Arc<State>,
+}
+
+impl TransferHandle {
+    pub fn new() -> (TransferHandle, Completer) {
+        let state = Arc::new(State::default());
+        (
+            TransferHandle {
+                state: state.clone(),
+            },
+            Completer(state),
+        )
+    }
+}
+
+#[pymethods]
+impl TransferHandle {
+    fn done(&self) -> bool {
+        self.state.outcome.lock().unwrap().is_some()
+    }
+
+    #[pyo3(signature = (timeout=None))]
+    fn result(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<()> {
+        let state = self.state.clone();
+        let done = py.allow_threads(move || {
+            let outcome = state.outcome.lock().unwrap();
+            match timeout {
+                Some(timeout) => {
+                    let timeout = Duration::from_secs_f64(timeout.max(0.0));
+                    let (outcome, _) = state
+                        .cond
+                        .wait_timeout_while(outcome, timeout, |outcome| outcome.is_none())
+                        .unwrap();
+                    outcome.is_some()
+                }
+                None => {
+                    let _outcome = state
+                        .cond
+                        .wait_while(outcome, |outcome| outcome.is_none())
+                        .unwrap();
+                    true
+                }
+            }
+        });
+        if !done {
+            return Err(PyTimeoutError::new_err("transfer still in flight"));
+        }
+        self.state.outcome(py)
+    }
+
+    pub async fn wait(&self) -> PyResult<()> {
+        let state = self.state.clone();
+        loop {
+            let notified = state.notify.notified();
+            if state.outcome.lock().unwrap().is_some() {
+                break;
+            }
+            notified.await;
+        }
+        Python::with_gil(|py| state.outcome(py))
+    }
+
+    fn __await__(slf: &Bound<'_, Self>) -> PyResult<PyObject> {
+        let coroutine = slf.call_method0("wait")?;
+        Ok(coroutine.call_method0("__await__")?.unbind())
+    }
+}
@@ -1,19 +1,27 @@
 mod client;
+mod handle;
 mod server;
 
-use std::{collections::{HashSet, VecDeque}, ops::{Deref, DerefMut}};
+use std::{collections::{HashSet, VecDeque}, net::IpAddr, ops::{Deref, DerefMut}, sync::Arc};
 
 pub use client::VllmRdmaClient;
-use pyo3::{pyclass, pymethods};
+pub use handle::TransferHandle;
+use pyo3::{pyclass, pymethods, PyResult};
 use rdma_transport::{
-    rdma::Connection,
-    GPUMemBuffer,
+    rdma::{Connection, QpOptions},
+    topology, GPUMemBuffer,
 };
 pub use server::VllmRdmaServer;
+use tokio::sync::Notify;
+
+use crate::errors::into_py_err;
 
 pub struct CompletionReqs {
     fifo_reqs: VecDeque<Vec<u8>>,
-    reqs_set: HashSet<Vec<u8>>
+    reqs_set: HashSet<Vec<u8>>,
+    // woken on every added req and on close
+    changed: Arc<Notify>,
+    closed: bool,
 }
 
 impl CompletionReqs {
@@ -22,13 +30,16 @@ impl CompletionReqs {
         let reqs_set = HashSet::with_capacity(size);
         CompletionReqs {
             fifo_reqs,
-            reqs_set
+            reqs_set,
+            changed: Arc::new(Notify::new()),
+            closed: false,
         }
     }
 
     pub fn add_req(&mut self, req: &Vec<u8>) {
         self.reqs_set.insert(req.to_vec());
         self.fifo_reqs.push_back(req.to_vec());
+        self.changed.notify_waiters();
     }
 
     pub fn remove_first(&mut self) {
@@ -45,10 +56,52 @@ impl CompletionReqs {
         self.fifo_reqs.len() == self.fifo_reqs.capacity()
     }
 
+    pub fn changed(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.changed.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
 }
 
 
 
+// the RDMA keyword arguments of the client and server constructors; whatever is left out
+// keeps its QpOptions default
+fn qp_options(
+    queue_depth: Option<u32>,
+    recv_credits: Option<u32>,
+    retry_count: Option<u8>,
+    rnr_retry_count: Option<u8>,
+    min_rnr_timer: Option<u8>,
+) -> QpOptions {
+    let defaults = QpOptions::default();
+    QpOptions {
+        queue_depth: queue_depth.unwrap_or(defaults.queue_depth),
+        recv_credits: recv_credits.unwrap_or(defaults.recv_credits),
+        retry_count: retry_count.unwrap_or(defaults.retry_count),
+        rnr_retry_count: rnr_retry_count.unwrap_or(defaults.rnr_retry_count),
+        min_rnr_timer: min_rnr_timer.unwrap_or(defaults.min_rnr_timer),
+        local_addr: defaults.local_addr,
+    }
+}
+
+// the address of the RDMA device `rdma_device` names, or of the one closest to the GPU for
+// "auto"
+fn addr_for_gpu(gpu_ordinal: i32, rdma_device: &str) -> PyResult<IpAddr> {
+    let device_override = Some(rdma_device).filter(|&name| name != "auto");
+    let (_, addr) =
+        topology::select_for_gpu(gpu_ordinal, device_override).map_err(into_py_err)?;
+    Ok(addr)
+}
+
 #[pyclass]
 #[derive(Debug, Clone, Default)]
 pub struct TensorBlock {
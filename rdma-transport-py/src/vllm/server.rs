@@ -1,14 +1,19 @@
 use log::{error, info};
+use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use rdma_transport::{cuda, rdma, GPUMemBuffer};
+use rdma_transport::transport::{
+    Backend, LoopbackTransport, RdmaSrqTransport, RdmaTransport, TcpTransport, Transport,
+};
+use rdma_transport::{cuda, rdma::QpOptions, GPUMemBuffer, Result};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Instant;
 use tokio::runtime::Runtime;
-use tokio::sync::oneshot::{self, Sender};
+use tokio::sync::oneshot::{self, Receiver, Sender};
 
-use super::{CompletionReqs, TensorBlocks};
+use super::{addr_for_gpu, qp_options, CompletionReqs, TensorBlocks};
+use crate::errors::{into_py_err, TransportError};
 
 #[pyclass]
 pub enum Command {
@@ -21,81 +26,163 @@ pub struct VllmRdmaServer {
     sock_addr: SocketAddr,
     gpu_ordinal: i32,
     local_buffer: TensorBlocks,
+    backend: Backend,
+    qp_options: QpOptions,
     completion_reqs: Option<Arc<RwLock<CompletionReqs>>>,
 }
 
 #[pymethods]
 impl VllmRdmaServer {
+    /// `rdma_device` names the RDMA device to listen on, or is "auto" for the one closest to the
+    /// GPU, and the address of `sock_addr` is replaced by the device's. A wildcard `sock_addr`
+    /// means "auto" for the RDMA backends. The keyword arguments after it tune the RDMA
+    /// backends' QPs, see `QpOptions`.
     #[new]
-    fn new(sock_addr: String, gpu_ordinal: i32, local_buffer: TensorBlocks) -> Self {
-        let sock_addr = match sock_addr.parse::<SocketAddr>() {
-            Ok(sock_addr) => sock_addr,
-            Err(e) => {
-                error!("parse socket address failed: {:?}", e);
-                panic!();
+    #[pyo3(signature = (
+        sock_addr,
+        gpu_ordinal,
+        local_buffer,
+        backend="rdma",
+        rdma_device=None,
+        queue_depth=None,
+        recv_credits=None,
+        retry_count=None,
+        rnr_retry_count=None,
+        min_rnr_timer=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        sock_addr: String,
+        gpu_ordinal: i32,
+        local_buffer: TensorBlocks,
+        backend: &str,
+        rdma_device: Option<&str>,
+        queue_depth: Option<u32>,
+        recv_credits: Option<u32>,
+        retry_count: Option<u8>,
+        rnr_retry_count: Option<u8>,
+        min_rnr_timer: Option<u8>,
+    ) -> PyResult<Self> {
+        let mut sock_addr = sock_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| PyValueError::new_err(format!("invalid socket address: {}", e)))?;
+
+        let backend = backend
+            .parse::<Backend>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+        if matches!(backend, Backend::Rdma | Backend::RdmaSrq) {
+            // rdma_cm cannot tell which device a wildcard address means
+            let rdma_device = rdma_device.or(sock_addr.ip().is_unspecified().then_some("auto"));
+            if let Some(rdma_device) = rdma_device {
+                sock_addr.set_ip(addr_for_gpu(gpu_ordinal, rdma_device)?);
             }
-        };
+        }
 
-        VllmRdmaServer {
+        Ok(VllmRdmaServer {
             cmd_sender: None,
             sock_addr,
             gpu_ordinal,
             local_buffer,
+            backend,
+            qp_options: qp_options(
+                queue_depth,
+                recv_credits,
+                retry_count,
+                rnr_retry_count,
+                min_rnr_timer,
+            ),
             completion_reqs: None,
-        }
+        })
     }
 
-    fn listen(&mut self) {
-        let (cmd_tx, mut cmd_rx) = oneshot::channel::<Command>();
+    /// Binds and starts accepting in the background, raising if the address cannot be bound.
+    fn listen(&mut self, py: Python<'_>) -> PyResult<()> {
+        let (cmd_tx, cmd_rx) = oneshot::channel::<Command>();
+        let (bound_tx, bound_rx) = oneshot::channel();
         self.cmd_sender = Some(cmd_tx);
         let completion_reqs = Arc::new(RwLock::new(CompletionReqs::new(1024)));
         self.completion_reqs = Some(completion_reqs.clone());
-        let mut listen_id = rdma::server_init(&self.sock_addr).unwrap();
+        let sock_addr = self.sock_addr;
         let gpu_ordinal = self.gpu_ordinal;
-        let gpu_buffers = self.local_buffer.iter().map(Into::into).collect::<Vec<GPUMemBuffer>>();
+        let gpu_buffers = self
+            .local_buffer
+            .iter()
+            .map(Into::into)
+            .collect::<Vec<GPUMemBuffer>>();
+        let backend = self.backend;
+        let qp_options = self.qp_options;
         let _ = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async {
-                loop {
-                    let completion_reqs = completion_reqs.clone();
-                    tokio::select! {
-                        Ok(Command::Disconnect()) = (&mut cmd_rx) => {
-                            cuda::cuda_device_primary_ctx_release(gpu_ordinal).unwrap();
-                            break;
-                        }
-                        Ok(mut cm_id) = rdma::listen(&mut listen_id) => {
-                            info!("start qp handshake");
-                            let gpu_buffers = gpu_buffers.clone();
-                            rt.spawn( async move {
-                                match rdma::accept(&mut cm_id, gpu_ordinal, gpu_buffers).await {
-                                    Ok((_conn, (mut cpu_mr, mut cpu_buffer), _)) =>loop {
-                                        let notification = rdma::handle_notification(&mut cm_id, &mut cpu_mr, &mut cpu_buffer).await.unwrap();
-                                        if notification.done > 0 {
-                                            info!("notifcation: {:?}" , notification);
-                                            break;
-                                        }
-                                        
-                                        if let Some(req_id) = &notification.req_id {
-                                            let mut reqs = completion_reqs.write().unwrap();
-                                            reqs.add_req(req_id);
-                                            if reqs.is_full() {
-                                                reqs.remove_first();
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        error!("exchange qp failed: {:?}", e);
-                                    }
-                                };
-                                rdma::server_disconnect(&mut cm_id).unwrap();
-                            });
-                        }
+                match backend {
+                    Backend::Rdma => {
+                        serve::<RdmaTransport>(
+                            sock_addr,
+                            qp_options,
+                            gpu_ordinal,
+                            gpu_buffers,
+                            cmd_rx,
+                            completion_reqs,
+                            bound_tx,
+                        )
+                        .await
+                    }
+                    Backend::RdmaSrq => {
+                        serve::<RdmaSrqTransport>(
+                            sock_addr,
+                            qp_options,
+                            gpu_ordinal,
+                            gpu_buffers,
+                            cmd_rx,
+                            completion_reqs,
+                            bound_tx,
+                        )
+                        .await
+                    }
+                    Backend::Tcp => {
+                        serve::<TcpTransport>(
+                            sock_addr,
+                            (),
+                            gpu_ordinal,
+                            gpu_buffers,
+                            cmd_rx,
+                            completion_reqs,
+                            bound_tx,
+                        )
+                        .await
+                    }
+                    Backend::Loopback => {
+                        serve::<LoopbackTransport>(
+                            sock_addr,
+                            (),
+                            gpu_ordinal,
+                            gpu_buffers,
+                            cmd_rx,
+                            completion_reqs,
+                            bound_tx,
+                        )
+                        .await
                     }
                 }
             });
 
             info!("runtime end at {:?}", Instant::now());
         });
+
+        match py.allow_threads(move || bound_rx.blocking_recv()) {
+            Ok(Ok(())) => Ok(()),
+            bound => {
+                self.cmd_sender = None;
+                self.completion_reqs = None;
+                match bound {
+                    Ok(Err(e)) => Err(into_py_err(e)),
+                    _ => Err(TransportError::new_err(
+                        "server runtime exited before binding",
+                    )),
+                }
+            }
+        }
     }
 
     fn is_complete(&mut self, req_id: Vec<u8>) -> bool {
@@ -109,9 +196,100 @@ impl VllmRdmaServer {
         }
     }
 
+    /// Resolves once the client reports `req_id` complete.
+    async fn wait_complete(slf: Py<Self>, req_id: Vec<u8>) -> PyResult<()> {
+        let completion_reqs = Python::with_gil(|py| slf.borrow(py).completion_reqs.clone())
+            .ok_or_else(|| TransportError::new_err("server is not listening"))?;
+        let changed = completion_reqs.read().unwrap().changed();
+        loop {
+            // registered before the check, so an add between the check and the await still wakes it
+            let notified = changed.notified();
+            {
+                let reqs = completion_reqs.read().unwrap();
+                if reqs.is_req_complete(&req_id) {
+                    return Ok(());
+                }
+                if reqs.is_closed() {
+                    return Err(TransportError::new_err("server shut down"));
+                }
+            }
+            notified.await;
+        }
+    }
+
     fn shutdown(&mut self) {
         if let Some(sender) = self.cmd_sender.take() {
             let _ = sender.send(Command::Disconnect());
         }
+        if let Some(completion_reqs) = &self.completion_reqs {
+            completion_reqs.write().unwrap().close();
+        }
+    }
+}
+
+async fn serve<T: Transport>(
+    sock_addr: SocketAddr,
+    options: T::Options,
+    gpu_ordinal: i32,
+    gpu_buffers: Vec<GPUMemBuffer>,
+    mut cmd_rx: Receiver<Command>,
+    completion_reqs: Arc<RwLock<CompletionReqs>>,
+    bound_tx: oneshot::Sender<Result<()>>,
+) {
+    let mut listener = match T::bind(&sock_addr, options) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = bound_tx.send(Err(e));
+            return;
+        }
+    };
+    let _ = bound_tx.send(Ok(()));
+    loop {
+        let completion_reqs = completion_reqs.clone();
+        tokio::select! {
+            Ok(Command::Disconnect()) = (&mut cmd_rx) => {
+                if let Err(e) = cuda::cuda_device_primary_ctx_release(gpu_ordinal) {
+                    error!("release primary context failed: {:?}", e);
+                }
+                break;
+            }
+            Ok(incoming) = T::listen(&mut listener) => {
+                info!("start qp handshake");
+                let gpu_buffers = gpu_buffers.clone();
+                tokio::spawn(async move {
+                    match T::accept(incoming, gpu_ordinal, gpu_buffers).await {
+                        Ok(mut conn) => {
+                            loop {
+                                let notification = match conn.recv_notification().await {
+                                    Ok(notification) => notification,
+                                    Err(e) => {
+                                        error!("receive notification failed: {:?}", e);
+                                        break;
+                                    }
+                                };
+                                if notification.done > 0 {
+                                    info!("notifcation: {:?}" , notification);
+                                    break;
+                                }
+
+                                if let Some(req_id) = &notification.req_id {
+                                    let mut reqs = completion_reqs.write().unwrap();
+                                    reqs.add_req(req_id);
+                                    if reqs.is_full() {
+                                        reqs.remove_first();
+                                    }
+                                }
+                            }
+                            if let Err(e) = conn.disconnect().await {
+                                error!("disconnect failed: {:?}", e);
+                            }
+                        }
+                        Err(e) => {
+                            error!("exchange qp failed: {:?}", e);
+                        }
+                    };
+                });
+            }
+        }
     }
 }
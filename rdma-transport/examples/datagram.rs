@@ -0,0 +1,33 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use rdma_transport::rdma::{DatagramEndpoint, Notification, RdmaDevice};
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let local_addr = "192.168.14.224:0".parse::<SocketAddr>()?;
+
+    let device = RdmaDevice::for_local(&local_addr)?;
+    let mut ping = DatagramEndpoint::new(&device)?;
+    let mut pong = DatagramEndpoint::new(&device)?;
+    println!(
+        "ping at {:?}, pong at {:?}",
+        ping.local_addr(),
+        pong.local_addr()
+    );
+
+    for i in 0..4u8 {
+        let notification = Notification {
+            done: 0,
+            req_id: Some(vec![i]),
+        };
+        ping.send(&pong.local_addr(), &notification).await?;
+        let (from, notification) = pong.recv().await?;
+        println!("pong got {:?} from qpn {}", notification.req_id, from.qpn);
+
+        pong.send(&from, &notification).await?;
+        let (_, notification) = ping.recv().await?;
+        println!("ping got {:?} back", notification.req_id);
+    }
+    Ok(())
+}
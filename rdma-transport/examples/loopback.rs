@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+
+use rdma_transport::rdma::Notification;
+use rdma_transport::transport::{LoopbackTransport, Transport};
+use rdma_transport::GPUMemBuffer;
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    let bind_addr = "127.0.0.1:23460".parse::<SocketAddr>()?;
+    let buffer_size = 4096;
+    let loops = 16;
+
+    let mut server_memory = vec![0u8; buffer_size];
+    let server_buffer = GPUMemBuffer::new(server_memory.as_mut_ptr() as u64, buffer_size);
+
+    let mut listener = LoopbackTransport::bind(&bind_addr, ())?;
+    let server = tokio::spawn(async move {
+        let incoming = LoopbackTransport::listen(&mut listener).await?;
+        let mut conn = LoopbackTransport::accept(incoming, 0, vec![server_buffer]).await?;
+        let mut received = 0;
+        loop {
+            let notification = conn.recv_notification().await?;
+            if notification.done == 1 {
+                break;
+            }
+            if let Some(req_id) = &notification.req_id {
+                println!("request {} complete", String::from_utf8_lossy(req_id));
+            }
+            received += 1;
+        }
+        conn.disconnect().await?;
+        Ok::<usize, rdma_transport::TransportErrors>(received)
+    });
+
+    let mut client_memory = b"Hello, loopback!".repeat(buffer_size / 16);
+    let client_buffer = GPUMemBuffer::new(client_memory.as_mut_ptr() as u64, buffer_size);
+    let mut conn = LoopbackTransport::connect(bind_addr, 0, vec![client_buffer], ()).await?;
+
+    let remote_base_ptr = *conn.remote_buffers().keys().next().unwrap();
+    let block_size = (buffer_size / loops) as u32;
+    for i in 0..loops as u64 {
+        let offset = i * block_size as u64;
+        conn.write(
+            client_buffer.get_base_ptr() + offset,
+            remote_base_ptr + offset,
+            block_size,
+        )
+        .await?;
+        conn.notify(&Notification {
+            done: 0,
+            req_id: Some(format!("request: {}", i).into_bytes()),
+        })
+        .await?;
+    }
+    conn.disconnect().await?;
+
+    let received = server.await??;
+    assert_eq!(received, loops);
+    assert_eq!(server_memory, client_memory);
+    println!(
+        "loops: {}, data: {}",
+        received,
+        String::from_utf8_lossy(&server_memory[0..16])
+    );
+
+    Ok(())
+}
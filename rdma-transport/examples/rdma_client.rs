@@ -1,5 +1,4 @@
 use std::net::SocketAddr;
-use std::ops::DerefMut;
 use std::time::Instant;
 
 use anyhow::Result;
@@ -24,10 +23,11 @@ pub async fn main() -> Result<()> {
         local_gpu_buffers.push(cuda_mem_alloc(GPU_BUFFER_BASE_SIZE)?);
     }
 
-    let mut cm_id = rdma::client_init(server_addr)?;
-
-    let (cpu_conn, (mut cpu_mr, mut cpu_buffer), mut local_gpu_buffer_map, remote_gpu_conn_map) =
-        rdma::connect(&mut cm_id, gpu_ordinal, local_gpu_buffers.clone())?;
+    let options = rdma::QpOptions::default();
+    // connect from the NIC closest to the GPU
+    let device = rdma::RdmaDevice::for_gpu(gpu_ordinal, None)?;
+    let endpoint = rdma::client_init(server_addr, &device, &options)?;
+    let mut conn = rdma::connect(endpoint, gpu_ordinal, local_gpu_buffers.clone()).await?;
 
     let origin_msg = "Hello, RDMA! The voice echoed through the dimly lit control room. The array of monitors flickered to life, displaying a mesmerizing array of data streams, holographic charts, and real-time simulations. Sitting at the central console was Dr. Elara Hinton, a leading expert in quantum computing and neural networks.".as_bytes();
 
@@ -39,15 +39,13 @@ pub async fn main() -> Result<()> {
         cuda_host_to_device(&msg[0..GPU_BUFFER_BASE_SIZE], &local_gpu_buffers[i])?;
     }
 
-    let remote_base_ptrs = remote_gpu_conn_map.keys().collect::<Vec<&u64>>();
+    let remote_base_ptrs = conn.remote_buffers().keys().copied().collect::<Vec<u64>>();
 
     let start = Instant::now();
     for i in 0..loops {
         let gpu_buffer_index = i % gpu_buffer_count;
         let base_ptr = local_gpu_buffers[gpu_buffer_index].get_base_ptr();
-        let (gpu_mr, gpu_buffer) = local_gpu_buffer_map.get_mut(&base_ptr).unwrap();
         let remote_base_ptr = remote_base_ptrs[gpu_buffer_index];
-        let remote_gpu_conn = remote_gpu_conn_map.get(remote_base_ptr).unwrap();
 
         let notification = Notification {
             done: 0,
@@ -56,27 +54,10 @@ pub async fn main() -> Result<()> {
 
         // println!("sample data: {}", String::from_utf8_lossy(&msg[0..50]));
 
-        let size = bincode::serialized_size(&notification).unwrap();
-        bincode::serialize_into(cpu_buffer.deref_mut(), &notification)?;
-
-        rdma::write(
-            &mut cm_id,
-            remote_gpu_conn,
-            gpu_mr,
-            base_ptr,
-            remote_gpu_conn.get_base_ptr(),
-            msg_size,
-        )
-        .await?;
-        rdma::write_metadata(
-            &mut cm_id,
-            &cpu_conn,
-            &mut cpu_mr,
-            &mut cpu_buffer,
-            0,
-            size as u16,
-        )
-        .await?;
+        conn.write(base_ptr, remote_base_ptr, msg_size)
+            .await?
+            .await?;
+        conn.notify(&notification).await?;
     }
 
     let elapse = start.elapsed().as_millis();
@@ -86,7 +67,7 @@ pub async fn main() -> Result<()> {
         msg_size, loops, elapse, bw
     );
 
-    rdma::client_disconnect(&mut cm_id, &cpu_conn, &mut cpu_mr, &mut cpu_buffer).await?;
+    conn.close().await?;
 
     for gpu_buffer in local_gpu_buffers {
         cuda_mem_free(&gpu_buffer)?;
@@ -6,8 +6,6 @@ use rdma_transport::{rdma, GPUMemBuffer, GPU_BUFFER_BASE_SIZE};
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
-    let bind_addr = "192.168.14.224:23460".parse::<SocketAddr>()?;
-    // let bind_addr = "[::0]:32768".parse::<SocketAddr>()?;
     let gpu_ordinal = 4;
     let gpu_buffer_count = 4;
 
@@ -16,20 +14,22 @@ pub async fn main() -> Result<()> {
     for _ in 0..gpu_buffer_count {
         local_gpu_buffers.push(cuda_mem_alloc(GPU_BUFFER_BASE_SIZE)?);
     }
-    let mut listen_id = rdma::server_init(&bind_addr)?;
+    let options = rdma::QpOptions::default();
+    // the NIC closest to the GPU, with one PD for every client so the buffers are registered
+    // once
+    let device = rdma::RdmaDevice::for_gpu(gpu_ordinal, None)?;
+    let bind_addr = SocketAddr::new(device.local_addr().unwrap(), 23460);
+    let mut listener = rdma::server_init(&bind_addr, &device, &options)?;
 
-    while let Ok(mut cm_id) = rdma::listen(&mut listen_id).await {
+    while let Ok(endpoint) = rdma::listen(&mut listener).await {
         let local_gpu_buffers = local_gpu_buffers.clone();
         tokio::spawn(async move {
-            match rdma::accept(&mut cm_id, gpu_ordinal, local_gpu_buffers).await {
-                Ok((_conn, (mut cpu_mr, mut cpu_buffer), _)) => loop {
-                    let notification =
-                        rdma::handle_notification(&mut cm_id, &mut cpu_mr, &mut cpu_buffer)
-                            .await
-                            .unwrap();
+            match rdma::accept(endpoint, gpu_ordinal, local_gpu_buffers).await {
+                Ok(mut conn) => loop {
+                    let notification = conn.recv_notification().await.unwrap();
                     if notification.done == 1 {
                         println!("notifcation: {:?}", notification);
-                        rdma::server_disconnect(&mut cm_id).unwrap();
+                        conn.close().await.unwrap();
                         break;
                     } else {
                         // println!("notification: {:?}", notification);
@@ -8,6 +8,10 @@ pub const CPU_BUFFER_BASE_SIZE: usize = 4096; // 4KB
 pub const CPU_BUFFER_SIZE: usize = CPU_BUFFER_BASE_SIZE * OFFSET_SLOTS;
 pub const GPU_BUFFER_BASE_SIZE: usize = 1024 * 1024; // 1MB
 pub const GPU_BUFFER_SIZE: usize = GPU_BUFFER_BASE_SIZE * OFFSET_SLOTS;
+// a ring of OFFSET_SLOTS slots for each direction plus the consumer counters of notifications,
+// message fragments and rendezvous messages and the result word of remote atomics, see
+// rdma::RdmaConnection
+pub const CONTROL_BUFFER_SIZE: usize = CPU_BUFFER_SIZE * 2 + 56;
 
 #[derive(Debug, Clone, Copy)]
 pub struct GPUMemBuffer {
@@ -47,13 +51,17 @@ impl DerefMut for GPUMemBuffer {
 
 #[derive(Debug, Clone)]
 pub struct MemBuffer {
-    buffer: Box<[u8; CPU_BUFFER_BASE_SIZE]>,
+    buffer: Box<[u8; CONTROL_BUFFER_SIZE]>,
 }
 
 impl MemBuffer {
     pub fn new() -> MemBuffer {
         MemBuffer {
-            buffer: Box::new([0; CPU_BUFFER_BASE_SIZE]),
+            // allocated on the heap directly, the array is too big to build on the stack first
+            buffer: vec![0; CONTROL_BUFFER_SIZE]
+                .into_boxed_slice()
+                .try_into()
+                .unwrap(),
         }
     }
 
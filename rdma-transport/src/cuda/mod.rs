@@ -1,8 +1,8 @@
-use std::{ops::DerefMut, ptr};
+use std::ptr;
 
 use cuda::{cuda_call, CuCtx, CuEvent, CuStream};
 use cuda_sys::{
-    cuCtxCreate_v2, cuCtxSetCurrent, cuDeviceGet, cuDevicePrimaryCtxRelease_v2, cuDevicePrimaryCtxRetain, cuEventCreate, cuEventQuery, cuInit, cuMemAlloc_v2, cuMemFree_v2, cuMemcpyDtoH_v2_ptds as cuMemcpyDtoH_v2, cuMemcpyHtoD_v2_ptds as cuMemcpyHtoD_v2, cuStreamCreate, cuStreamWaitEvent_ptsz, CU_CTX_MAP_HOST, CU_EVENT_DISABLE_TIMING, CU_EVENT_WAIT_DEFAULT, CU_STREAM_NON_BLOCKING
+    cuCtxCreate_v2, cuCtxSetCurrent, cuDeviceGet, cuDeviceGetPCIBusId, cuDevicePrimaryCtxRelease_v2, cuDevicePrimaryCtxRetain, cuEventCreate, cuEventQuery, cuInit, cuMemAlloc_v2, cuMemFree_v2, cuMemcpy_ptds as cuMemcpy, cuMemcpyDtoH_v2_ptds as cuMemcpyDtoH_v2, cuMemcpyHtoD_v2_ptds as cuMemcpyHtoD_v2, cuStreamCreate, cuStreamWaitEvent_ptsz, CU_CTX_MAP_HOST, CU_EVENT_DISABLE_TIMING, CU_EVENT_WAIT_DEFAULT, CU_STREAM_NON_BLOCKING
 };
 
 use crate::{GPUMemBuffer, Result};
@@ -41,9 +41,47 @@ pub fn cuda_device_primary_ctx_release(gpu_ordinal: i32) -> Result<()> {
     Ok(())
 }
 
+// the GPU's PCI address as the driver prints it, e.g. `0000:3B:00.0`
+pub fn cuda_device_pci_bus_id(gpu_ordinal: i32) -> Result<String> {
+    let mut cu_dev = 0;
+    let mut bus_id = [0 as std::ffi::c_char; 32];
+    cuda_call!(cuInit, cuInit(0))?;
+    cuda_call!(cuDeviceGet, cuDeviceGet(&mut cu_dev, gpu_ordinal))?;
+    cuda_call!(
+        cuDeviceGetPCIBusId,
+        cuDeviceGetPCIBusId(bus_id.as_mut_ptr(), bus_id.len() as i32, cu_dev)
+    )?;
+    let bus_id = unsafe { std::ffi::CStr::from_ptr(bus_id.as_ptr()) };
+    Ok(bus_id.to_string_lossy().into_owned())
+}
+
 pub fn cuda_set_current_ctx(cu_ctx: &mut CuCtx) -> Result<()> {
-    let cu_ctx: *mut cuda_sys::CUctx_st = cu_ctx.deref_mut();
-    cuda_call!(cuCtxSetCurrent, cuCtxSetCurrent(cu_ctx)).map_err(|e| e.into())
+    cuda_call!(cuCtxSetCurrent, cuCtxSetCurrent(cu_ctx.as_ptr())).map_err(|e| e.into())
+}
+
+/// A device's primary context, retained for as long as this is alive.
+#[derive(Debug)]
+pub struct PrimaryCtx {
+    gpu_ordinal: i32,
+    cu_ctx: CuCtx,
+}
+
+impl PrimaryCtx {
+    pub fn retain(gpu_ordinal: i32) -> Result<PrimaryCtx> {
+        let cu_ctx = cuda_device_primary_ctx_retain(gpu_ordinal)?;
+        Ok(PrimaryCtx { gpu_ordinal, cu_ctx })
+    }
+
+    pub fn set_current(&self) -> Result<()> {
+        let mut cu_ctx = self.cu_ctx;
+        cuda_set_current_ctx(&mut cu_ctx)
+    }
+}
+
+impl Drop for PrimaryCtx {
+    fn drop(&mut self) {
+        let _ = cuda_device_primary_ctx_release(self.gpu_ordinal);
+    }
 }
 
 pub fn cuda_mem_alloc(size: usize) -> Result<GPUMemBuffer> {
@@ -96,6 +134,11 @@ pub fn cuda_device_to_host(device_buffer: &GPUMemBuffer, host_buffer: &mut [u8],
     .map_err(|e| e.into())
 }
 
+// copies between any two addresses in the unified address space, host or device
+pub fn cuda_memcpy(dst: u64, src: u64, size: usize) -> Result<()> {
+    cuda_call!(cuMemcpy, cuMemcpy(dst, src, size)).map_err(|e| e.into())
+}
+
 pub fn cuda_create_stream() -> Result<CuStream> {
     let mut cu_stream = ptr::null_mut();
 
@@ -117,7 +160,7 @@ pub fn cuda_create_event() -> Result<CuEvent> {
 
 pub fn cuda_query_event(event: &mut CuEvent) -> Result<bool> {
     let ret = unsafe {
-        cuEventQuery(event.deref_mut())
+        cuEventQuery(event.as_ptr())
     };
     if ret == cuda_sys::CUDA_SUCCESS {
         Ok(true)
@@ -131,7 +174,7 @@ pub fn cuda_query_event(event: &mut CuEvent) -> Result<bool> {
 pub fn cuda_wait_evnet(event: &mut CuEvent, stream: &mut CuStream) -> Result<()> {
     cuda_call!(
         cuStreamWaitEvent,
-        cuStreamWaitEvent_ptsz(stream.deref_mut(), event.deref_mut(), CU_EVENT_WAIT_DEFAULT)
+        cuStreamWaitEvent_ptsz(stream.as_ptr(), event.as_ptr(), CU_EVENT_WAIT_DEFAULT)
     )?;
     Ok(())
 }
@@ -8,8 +8,14 @@ pub enum TransportErrors {
     RdmaErrors(RdmaErrors),
     #[error("Cuda error: {0}")]
     CudaErrors(CudaErrors),
+    #[error("Io error: {0}")]
+    IoErrors(std::io::Error),
     #[error("ops {0} failed with msg {1} ")]
     OpsFailed(String, String),
+    #[error("handshake rejected: {0}")]
+    HandshakeRejected(String),
+    #[error("timed out: {0}")]
+    TimedOut(String),
 }
 
 impl From<RdmaErrors> for TransportErrors {
@@ -24,4 +30,10 @@ impl From<CudaErrors> for TransportErrors {
     }
 }
 
+impl From<std::io::Error> for TransportErrors {
+    fn from(value: std::io::Error) -> Self {
+        TransportErrors::IoErrors(value)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, TransportErrors>;
@@ -2,9 +2,12 @@ mod buffer;
 pub mod cuda;
 mod errors;
 pub mod rdma;
+pub mod rpc;
+pub mod topology;
+pub mod transport;
 pub use buffer::{
-    GPUMemBuffer, MemBuffer, CPU_BUFFER_BASE_SIZE, CPU_BUFFER_SIZE, GPU_BUFFER_BASE_SIZE,
-    GPU_BUFFER_SIZE,
+    GPUMemBuffer, MemBuffer, CONTROL_BUFFER_SIZE, CPU_BUFFER_BASE_SIZE, CPU_BUFFER_SIZE,
+    GPU_BUFFER_BASE_SIZE, GPU_BUFFER_SIZE, OFFSET_SLOTS,
 };
 
 pub use errors::{Result, TransportErrors};
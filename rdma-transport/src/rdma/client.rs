@@ -1,184 +1,181 @@
-use std::{collections::HashMap, net::SocketAddr, ops::DerefMut};
+use std::{net::SocketAddr, ops::DerefMut};
 
 use os_socketaddr::OsSocketAddr;
 
 use rdma_core::{
-    ibverbs::{ibv_modify_qp, ibv_poll_cq, ibv_reg_mr, IbvMr, IbvQpInitAttr},
-    rdma::{
-        rdma_connect, rdma_create_ep, rdma_disconnect, rdma_getaddrinfo, rdma_post_recv,
-        rdma_post_send, RdmaAddrInfo, RdmaCmId,
-    },
+    ibverbs::{ibv_modify_qp, ibv_reg_mr, IbvMr},
+    rdma::{rdma_connect, rdma_create_ep, rdma_getaddrinfo, RdmaCmId},
 };
 use rdma_core_sys::{
-    ibv_qp_attr, ibv_wc, ntohl, IBV_ACCESS_LOCAL_WRITE, IBV_ACCESS_REMOTE_READ, IBV_ACCESS_REMOTE_WRITE, IBV_QPT_RC, IBV_QP_ACCESS_FLAGS, IBV_SEND_INLINE, IBV_WC_SUCCESS, RDMA_PS_TCP
+    ibv_qp_attr, rdma_addrinfo, IBV_QP_ACCESS_FLAGS, RDMA_PS_TCP
 };
 
 use crate::{
     cuda::{cuda_device_primary_ctx_retain,  cuda_set_current_ctx},
-    GPUMemBuffer, MemBuffer, Result, TransportErrors,
+    GPUMemBuffer, MemBuffer, Result,
 };
 
-use super::{write_metadata, Connection, Connections, Notification};
+use super::{
+    connection::Handshake,
+    message::recv_depth,
+    protocol::{
+        negotiate, recv_buffers, send_buffers, BufferTable, Hello, Negotiated,
+        MAX_CONNECT_PRIVATE_DATA,
+    },
+    server::qp_init_attr,
+    mr_access, qp_access, set_min_rnr_timer, sge_limits, CompletionQueue,
+    Connection, Connections, QpOptions, RdmaConnection, RdmaDevice, RdmaEndpoint,
+    RecvQueue,
+};
 
 // const BUFFER_SIZE: usize = 16 * 1024 * 1024;
 
-pub fn init(server_addr: SocketAddr) -> Result<RdmaCmId> {
-
-    let mut hints = RdmaAddrInfo::default();
+/// Resolves `server_addr` and creates the cm_id and its QP on `device`'s PD. The connection is
+/// made from the device's [`local_addr`](RdmaDevice::local_addr) if it has one; otherwise the
+/// device has to be the one the route to the server leaves through, see
+/// [`RdmaDevice::for_peer`].
+pub fn init(
+    server_addr: SocketAddr,
+    device: &RdmaDevice,
+    options: &QpOptions,
+) -> Result<RdmaEndpoint> {
+    let mut hints = rdma_addrinfo::default();
     hints.ai_port_space = RDMA_PS_TCP as i32;
+    // has to outlive the lookup, which copies it
+    let mut src_addr = device
+        .local_addr()
+        .map(|addr| OsSocketAddr::from(SocketAddr::new(addr, 0)));
+    if let Some(src_addr) = src_addr.as_mut() {
+        hints.ai_src_addr = src_addr.as_mut_ptr() as *mut _;
+        hints.ai_src_len = src_addr.len();
+    }
 
-    let mut addr_info = rdma_getaddrinfo(
+    let addr_info = rdma_getaddrinfo(
         &server_addr.ip().to_string(),
         &server_addr.port().to_string(),
         &hints,
     )?;
 
-    let mut qp_init_attr = IbvQpInitAttr::default();
-    qp_init_attr.cap.max_send_wr = 1;
-    qp_init_attr.cap.max_recv_wr = 1;
-    qp_init_attr.cap.max_send_sge = 1;
-    qp_init_attr.cap.max_recv_sge = 1;
-    qp_init_attr.cap.max_inline_data = 16;
-    qp_init_attr.qp_type = IBV_QPT_RC;
-    qp_init_attr.sq_sig_all = 1;
-    let cm_id = rdma_create_ep(&mut addr_info, None, Some(&mut qp_init_attr))?;
-    Ok(cm_id)
+    let mut qp_init_attr = qp_init_attr(options, 16);
+    qp_init_attr.cap.max_send_sge = sge_limits(device.pd().context())?.0;
+    let cm_id = rdma_create_ep(&addr_info, Some(device.pd()), Some(&mut qp_init_attr))?;
+    Ok(RdmaEndpoint {
+        cm_id,
+        device: device.clone(),
+        options: *options,
+    })
 }
 
-pub fn connect(
-    cm_id: &mut RdmaCmId,
+/// Connects the cm_id from [`init`], registers the local buffers and exchanges hellos and
+/// buffer tables with the server, failing with
+/// [`crate::TransportErrors::HandshakeRejected`] if the two are incompatible.
+pub async fn connect(
+    endpoint: RdmaEndpoint,
     gpu_ordinal: i32,
     gpu_buffers: Vec<GPUMemBuffer>,
-) -> Result<(
-    Connection,
-    (IbvMr, MemBuffer),
-    HashMap<u64, (IbvMr, GPUMemBuffer)>,
-    HashMap<u64, Connection>,
-)> {
+) -> Result<RdmaConnection> {
+    let (mut cm_id, options) = (endpoint.cm_id, &endpoint.options);
     let qp = cm_id.qp;
-    let pd = cm_id.pd;
+    let pd = endpoint.device.pd();
 
     let mut mod_attr = ibv_qp_attr::default();
-    mod_attr.qp_access_flags = IBV_ACCESS_REMOTE_READ | IBV_ACCESS_REMOTE_WRITE;
+    mod_attr.qp_access_flags = qp_access(pd);
     ibv_modify_qp(qp, &mut mod_attr, IBV_QP_ACCESS_FLAGS as i32)?;
 
-    let access = IBV_ACCESS_LOCAL_WRITE | IBV_ACCESS_REMOTE_WRITE | IBV_ACCESS_REMOTE_READ;
+    let access = mr_access(pd);
     let mut cpu_buffer: MemBuffer = MemBuffer::default();
-    let mut cpu_mr = ibv_reg_mr(pd, cpu_buffer.deref_mut(), access as i32)?;
+    let mut cpu_mr = ibv_reg_mr(pd, cpu_buffer.deref_mut(), access)?;
 
     let mut cu_ctx = cuda_device_primary_ctx_retain(gpu_ordinal)?;
     cuda_set_current_ctx(&mut cu_ctx)?;
 
-    let mut local_gpu_buffer_map: HashMap<u64, (IbvMr, GPUMemBuffer)> = HashMap::new();
-    for mut buffer in gpu_buffers.into_iter() {
-        let gpu_mr = ibv_reg_mr(pd, &mut buffer, access as i32)?;
-        local_gpu_buffer_map.insert(buffer.get_base_ptr(), (gpu_mr, buffer));
-    }
-
-    let server_conn = establish_conn(cm_id, &mut cpu_mr, &mut cpu_buffer)?;
-
-    // the following blocks try to recv the GPU mem conn information from server side
-    rdma_post_recv(
-        cm_id,
-        None::<&mut u32>,
-        cpu_buffer.get_ptr(),
-        cpu_buffer.get_size(),
-        &mut cpu_mr,
-    )?;
-
-    let mut wc = ibv_wc::default();
-    let recv_cq = cm_id.recv_cq;
-    ibv_poll_cq(recv_cq, 1, &mut wc)?;
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "connect".to_string(),
-            format!("poll_recv_comp failed with status: {:?}", wc.status),
+    // other connections on the same device may already have registered these buffers
+    let mr_cache = endpoint.device.mr_cache();
+    let mut local_buffers = Vec::new();
+    let mut conns = Connections::default();
+    for buffer in gpu_buffers.into_iter() {
+        let gpu_mr = mr_cache.register(&buffer)?;
+        conns.add(Connection::new(
+            buffer.get_base_ptr(),
+            buffer.get_size() as u64,
+            gpu_mr.rkey,
         ));
+        local_buffers.push(gpu_mr);
     }
 
-    if wc.opcode != rdma_core_sys::IBV_WC_RECV_RDMA_WITH_IMM {
-        return Err(TransportErrors::OpsFailed(
-            "connect".to_string(),
-            "expect IBV_WR_RDMA_WRITE_WITH_IMM opcode".to_string(),
-        ));
-    }
-
-    let imm_data = unsafe { ntohl(wc.__bindgen_anon_1.imm_data) };
-    let size = imm_data as usize;
-    let data = &cpu_buffer[0..size];
-    let server_gpu_conns = bincode::deserialize::<Connections>(data)
-        .map_err(|e| TransportErrors::OpsFailed("connect".to_string(), e.to_string()))?;
-    let mut remote_gpu_conns_map: HashMap<u64, Connection> = HashMap::new();
-    for conn in server_gpu_conns.into_iter() {
-        remote_gpu_conns_map.insert(conn.base_ptr, conn.to_owned());
-    }
-
-    Ok((
-        server_conn,
-        (cpu_mr, cpu_buffer),
-        local_gpu_buffer_map,
-        remote_gpu_conns_map,
-    ))
+    let table = BufferTable::new(pd, &conns)?;
+    let mut send_cq = CompletionQueue::new(&cm_id, cm_id.send_cq)?;
+    let mut recv = RecvQueue::own(&cm_id, options)?;
+    let (server_hello, protocol, server_buffers) = establish_conn(
+        &mut cm_id,
+        &mut send_cq,
+        &mut recv,
+        &mut cpu_mr,
+        &mut cpu_buffer,
+        &table,
+        options,
+    )
+    .await?;
+
+    let remote_gpu_conns_map = server_buffers
+        .iter()
+        .map(|conn| (conn.get_base_ptr(), conn.clone()))
+        .collect();
+
+    RdmaConnection::new(Handshake {
+        cm_id,
+        send_cq,
+        recv,
+        is_client: true,
+        protocol,
+        conn: server_hello.control,
+        cpu_mr,
+        cpu_buffer,
+        mr_cache,
+        local_buffers,
+        remote_buffers: remote_gpu_conns_map,
+        buffer_table: table,
+    })
 }
 
-fn establish_conn(
+async fn establish_conn(
     cm_id: &mut RdmaCmId,
+    send_cq: &mut CompletionQueue,
+    recv: &mut RecvQueue,
     cpu_mr: &mut IbvMr,
     cpu_buffer: &mut MemBuffer,
-) -> Result<Connection> {
-    let mut server_conn = Connection::default();
-    rdma_post_recv(
-        cm_id,
-        None::<&mut u32>,
-        &mut server_conn as *mut _ as u64,
-        std::mem::size_of::<Connection>(),
-        cpu_mr,
-    )?;
-
-    rdma_connect(cm_id, None)?;
-
-    let mut client_conn = Connection::new(cpu_buffer.get_ptr(), cpu_mr.rkey);
-    rdma_post_send(
+    table: &BufferTable,
+    options: &QpOptions,
+) -> Result<(Hello, Negotiated, Connections)> {
+    let hello = &Hello::new(
         cm_id,
-        None::<&mut u32>,
-        &mut client_conn,
-        std::mem::size_of::<Connection>(),
-        None,
-        IBV_SEND_INLINE,
+        Connection::new(
+            cpu_buffer.get_ptr(),
+            cpu_buffer.get_size() as u64,
+            cpu_mr.rkey,
+        ),
+        options.credits(),
     )?;
-    let mut wc = ibv_wc::default();
-    let send_cq = cm_id.send_cq;
-    ibv_poll_cq(send_cq, 1, &mut wc)?;
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "establish_conn".to_string(),
-            format!("poll_send_comp failed with status: {:?}", wc.status),
-        ));
-    }
-    let mut wc = ibv_wc::default();
-    let recv_cq = cm_id.recv_cq;
-    ibv_poll_cq(recv_cq, 1, &mut wc)?;
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "establish_conn".to_string(),
-            format!("poll_recv_comp failed with status: {:?}", wc.status),
-        ));
+    recv.post(cm_id, recv_depth(options) as usize)?;
+
+    let private_data = hello.encode(MAX_CONNECT_PRIVATE_DATA)?;
+    if let Err(e) = rdma_connect(cm_id, Some(&mut Hello::conn_param(&private_data, options))) {
+        // a server that turns us away puts its own hello in the reject, so both sides
+        // report the same reason
+        return Err(match Hello::decode(cm_id) {
+            Ok(server_hello) => negotiate(hello, &server_hello)
+                .err()
+                .unwrap_or_else(|| e.into()),
+            Err(_) => e.into(),
+        });
     }
-
-    Ok(server_conn)
-}
-
-pub async fn disconnect(
-    cm_id: &mut RdmaCmId,
-    conn: &Connection,
-    cpu_mr: &mut IbvMr,
-    cpu_buffer: &mut MemBuffer,
-) -> Result<()> {
-    let notification = Notification::complete();
-    let size = bincode::serialized_size(&notification).unwrap();
-    bincode::serialize_into(cpu_buffer.deref_mut(), &notification)
-        .map_err(|e| TransportErrors::OpsFailed("disconnect".to_string(), e.to_string()))?;
-
-    write_metadata(cm_id, conn, cpu_mr, cpu_buffer, 0, size as u16).await?;
-    rdma_disconnect(cm_id).map_err(|e| e.into())
+    let server_hello = Hello::decode(cm_id)?;
+    let protocol = negotiate(hello, &server_hello)?;
+    set_min_rnr_timer(cm_id, options)?;
+
+    // The client goes first; the server only answers once it has read this table, so ours is
+    // no longer needed by the time we read theirs.
+    send_buffers(cm_id, send_cq, cpu_mr, cpu_buffer, &server_hello, &protocol, table).await?;
+    let server_buffers = recv_buffers(cm_id, send_cq, recv, cpu_buffer, &protocol).await?;
+    Ok((server_hello, protocol, server_buffers))
 }
@@ -0,0 +1,103 @@
+use std::{
+    any::Any,
+    os::fd::{AsRawFd, RawFd},
+};
+
+use rdma_core::{
+    ibverbs::{ibv_ack_cq_events, ibv_get_cq_event, ibv_poll_cq, ibv_req_notify_cq, IbvCq},
+    rdma::RdmaCmId,
+    RdmaErrors,
+};
+use rdma_core_sys::{ibv_comp_channel, ibv_cq, ibv_wc};
+use tokio::io::unix::AsyncFd;
+
+use crate::{Result, TransportErrors};
+
+// the channel itself is owned by the cm_id or CQ handle, so dropping this must not close the fd
+struct ChannelFd(RawFd);
+
+impl AsRawFd for ChannelFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// A CQ whose completion channel is driven by the tokio reactor.
+///
+/// `poll` parks the task on the channel fd instead of spinning on `ibv_poll_cq`, so a
+/// connection waiting on the NIC does not hold a runtime worker.
+pub struct CompletionQueue {
+    cq: *mut ibv_cq,
+    channel: *mut ibv_comp_channel,
+    fd: AsyncFd<ChannelFd>,
+    // owns the CQ and its channel, a cm_id or the CQ handle itself; declared last so the fd
+    // is deregistered first
+    _owner: Box<dyn Any + Send>,
+}
+
+// The CQ and its channel can be driven from any thread, but not from two at once: concurrent
+// pollers would race on which of them arms the CQ and consumes the channel event. `poll` takes
+// `&mut self`, so the queue is Send but deliberately not Sync.
+unsafe impl Send for CompletionQueue {}
+
+impl CompletionQueue {
+    /// One of the CQs rdma_cm created for `cm_id`.
+    pub fn new(cm_id: &RdmaCmId, cq: *mut ibv_cq) -> Result<CompletionQueue> {
+        Self::with_owner(cq, Box::new(cm_id.clone()))
+    }
+
+    /// A CQ created on its own with `ibv_create_cq`.
+    pub fn from_cq(cq: IbvCq) -> Result<CompletionQueue> {
+        Self::with_owner(cq.as_ptr(), Box::new(cq))
+    }
+
+    fn with_owner(cq: *mut ibv_cq, owner: Box<dyn Any + Send>) -> Result<CompletionQueue> {
+        let channel = unsafe { (*cq).channel };
+        if channel.is_null() {
+            return Err(TransportErrors::OpsFailed(
+                "completion_queue".to_string(),
+                "cq was created without a completion channel".to_string(),
+            ));
+        }
+
+        let fd = unsafe { (*channel).fd };
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        // SAFETY: the channel lives as long as the owner, which this queue keeps alive
+        let fd = unsafe { AsyncFd::register(ChannelFd(fd)) }.map_err(std::io::Error::from)?;
+        Ok(CompletionQueue {
+            cq,
+            channel,
+            fd,
+            _owner: owner,
+        })
+    }
+
+    pub async fn poll(&mut self) -> Result<ibv_wc> {
+        let mut wc = ibv_wc::default();
+        loop {
+            if ibv_poll_cq(self.cq, 1, &mut wc)? > 0 {
+                return Ok(wc);
+            }
+
+            // arm, then poll once more: a completion that landed before the arm
+            // generates no event
+            ibv_req_notify_cq(self.cq, false)?;
+            if ibv_poll_cq(self.cq, 1, &mut wc)? > 0 {
+                return Ok(wc);
+            }
+
+            let mut guard = self.fd.readable().await?;
+            match ibv_get_cq_event(self.channel) {
+                Ok(cq) => ibv_ack_cq_events(cq, 1),
+                Err(RdmaErrors::OpsFailed(_, errno)) if errno == libc::EAGAIN => {
+                    guard.clear_ready()
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
@@ -0,0 +1,770 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ptr,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use rdma_core::{
+    ibverbs::{ibv_query_qp, ibv_reg_mr, ibv_reg_mr_read_only, IbvMr},
+    rdma::{rdma_disconnect, RdmaCmId},
+};
+use rdma_core_sys::{
+    ibv_qp_attr, ntohl, IBV_ACCESS_LOCAL_WRITE, IBV_ACCESS_REMOTE_READ, IBV_QP_CAP, IBV_WC_RECV,
+    IBV_WC_RECV_RDMA_WITH_IMM, IBV_WC_SUCCESS,
+};
+
+use crate::{
+    transport::{coalesce, find_remote_buffer},
+    MemBuffer, Result, TransportErrors, CPU_BUFFER_BASE_SIZE, OFFSET_SLOTS,
+};
+
+use super::{
+    message::{
+        Descriptor, Inbox, SendRing, EAGER_LIMIT, MAX_MESSAGE_SIZE, MESSAGE_CREDITS, RENDEZVOUS,
+    },
+    protocol::{
+        BufferTable, Negotiated, FEATURE_MESSAGES, FEATURE_NOTIFY_RING, FEATURE_RDMA_READ,
+        FEATURE_REMOTE_ATOMIC, FEATURE_RENDEZVOUS,
+    },
+    sge_limits, AtomicOp, CachedMr, CompletionQueue, Connection, MrCache, Notification, RecvQueue,
+    Segment, Transfer, TransferEngine,
+};
+
+// Layout of the control buffer. The peer writes its notifications into RECV_RING and how many
+// of ours it has consumed into PEER_RECEIVED; SEND_RING and RECEIVED stage the same for the
+// peer's buffer. A notification's immediate is `(slot << 16) | size`. ATOMIC_RESULT receives
+// the prior value of the remote word an atomic operated on. PEER_FRAGMENTS and FRAGMENTS are
+// the consumer count of message fragments, like PEER_RECEIVED and RECEIVED, and PEER_FETCHED and
+// FETCHED how many rendezvous messages have been read.
+const SLOT_SIZE: usize = CPU_BUFFER_BASE_SIZE;
+const RECV_RING: usize = 0;
+const SEND_RING: usize = OFFSET_SLOTS * SLOT_SIZE;
+const PEER_RECEIVED: usize = 2 * OFFSET_SLOTS * SLOT_SIZE;
+const RECEIVED: usize = PEER_RECEIVED + 8;
+const ATOMIC_RESULT: usize = RECEIVED + 8;
+const PEER_FRAGMENTS: usize = ATOMIC_RESULT + 8;
+const FRAGMENTS: usize = PEER_FRAGMENTS + 8;
+const PEER_FETCHED: usize = FRAGMENTS + 8;
+const FETCHED: usize = PEER_FETCHED + 8;
+
+// a single RDMA read must stay under the 2GB limit on the length of a message
+const READ_CHUNK: usize = 1 << 30;
+
+// Waiting on a counter the peer's NIC writes yields for SPIN_ROUNDS polls, then sleeps for
+// twice as long after every poll, up to MAX_BACKOFF.
+const SPIN_ROUNDS: u32 = 16;
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
+/// An established RC connection and everything registered for it.
+///
+/// Owns the cm_id, the control buffer used for notifications, its holds on the local memory
+/// registrations and the peer's buffer table. Dropping it deregisters the MRs no other
+/// connection holds and destroys the cm_id.
+///
+/// Notifications go through a ring of `OFFSET_SLOTS` slots in the peer's control buffer. The
+/// peer keeps a receive posted for each credit it grants, so up to that many can wait for it to
+/// consume them before [`notify`](Self::notify) blocks.
+///
+/// Messages are SENDs on the same QP, so they stay ordered with the transfers posted before
+/// them. They are split into fragments that take the same receives as notifications, of which
+/// the peer keeps `MESSAGE_CREDITS` more posted. A message over `EAGER_LIMIT` bytes only
+/// sends where it is, and the peer RDMA-reads it before taking the next one.
+pub struct RdmaConnection {
+    cm_id: RdmaCmId,
+    engine: TransferEngine,
+    recv: RecvQueue,
+    is_client: bool,
+    protocol: Negotiated,
+    // the peer's control buffer
+    conn: Connection,
+    cpu_mr: IbvMr,
+    cpu_buffer: MemBuffer,
+    // where transfers look up the lkeys of local buffers; the holds keep the buffers the
+    // connection was set up with registered
+    mr_cache: Arc<MrCache>,
+    _local_buffers: Vec<CachedMr>,
+    remote_buffers: HashMap<u64, Connection>,
+    // notifications sent and received so far, and the received count last told to the peer
+    sent: u64,
+    received: u64,
+    published: u64,
+    outbox: SendRing,
+    inbox: Inbox,
+    // notifications received but not handed out yet
+    notifications: VecDeque<Notification>,
+    // the same counts for message fragments
+    fragments_sent: u64,
+    fragments_received: u64,
+    fragments_published: u64,
+    // what is left of a message whose send was cancelled after its first fragment went out
+    unsent: Bytes,
+    // the rendezvous message being read, and how many have been sent, read and acknowledged
+    fetching: Option<Fetch>,
+    rendezvous_sent: u64,
+    fetched: u64,
+    fetched_published: u64,
+    // the registrations of our rendezvous messages the peer may still be reading, oldest
+    // first, and the one whose descriptor is being sent, with the fragment it goes out in
+    lent: VecDeque<IbvMr>,
+    lending: Option<(u64, IbvMr)>,
+    // the peer may still be reading it when our side of the handshake returns
+    _buffer_table: BufferTable,
+}
+
+// what the handshake in client.rs/server.rs hands over to the connection
+pub(super) struct Handshake {
+    pub cm_id: RdmaCmId,
+    pub send_cq: CompletionQueue,
+    pub recv: RecvQueue,
+    pub is_client: bool,
+    pub protocol: Negotiated,
+    pub conn: Connection,
+    pub cpu_mr: IbvMr,
+    pub cpu_buffer: MemBuffer,
+    pub mr_cache: Arc<MrCache>,
+    pub local_buffers: Vec<CachedMr>,
+    pub remote_buffers: HashMap<u64, Connection>,
+    pub buffer_table: BufferTable,
+}
+
+// where a rendezvous message is read to; kept by the connection, so a fetch that is cancelled
+// leaves nothing the NIC may still write into
+struct Fetch {
+    data: Vec<u8>,
+    mr: IbvMr,
+    transfer: Option<Transfer>,
+}
+
+impl RdmaConnection {
+    pub(super) fn new(mut handshake: Handshake) -> Result<RdmaConnection> {
+        // the provider may round max_send_wr up, so size the engine from the QP itself
+        let mut attr = ibv_qp_attr::default();
+        ibv_query_qp(handshake.cm_id.qp, &mut attr, IBV_QP_CAP as i32, None)?;
+        let (max_write_sge, max_read_sge) = sge_limits(handshake.cm_id.context())?;
+        let engine = TransferEngine::new(
+            &handshake.cm_id,
+            handshake.send_cq,
+            attr.cap.max_send_wr,
+            (
+                max_write_sge.min(attr.cap.max_send_sge),
+                max_read_sge.min(attr.cap.max_send_sge),
+            ),
+        )?;
+        // replaces the receive the peer's buffer table took
+        handshake.recv.post(&handshake.cm_id, 1)?;
+        let outbox = SendRing::new(&handshake.cm_id)?;
+
+        Ok(RdmaConnection {
+            cm_id: handshake.cm_id,
+            engine,
+            recv: handshake.recv,
+            is_client: handshake.is_client,
+            protocol: handshake.protocol,
+            conn: handshake.conn,
+            cpu_mr: handshake.cpu_mr,
+            cpu_buffer: handshake.cpu_buffer,
+            mr_cache: handshake.mr_cache,
+            _local_buffers: handshake.local_buffers,
+            remote_buffers: handshake.remote_buffers,
+            sent: 0,
+            received: 0,
+            published: 0,
+            outbox,
+            inbox: Inbox::default(),
+            notifications: VecDeque::new(),
+            fragments_sent: 0,
+            fragments_received: 0,
+            fragments_published: 0,
+            unsent: Bytes::new(),
+            fetching: None,
+            rendezvous_sent: 0,
+            fetched: 0,
+            fetched_published: 0,
+            lent: VecDeque::new(),
+            lending: None,
+            _buffer_table: handshake.buffer_table,
+        })
+    }
+
+    /// The peer's GPU buffers keyed by base address.
+    pub fn remote_buffers(&self) -> &HashMap<u64, Connection> {
+        &self.remote_buffers
+    }
+
+    /// The protocol version and features agreed on during the handshake.
+    pub fn protocol(&self) -> &Negotiated {
+        &self.protocol
+    }
+
+    pub async fn write(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> Result<Transfer> {
+        self.write_many(&[(local_buffer_addr, remote_buffer_addr, size)])
+            .await
+    }
+
+    pub async fn read(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> Result<Transfer> {
+        self.read_many(&[(local_buffer_addr, remote_buffer_addr, size)])
+            .await
+    }
+
+    /// Posts every `(local, remote, size)` block as one batch.
+    pub async fn write_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        let (segments, registrations) = self.segments(blocks)?;
+        self.engine.write_many(&segments, registrations).await
+    }
+
+    pub async fn read_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        if !self.protocol.has(FEATURE_RDMA_READ) {
+            return Err(TransportErrors::OpsFailed(
+                "read_many".to_string(),
+                "the peer does not accept RDMA reads".to_string(),
+            ));
+        }
+        let (segments, registrations) = self.segments(blocks)?;
+        self.engine.read_many(&segments, registrations).await
+    }
+
+    // Posted behind any outstanding writes: the QP executes them in order, so the peer only
+    // sees the notification once the data has landed. Waits while all of the peer's credits
+    // are taken by notifications it has not consumed yet.
+    pub async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        let size = bincode::serialized_size(notification)
+            .map_err(|e| TransportErrors::OpsFailed("notify".to_string(), e.to_string()))?
+            as usize;
+        if size > self.protocol.max_message_size as usize {
+            return Err(TransportErrors::OpsFailed(
+                "notify".to_string(),
+                format!(
+                    "notification of {} bytes exceeds the negotiated {} bytes",
+                    size, self.protocol.max_message_size
+                ),
+            ));
+        }
+
+        // a peer without the ring takes every notification in slot 0
+        let slot = if self.protocol.has(FEATURE_NOTIFY_RING) {
+            let mut backoff = Backoff::default();
+            // saturating, the counter is the peer's word
+            while self.sent.saturating_sub(self.counter(PEER_RECEIVED))
+                >= self.protocol.send_credits as u64
+            {
+                backoff.wait().await;
+            }
+            (self.sent % OFFSET_SLOTS as u64) as usize
+        } else {
+            0
+        };
+
+        let offset = slot * SLOT_SIZE;
+        let staging = SEND_RING + offset;
+        bincode::serialize_into(
+            &mut self.cpu_buffer[staging..staging + SLOT_SIZE],
+            notification,
+        )
+        .map_err(|e| TransportErrors::OpsFailed("notify".to_string(), e.to_string()))?;
+        let transfer = self
+            .engine
+            .write_with_imm(
+                &self.cpu_mr,
+                &self.conn,
+                self.cpu_buffer.get_ptr() + staging as u64,
+                self.conn.get_base_ptr() + (RECV_RING + offset) as u64,
+                size as u32,
+                ((slot << 16) | size) as u32,
+            )
+            .await?;
+        // counted once posted, as the peer may get it even if the wait below is cancelled or
+        // fails, and the slot must not be taken again until the peer has consumed it
+        self.sent += 1;
+        transfer.await
+    }
+
+    pub async fn recv_notification(&mut self) -> Result<Notification> {
+        loop {
+            if let Some(notification) = self.notifications.pop_front() {
+                return Ok(notification);
+            }
+            self.next_received().await?;
+        }
+    }
+
+    /// The notifications taken in while waiting for messages that nobody has asked for yet,
+    /// for a caller that only wants messages and would otherwise let them pile up.
+    pub fn drain_notifications(&mut self) -> impl Iterator<Item = Notification> + '_ {
+        self.notifications.drain(..)
+    }
+
+    /// Sends `message` in as many fragments as it takes, waiting while all of the peer's
+    /// message credits are in use. What arrives meanwhile is taken in, so two sides sending to
+    /// each other at once do not wait on each other's credits forever.
+    ///
+    /// Dropping the future before the first fragment has gone out sends nothing. Once it has,
+    /// the peer is in the middle of the message, so the rest is copied aside and sent ahead of
+    /// the next message; a cancelled send may therefore still arrive, but always whole.
+    ///
+    /// A message over `EAGER_LIMIT` bytes is registered for the peer to read instead, if it
+    /// supports that, and this waits until it has. Cancelling that wait keeps the registration
+    /// until the peer is done, so its NIC never reads unregistered memory; what it reads is
+    /// whatever the memory holds by then, though.
+    pub async fn send_msg(&mut self, message: &[u8]) -> Result<()> {
+        if !self.protocol.has(FEATURE_MESSAGES) {
+            return Err(TransportErrors::OpsFailed(
+                "send_msg".to_string(),
+                "the peer does not accept messages".to_string(),
+            ));
+        }
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(TransportErrors::OpsFailed(
+                "send_msg".to_string(),
+                format!(
+                    "message of {} bytes exceeds the {} byte limit",
+                    message.len(),
+                    MAX_MESSAGE_SIZE
+                ),
+            ));
+        }
+        self.settle_lending();
+        if message.len() > EAGER_LIMIT && self.protocol.has(FEATURE_RENDEZVOUS) {
+            return self.send_rendezvous(message).await;
+        }
+        self.send_fragments(message.len() as u64, message).await
+    }
+
+    /// The next message the peer sent with [`send_msg`](Self::send_msg). Notifications that
+    /// arrive in the meantime are kept for [`recv_notification`](Self::recv_notification).
+    ///
+    /// Cancel safe: dropping the future loses nothing that has arrived.
+    pub async fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        if !self.protocol.has(FEATURE_MESSAGES) {
+            return Err(TransportErrors::OpsFailed(
+                "recv_msg".to_string(),
+                "the peer does not send messages".to_string(),
+            ));
+        }
+        loop {
+            if let Some(message) = self.inbox.pop() {
+                return Ok(message);
+            }
+            self.take_in().await?;
+        }
+    }
+
+    // The first fragment goes straight out of `message`; the rest is kept in `unsent`, so it
+    // outlives a cancelled caller.
+    async fn send_fragments(&mut self, header: u64, message: &[u8]) -> Result<()> {
+        self.send_unsent().await?;
+        self.fragment_credit().await?;
+        let mut rest = message;
+        let (addr, size) = self
+            .outbox
+            .fill(self.fragments_sent, Some(header), &mut rest);
+        let transfer = self.engine.send(self.outbox.mr(), addr, size).await?;
+        self.fragments_sent += 1;
+        if rest.is_empty() {
+            return transfer.await;
+        }
+        self.unsent = Bytes::copy_from_slice(rest);
+        self.send_unsent().await
+    }
+
+    // Sends what is left in `unsent`, a fragment at a time, each taken out of it only once it
+    // is posted. SENDs complete in order, so the last one stands for the whole message.
+    async fn send_unsent(&mut self) -> Result<()> {
+        let mut transfer = None;
+        while !self.unsent.is_empty() {
+            self.fragment_credit().await?;
+            let mut rest = &self.unsent[..];
+            let (addr, size) = self.outbox.fill(self.fragments_sent, None, &mut rest);
+            let filled = self.unsent.len() - rest.len();
+            transfer = Some(self.engine.send(self.outbox.mr(), addr, size).await?);
+            self.fragments_sent += 1;
+            self.unsent.advance(filled);
+        }
+        match transfer {
+            Some(transfer) => transfer.await,
+            None => Ok(()),
+        }
+    }
+
+    // waits for one of the peer's message credits to be free, taking in what arrives meanwhile
+    async fn fragment_credit(&mut self) -> Result<()> {
+        let mut backoff = Backoff::default();
+        // saturating, like the notification credits
+        while self
+            .fragments_sent
+            .saturating_sub(self.counter(PEER_FRAGMENTS))
+            >= MESSAGE_CREDITS as u64
+        {
+            tokio::select! {
+                biased;
+                received = self.take_in() => received?,
+                _ = backoff.wait() => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Sends where `message` is and waits for the peer to have read it. The registration is
+    // read-only, so the peer cannot write into the caller's memory through it, and is kept in
+    // `lending` while the descriptor goes out, so a cancelled send does not lose it. It is the
+    // message's own rather than a cached one: a cached registration of the same range may pin
+    // the pages of a buffer that was freed since.
+    async fn send_rendezvous(&mut self, message: &[u8]) -> Result<()> {
+        let pd = self.cm_id.pd().ok_or_else(|| {
+            TransportErrors::OpsFailed("send_msg".to_string(), "cm_id has no pd".to_string())
+        })?;
+        let mr = ibv_reg_mr_read_only(&pd, message, IBV_ACCESS_REMOTE_READ as i32)?;
+        let descriptor = Descriptor {
+            addr: message.as_ptr() as u64,
+            len: message.len() as u64,
+            rkey: mr.rkey,
+        };
+        let descriptor = bincode::serialize(&descriptor)
+            .map_err(|e| TransportErrors::OpsFailed("send_msg".to_string(), e.to_string()))?;
+        // once these return, the descriptor goes out in the next fragment
+        self.send_unsent().await?;
+        self.fragment_credit().await?;
+        self.lending = Some((self.fragments_sent, mr));
+        self.send_fragments(descriptor.len() as u64 | RENDEZVOUS, &descriptor)
+            .await?;
+        self.settle_lending();
+
+        let mut backoff = Backoff::default();
+        while self.counter(PEER_FETCHED) < self.rendezvous_sent {
+            tokio::select! {
+                biased;
+                received = self.take_in() => received?,
+                _ = backoff.wait() => {}
+            }
+        }
+        self.settle_lending();
+        Ok(())
+    }
+
+    // Counts the rendezvous message in `lending` as sent and keeps its registration if its
+    // descriptor went out, and deregisters it if not; so this must run before any other
+    // fragment is sent. Then deregisters the messages the peer has read.
+    fn settle_lending(&mut self) {
+        if let Some((fragment, mr)) = self.lending.take() {
+            if self.fragments_sent > fragment {
+                self.rendezvous_sent += 1;
+                self.lent.push_back(mr);
+            }
+        }
+        let fetched = self.counter(PEER_FETCHED);
+        while !self.lent.is_empty() && self.rendezvous_sent - (self.lent.len() as u64) < fetched {
+            self.lent.pop_front();
+        }
+    }
+
+    /// Adds `add` to the 8-byte word at `remote_addr` in one of the peer's buffers, returning
+    /// its prior value.
+    pub async fn fetch_add(&mut self, remote_addr: u64, add: u64) -> Result<u64> {
+        self.atomic(AtomicOp::FetchAdd(add), remote_addr).await
+    }
+
+    /// Sets the 8-byte word at `remote_addr` to `swap` if it is `compare`, returning its prior
+    /// value either way.
+    pub async fn compare_swap(&mut self, remote_addr: u64, compare: u64, swap: u64) -> Result<u64> {
+        self.atomic(AtomicOp::CompareSwap { compare, swap }, remote_addr)
+            .await
+    }
+
+    /// Claims the lock word at `remote_addr` for `owner`, a non-zero id; 0 is unlocked. Returns
+    /// whether it was free, without waiting for it.
+    pub async fn try_lock(&mut self, remote_addr: u64, owner: u64) -> Result<bool> {
+        if owner == 0 {
+            return Err(TransportErrors::OpsFailed(
+                "try_lock".to_string(),
+                "0 marks the lock as free and cannot own it".to_string(),
+            ));
+        }
+        Ok(self.compare_swap(remote_addr, 0, owner).await? == 0)
+    }
+
+    /// Releases the lock word at `remote_addr`, failing if `owner` does not hold it.
+    pub async fn unlock(&mut self, remote_addr: u64, owner: u64) -> Result<()> {
+        let holder = self.compare_swap(remote_addr, owner, 0).await?;
+        if holder != owner {
+            return Err(TransportErrors::OpsFailed(
+                "unlock".to_string(),
+                format!(
+                    "the lock at {:#x} is held by {}, not {}",
+                    remote_addr, holder, owner
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Tells the server this client is done, then disconnects. The registrations and the
+    /// cm_id are released when the connection is dropped.
+    pub async fn close(&mut self) -> Result<()> {
+        if self.is_client {
+            self.notify(&Notification::complete()).await?;
+        }
+        rdma_disconnect(&mut self.cm_id).map_err(Into::into)
+    }
+
+    // Takes the next receive completion into the notification queue or, for a message
+    // fragment, the inbox. Both are updated before anything is awaited, so a caller that is
+    // cancelled loses nothing.
+    async fn next_received(&mut self) -> Result<()> {
+        let wc = self.recv.poll().await?;
+        if wc.status != IBV_WC_SUCCESS {
+            return Err(TransportErrors::OpsFailed(
+                "recv_notification".to_string(),
+                format!("poll_recv_comp failed with status: {:?}", wc.status),
+            ));
+        }
+
+        if wc.opcode == IBV_WC_RECV {
+            // copied out before the receive is posted again
+            self.inbox.push(self.recv.fragment(&wc)?)?;
+            self.recv.post(&self.cm_id, 1)?;
+            self.fragments_received += 1;
+            return self.publish_due().await;
+        }
+
+        self.recv.post(&self.cm_id, 1)?;
+        if wc.opcode != IBV_WC_RECV_RDMA_WITH_IMM {
+            self.notifications.push_back(Notification::default());
+            return Ok(());
+        }
+
+        let imm_data = unsafe { ntohl(wc.__bindgen_anon_1.imm_data) } as usize;
+        let (slot, size) = (imm_data >> 16, imm_data & 0xffff);
+        if slot >= OFFSET_SLOTS || size > SLOT_SIZE {
+            return Err(TransportErrors::OpsFailed(
+                "recv_notification".to_string(),
+                format!(
+                    "notification of {} bytes in slot {} is out of the ring",
+                    size, slot
+                ),
+            ));
+        }
+        let offset = RECV_RING + slot * SLOT_SIZE;
+        let notification = bincode::deserialize::<Notification>(
+            &self.cpu_buffer[offset..offset + size],
+        )
+        .map_err(|e| TransportErrors::OpsFailed("recv_notification".to_string(), e.to_string()))?;
+        self.notifications.push_back(notification);
+        self.received += 1;
+
+        // handing credits back in batches of half keeps it from costing a write per
+        // notification, and a sender that ran out of them still gets them back
+        if self.protocol.has(FEATURE_NOTIFY_RING)
+            && self.received - self.published >= (self.protocol.recv_credits / 2).max(1) as u64
+        {
+            self.publish(RECEIVED, PEER_RECEIVED, self.received).await?;
+            self.published = self.received;
+        }
+        Ok(())
+    }
+
+    // Makes progress on messages: reads the first rendezvous message still waiting, if there
+    // is one, else takes the next receive completion.
+    async fn take_in(&mut self) -> Result<()> {
+        // in case a caller was cancelled before it got to them
+        self.publish_due().await?;
+        if self.fetching.is_some() || self.inbox.waiting().is_some() {
+            return self.fetch().await;
+        }
+        self.next_received().await
+    }
+
+    // Reads the first rendezvous message into a buffer of ours and acknowledges it. Reading
+    // the same data into the same buffer again does no harm, so if this was cancelled before
+    // all of it was posted, it is simply posted again.
+    async fn fetch(&mut self) -> Result<()> {
+        let Some(descriptor) = self.inbox.waiting() else {
+            return Ok(());
+        };
+        // checked before allocating, since the peer picks the size; a smaller message would
+        // have been sent eagerly
+        if descriptor.len == 0
+            || descriptor.len <= EAGER_LIMIT as u64
+            || descriptor.len > MAX_MESSAGE_SIZE as u64
+        {
+            return Err(TransportErrors::OpsFailed(
+                "recv_msg".to_string(),
+                format!(
+                    "rendezvous message of {} bytes is not within {}..={}",
+                    descriptor.len,
+                    EAGER_LIMIT + 1,
+                    MAX_MESSAGE_SIZE
+                ),
+            ));
+        }
+        if self.fetching.is_none() {
+            let pd = self.cm_id.pd().ok_or_else(|| {
+                TransportErrors::OpsFailed("recv_msg".to_string(), "cm_id has no pd".to_string())
+            })?;
+            let mut data = vec![0; descriptor.len as usize];
+            let mr = ibv_reg_mr(&pd, &mut data, IBV_ACCESS_LOCAL_WRITE as i32)?;
+            self.fetching = Some(Fetch {
+                data,
+                mr,
+                transfer: None,
+            });
+        }
+
+        let fetch = self.fetching.as_mut().unwrap();
+        if fetch.transfer.is_none() {
+            // reads complete in order, so the last one stands for them all
+            let mut transfer = None;
+            for start in (0..fetch.data.len()).step_by(READ_CHUNK) {
+                let segment = Segment {
+                    lkey: fetch.mr.lkey,
+                    local_buffer_addr: fetch.data.as_ptr() as u64 + start as u64,
+                    rkey: descriptor.rkey,
+                    remote_buffer_addr: descriptor.addr + start as u64,
+                    size: (fetch.data.len() - start).min(READ_CHUNK) as u32,
+                };
+                // the registration is the fetch's own, kept in `self.fetching`
+                transfer = Some(self.engine.read_many(&[segment], Vec::new()).await?);
+            }
+            fetch.transfer = transfer;
+        }
+        let result = match fetch.transfer.as_mut() {
+            Some(transfer) => transfer.await,
+            None => Ok(()),
+        };
+        // a failed read is not retried, the QP is in error by now
+        let fetch = self.fetching.take().unwrap();
+        result?;
+        self.inbox.fetched(fetch.data);
+        self.fetched += 1;
+        self.publish_due().await
+    }
+
+    // Hands message credits back in batches of half, like notification credits, and tells the
+    // peer as soon as one of its rendezvous messages has been read.
+    async fn publish_due(&mut self) -> Result<()> {
+        if self.fragments_received - self.fragments_published >= (MESSAGE_CREDITS / 2) as u64 {
+            self.publish(FRAGMENTS, PEER_FRAGMENTS, self.fragments_received)
+                .await?;
+            self.fragments_published = self.fragments_received;
+        }
+        if self.fetched > self.fetched_published {
+            self.publish(FETCHED, PEER_FETCHED, self.fetched).await?;
+            self.fetched_published = self.fetched;
+        }
+        Ok(())
+    }
+
+    // read while the peer's NIC may be writing it
+    fn counter(&self, offset: usize) -> u64 {
+        let count =
+            unsafe { ptr::read_volatile(self.cpu_buffer[offset..].as_ptr() as *const [u8; 8]) };
+        u64::from_le_bytes(count)
+    }
+
+    // writes `count` to `offset` in the peer's control buffer, staged at `staging` in ours
+    async fn publish(&mut self, staging: usize, offset: usize, count: u64) -> Result<()> {
+        self.cpu_buffer[staging..staging + 8].copy_from_slice(&count.to_le_bytes());
+        self.engine
+            .write(
+                &self.cpu_mr,
+                &self.conn,
+                self.cpu_buffer.get_ptr() + staging as u64,
+                self.conn.get_base_ptr() + offset as u64,
+                8,
+            )
+            .await?
+            .await
+    }
+
+    async fn atomic(&mut self, op: AtomicOp, remote_addr: u64) -> Result<u64> {
+        if !self.protocol.has(FEATURE_REMOTE_ATOMIC) {
+            return Err(TransportErrors::OpsFailed(
+                "atomic".to_string(),
+                "the peer does not accept RDMA atomics".to_string(),
+            ));
+        }
+        if !remote_addr.is_multiple_of(8) {
+            return Err(TransportErrors::OpsFailed(
+                "atomic".to_string(),
+                format!("{:#x} is not 8-byte aligned", remote_addr),
+            ));
+        }
+        let rkey = find_remote_buffer(&self.remote_buffers, remote_addr, 8)?.get_mr_rkey();
+        self.engine
+            .atomic(
+                op,
+                (
+                    self.cpu_mr.lkey,
+                    self.cpu_buffer.get_ptr() + ATOMIC_RESULT as u64,
+                ),
+                (rkey, remote_addr),
+            )
+            .await?
+            .await?;
+        // the HCA writes the prior value in host order
+        let prior = unsafe {
+            ptr::read_volatile(self.cpu_buffer[ATOMIC_RESULT..].as_ptr() as *const [u8; 8])
+        };
+        Ok(u64::from_ne_bytes(prior))
+    }
+
+    // Also returns holds on the registrations the segments use, for the engine to keep until
+    // the transfer has completed.
+    fn segments(&self, blocks: &[(u64, u64, u32)]) -> Result<(Vec<Segment>, Vec<CachedMr>)> {
+        coalesce(blocks)
+            .into_iter()
+            .map(|(local_buffer_addr, remote_buffer_addr, size)| {
+                let mr = self
+                    .mr_cache
+                    .lookup(local_buffer_addr, size as u64)
+                    .ok_or_else(|| {
+                        TransportErrors::OpsFailed(
+                            "find_local_buffer".to_string(),
+                            format!(
+                                "no registered buffer covers {:#x}+{}",
+                                local_buffer_addr, size
+                            ),
+                        )
+                    })?;
+                let conn = find_remote_buffer(&self.remote_buffers, remote_buffer_addr, size)?;
+                let segment = Segment {
+                    lkey: mr.lkey,
+                    local_buffer_addr,
+                    rkey: conn.get_mr_rkey(),
+                    remote_buffer_addr,
+                    size,
+                };
+                Ok((segment, mr))
+            })
+            .collect()
+    }
+}
+
+// The write that moves a counter raises no event on this side, so there is nothing to wait on
+// but time; this keeps a wait for the peer from holding a core.
+#[derive(Default)]
+struct Backoff {
+    rounds: u32,
+}
+
+impl Backoff {
+    async fn wait(&mut self) {
+        self.rounds = self.rounds.saturating_add(1);
+        if self.rounds <= SPIN_ROUNDS {
+            tokio::task::yield_now().await;
+            return;
+        }
+        let delay = Duration::from_micros(1 << (self.rounds - SPIN_ROUNDS).min(10));
+        tokio::time::sleep(delay.min(MAX_BACKOFF)).await;
+    }
+}
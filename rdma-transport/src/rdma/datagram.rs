@@ -0,0 +1,252 @@
+use std::{collections::HashMap, ptr};
+
+use rdma_core::ibverbs::{
+    ibv_create_ah, ibv_create_comp_channel, ibv_create_cq, ibv_create_qp, ibv_init_ud_qp,
+    ibv_post_recv, ibv_post_send, ibv_query_gid_table, ibv_query_port, ibv_reg_mr, ud_ah_attr,
+    ud_send_wr, Gid, IbvAh, IbvContext, IbvMr, IbvPd, IbvQp, IbvQpInitAttr, LinkLayer, GRH_SIZE,
+};
+use rdma_core_sys::{
+    ibv_recv_wr, ibv_sge, IBV_ACCESS_LOCAL_WRITE, IBV_QPT_UD, IBV_SEND_SIGNALED, IBV_WC_SUCCESS,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, TransportErrors};
+
+use super::{CompletionQueue, Notification, RdmaDevice, PROTOCOL_MAGIC};
+
+/// The qkey of every datagram endpoint; the NIC drops datagrams sent with any other.
+pub const DATAGRAM_QKEY: u32 = PROTOCOL_MAGIC;
+/// Receives an endpoint keeps posted. Datagrams arriving while none is are dropped.
+pub const DATAGRAM_RECV_DEPTH: usize = 256;
+/// Sends an endpoint may have queued at once, each staged in a slot of its own.
+pub const DATAGRAM_SEND_DEPTH: usize = 64;
+// Only every this many sends is signaled. A UD send queue completes in order, so its completion
+// frees the slots of the unsignaled sends before it too.
+const SIGNAL_INTERVAL: u64 = 16;
+
+/// Where a [`DatagramEndpoint`] can be reached. It is not discoverable through rdma_cm, so
+/// peers exchange it out of band, e.g. over an existing connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DatagramAddr {
+    pub gid: [u8; 16],
+    pub lid: u16,
+    pub qpn: u32,
+}
+
+/// A UD QP that sends notifications to any number of peers and receives theirs, instead of
+/// holding an RC connection to each.
+///
+/// Delivery is unreliable: a datagram is lost if the receiver has no receive posted or the
+/// fabric drops it, and each has to fit the path MTU. Sends do not wait for the NIC, so those
+/// still queued when the endpoint is dropped are lost as well.
+pub struct DatagramEndpoint {
+    qp: IbvQp,
+    send_cq: CompletionQueue,
+    recv_cq: CompletionQueue,
+    pd: IbvPd,
+    port_num: u8,
+    // the local GID's index, for links that need a GRH on every datagram
+    sgid_index: Option<u8>,
+    addr: DatagramAddr,
+    ahs: HashMap<DatagramAddr, IbvAh>,
+    mtu: usize,
+    mr: IbvMr,
+    // DATAGRAM_RECV_DEPTH receive slots of GRH_SIZE + mtu bytes, then DATAGRAM_SEND_DEPTH send
+    // slots of mtu bytes
+    buffer: Vec<u8>,
+    // sends posted, and sends known to be done with their slots; a send's wr_id is its number
+    sent: u64,
+    freed: u64,
+}
+
+impl DatagramEndpoint {
+    /// An endpoint on the port `device` was reached through; must be called from within a
+    /// tokio runtime.
+    pub fn new(device: &RdmaDevice) -> Result<DatagramEndpoint> {
+        let pd = device.pd();
+        let context = pd.context().ok_or_else(|| {
+            TransportErrors::OpsFailed(
+                "DatagramEndpoint::new".to_string(),
+                "no context".to_string(),
+            )
+        })?;
+        let port = ibv_query_port(&context, device.port_num())?;
+        let sgid_index = match port.link_layer {
+            LinkLayer::Ethernet => Some(gid_index(&context, device)?),
+            _ => None,
+        };
+        let mtu = port.active_mtu.bytes();
+
+        // room for a failed completion of every queued send, not just the signaled ones
+        let send_cq = ibv_create_cq(&ibv_create_comp_channel(pd)?, DATAGRAM_SEND_DEPTH as i32)?;
+        let recv_cq = ibv_create_cq(&ibv_create_comp_channel(pd)?, DATAGRAM_RECV_DEPTH as i32)?;
+        let mut init_attr = IbvQpInitAttr::default();
+        init_attr.qp_type = IBV_QPT_UD;
+        init_attr.cap.max_send_wr = DATAGRAM_SEND_DEPTH as u32;
+        init_attr.cap.max_recv_wr = DATAGRAM_RECV_DEPTH as u32;
+        init_attr.cap.max_send_sge = 1;
+        init_attr.cap.max_recv_sge = 1;
+        let qp = ibv_create_qp(pd, &send_cq, &recv_cq, &mut init_attr)?;
+        ibv_init_ud_qp(&qp, device.port_num(), DATAGRAM_QKEY)?;
+
+        let mut buffer =
+            vec![0; DATAGRAM_RECV_DEPTH * (GRH_SIZE + mtu) + DATAGRAM_SEND_DEPTH * mtu];
+        let mr = ibv_reg_mr(pd, &mut buffer, IBV_ACCESS_LOCAL_WRITE as i32)?;
+        let mut endpoint = DatagramEndpoint {
+            addr: DatagramAddr {
+                gid: device.gid().0,
+                lid: port.lid,
+                qpn: qp.qp_num,
+            },
+            qp,
+            send_cq: CompletionQueue::from_cq(send_cq)?,
+            recv_cq: CompletionQueue::from_cq(recv_cq)?,
+            pd: pd.clone(),
+            port_num: device.port_num(),
+            sgid_index,
+            ahs: HashMap::new(),
+            mtu,
+            mr,
+            buffer,
+            sent: 0,
+            freed: 0,
+        };
+        for slot in 0..DATAGRAM_RECV_DEPTH {
+            endpoint.post_recv(slot)?;
+        }
+        Ok(endpoint)
+    }
+
+    pub fn local_addr(&self) -> DatagramAddr {
+        self.addr
+    }
+
+    /// Queues `notification` for the endpoint at `to`, waiting only while every send slot is
+    /// taken. A send the NIC fails is reported by the call that finds its completion, which
+    /// may be a later one. That a datagram arrived is only known if the peer answers.
+    pub async fn send(&mut self, to: &DatagramAddr, notification: &Notification) -> Result<()> {
+        let message = (self.addr, notification);
+        let size = bincode::serialized_size(&message)
+            .map_err(|e| TransportErrors::OpsFailed("send".to_string(), e.to_string()))?
+            as usize;
+        if size > self.mtu {
+            return Err(TransportErrors::OpsFailed(
+                "send".to_string(),
+                format!(
+                    "datagram of {} bytes exceeds the {} byte MTU",
+                    size, self.mtu
+                ),
+            ));
+        }
+        while self.sent - self.freed == DATAGRAM_SEND_DEPTH as u64 {
+            self.reap().await?;
+        }
+        let slot = (self.sent % DATAGRAM_SEND_DEPTH as u64) as usize;
+        let staging = DATAGRAM_RECV_DEPTH * self.slot_size() + slot * self.mtu;
+        bincode::serialize_into(&mut self.buffer[staging..staging + size], &message)
+            .map_err(|e| TransportErrors::OpsFailed("send".to_string(), e.to_string()))?;
+
+        let ah = self.address_handle(to)?;
+        let mut sge = ibv_sge {
+            addr: self.buffer.as_ptr() as u64 + staging as u64,
+            length: size as u32,
+            lkey: self.mr.lkey,
+        };
+        let mut wr = ud_send_wr(&ah, to.qpn, DATAGRAM_QKEY);
+        wr.wr_id = self.sent;
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        // a full ring always holds a signaled send, as it is deeper than the interval
+        if self.sent % SIGNAL_INTERVAL == SIGNAL_INTERVAL - 1 {
+            wr.send_flags = IBV_SEND_SIGNALED;
+        }
+        let mut bad = ptr::null_mut();
+        ibv_post_send(self.qp.as_ptr(), &mut wr, &mut bad)?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    // Waits for the next signaled send, or a failed one, to complete and frees the slots up to
+    // it. Only changes anything once the completion is in hand, so `send` stays cancel safe.
+    async fn reap(&mut self) -> Result<()> {
+        let wc = self.send_cq.poll().await?;
+        self.freed = wc.wr_id + 1;
+        if wc.status != IBV_WC_SUCCESS {
+            return Err(TransportErrors::OpsFailed(
+                "send".to_string(),
+                format!("datagram {} failed with status: {:?}", wc.wr_id, wc.status),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The next datagram and the endpoint that sent it.
+    pub async fn recv(&mut self) -> Result<(DatagramAddr, Notification)> {
+        let wc = self.recv_cq.poll().await?;
+        if wc.status != IBV_WC_SUCCESS {
+            return Err(TransportErrors::OpsFailed(
+                "recv".to_string(),
+                format!("poll_recv_comp failed with status: {:?}", wc.status),
+            ));
+        }
+
+        // The sender's address travels in the datagram: on RoCE v2 the GRH space holds an IPv4
+        // header rather than its GID, and the completion does not carry its qkey.
+        let slot = wc.wr_id as usize;
+        let start = slot * self.slot_size() + GRH_SIZE;
+        let size = (wc.byte_len as usize).saturating_sub(GRH_SIZE);
+        let message =
+            bincode::deserialize::<(DatagramAddr, Notification)>(&self.buffer[start..start + size]);
+        self.post_recv(slot)?;
+        message.map_err(|e| TransportErrors::OpsFailed("recv".to_string(), e.to_string()))
+    }
+
+    fn slot_size(&self) -> usize {
+        GRH_SIZE + self.mtu
+    }
+
+    fn post_recv(&mut self, slot: usize) -> Result<()> {
+        let slot_size = self.slot_size();
+        let mut sge = ibv_sge {
+            addr: self.buffer.as_ptr() as u64 + (slot * slot_size) as u64,
+            length: slot_size as u32,
+            lkey: self.mr.lkey,
+        };
+        let mut wr = ibv_recv_wr {
+            wr_id: slot as u64,
+            sg_list: &mut sge,
+            num_sge: 1,
+            ..Default::default()
+        };
+        let mut bad = ptr::null_mut();
+        ibv_post_recv(self.qp.as_ptr(), &mut wr, &mut bad)?;
+        Ok(())
+    }
+
+    // one AH per peer, kept for as long as the endpoint
+    fn address_handle(&mut self, to: &DatagramAddr) -> Result<IbvAh> {
+        if let Some(ah) = self.ahs.get(to) {
+            return Ok(ah.clone());
+        }
+        let global = self.sgid_index.map(|index| (Gid(to.gid), index));
+        let ah = ibv_create_ah(&self.pd, &ud_ah_attr(self.port_num, to.lid, global))?;
+        self.ahs.insert(*to, ah.clone());
+        Ok(ah)
+    }
+}
+
+// providers list the RoCE v1 entry of an address before its v2 one, and v2 is the one that
+// routes
+fn gid_index(context: &IbvContext, device: &RdmaDevice) -> Result<u8> {
+    ibv_query_gid_table(context, device.port_num())?
+        .into_iter()
+        .rev()
+        .find(|(_, gid)| *gid == device.gid())
+        .map(|(index, _)| index as u8)
+        .ok_or_else(|| {
+            TransportErrors::OpsFailed(
+                "gid_index".to_string(),
+                format!("{} is not in the port's GID table", device.gid()),
+            )
+        })
+}
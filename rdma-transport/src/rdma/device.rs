@@ -0,0 +1,108 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use libc::AI_PASSIVE;
+use rdma_core::{
+    ibverbs::{Gid, IbvPd},
+    rdma::{rdma_create_ep, rdma_getaddrinfo},
+};
+use rdma_core_sys::{rdma_addrinfo, RDMA_PS_TCP};
+
+use crate::{topology, Result, TransportErrors};
+
+use super::{mr_access, MrCache};
+
+/// An RDMA device and one protection domain on it, shared by every endpoint created from it,
+/// so that memory registered once can be used with every peer reached through the device.
+///
+/// Clones share the PD, and endpoints created from it keep it alive on their own.
+#[derive(Debug, Clone)]
+pub struct RdmaDevice {
+    pd: IbvPd,
+    // the port and GID the probe was bound or routed through
+    port_num: u8,
+    gid: Gid,
+    // the address it was opened by, when that was a local one
+    local_addr: Option<IpAddr>,
+}
+
+impl RdmaDevice {
+    /// The device `bind_addr` belongs to. It has to be the device's own address; a wildcard
+    /// address does not pick one.
+    pub fn for_local(bind_addr: &SocketAddr) -> Result<RdmaDevice> {
+        // port 0, so the probe does not take the port the listener binds afterwards
+        let mut device = Self::open(&bind_addr.ip().to_string(), "0", AI_PASSIVE)?;
+        device.local_addr = Some(bind_addr.ip());
+        Ok(device)
+    }
+
+    /// The device closest to `gpu_ordinal` on the PCI bus, or the one `device_override` names,
+    /// opened by the first address configured on it, see [`topology::select_for_gpu`].
+    pub fn for_gpu(gpu_ordinal: i32, device_override: Option<&str>) -> Result<RdmaDevice> {
+        let (_, addr) = topology::select_for_gpu(gpu_ordinal, device_override)?;
+        Self::for_local(&SocketAddr::new(addr, 0))
+    }
+
+    /// The device the route to `server_addr` leaves through.
+    pub fn for_peer(server_addr: &SocketAddr) -> Result<RdmaDevice> {
+        Self::open(
+            &server_addr.ip().to_string(),
+            &server_addr.port().to_string(),
+            0,
+        )
+    }
+
+    fn open(node: &str, service: &str, flags: i32) -> Result<RdmaDevice> {
+        let hints = rdma_addrinfo {
+            ai_flags: flags,
+            ai_port_space: RDMA_PS_TCP as i32,
+            ..Default::default()
+        };
+        let addr_info = rdma_getaddrinfo(node, service, &hints)?;
+        // bound or resolved but never connected; the PD keeps it around to pin the device
+        let cm_id = rdma_create_ep(&addr_info, None, None)?;
+        if cm_id.verbs.is_null() {
+            return Err(TransportErrors::OpsFailed(
+                "RdmaDevice::open".to_string(),
+                format!("{} is not on an RDMA device", node),
+            ));
+        }
+        Ok(RdmaDevice {
+            pd: cm_id.alloc_pd()?,
+            port_num: cm_id.port_num,
+            gid: cm_id.sgid(),
+            local_addr: None,
+        })
+    }
+
+    pub fn pd(&self) -> &IbvPd {
+        &self.pd
+    }
+
+    /// The port the device was reached through, for QPs created without rdma_cm.
+    pub fn port_num(&self) -> u8 {
+        self.port_num
+    }
+
+    /// The local GID of the address the device was reached through.
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    /// The address the device was opened by, if it was opened by a local one. Clients connect
+    /// from it, so that the connection leaves through this device whatever the routes say.
+    pub fn local_addr(&self) -> Option<IpAddr> {
+        self.local_addr
+    }
+
+    /// The registration cache `connect`/`accept` use for endpoints on this device. Buffers
+    /// registered through it up front stay registered for as long as their [`CachedMr`]s are
+    /// held, and every connection reuses them.
+    ///
+    /// [`CachedMr`]: super::CachedMr
+    pub fn mr_cache(&self) -> Arc<MrCache> {
+        MrCache::for_pd(&self.pd, mr_access(&self.pd))
+    }
+}
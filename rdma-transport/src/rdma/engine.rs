@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use rdma_core::{
+    ibverbs::{ibv_post_send, IbvMr, IbvQp},
+    rdma::RdmaCmId,
+};
+use rdma_core_sys::{
+    htonl, ibv_send_wr, ibv_send_wr__bindgen_ty_2__bindgen_ty_2, ibv_sge, IBV_SEND_SIGNALED,
+    IBV_WC_SUCCESS, IBV_WR_ATOMIC_CMP_AND_SWP, IBV_WR_ATOMIC_FETCH_AND_ADD, IBV_WR_RDMA_READ,
+    IBV_WR_RDMA_WRITE, IBV_WR_RDMA_WRITE_WITH_IMM, IBV_WR_SEND,
+};
+use tokio::{
+    sync::{oneshot, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{Result, TransportErrors};
+
+use super::{CachedMr, CompletionQueue, Connection};
+
+pub const DEFAULT_QUEUE_DEPTH: u32 = 128;
+/// The most segments merged into one WR; a device that takes fewer gets fewer, see
+/// [`TransferEngine::new`].
+pub const MAX_SEND_SGE: u32 = 16;
+
+// wr_id -> what a posted chain holds on to until its completion is reaped; the reason the
+// poller stopped instead once it has, as nothing would complete a transfer posted after
+type Inflight = Arc<Mutex<std::result::Result<HashMap<u64, Waiter>, String>>>;
+
+struct Waiter {
+    tx: oneshot::Sender<Result<()>>,
+    // the send queue slots of the chain
+    _permit: OwnedSemaphorePermit,
+    // the registrations its SGEs use, shared by every chain of a batch, so none of them is
+    // deregistered while the HCA may still use its lkey
+    _holds: Arc<[CachedMr]>,
+}
+
+/// Keeps up to `queue_depth` writes/reads outstanding on one QP.
+///
+/// Every post takes a send queue slot and returns a [`Transfer`] that resolves when the
+/// completion carrying its `wr_id` is reaped. A background task owns the send CQ, so
+/// nothing else may poll it while the engine is alive.
+pub struct TransferEngine {
+    // only posted to, which unlike the cm_id's connection management is safe from any thread
+    qp: IbvQp,
+    queue_depth: u32,
+    // SGEs per write and per read WR
+    max_write_sge: u32,
+    max_read_sge: u32,
+    credits: Arc<Semaphore>,
+    next_wr_id: AtomicU64,
+    inflight: Inflight,
+    poller: JoinHandle<()>,
+}
+
+impl TransferEngine {
+    /// Segments are merged into WRs of at most `max_write_sge`/`max_read_sge` SGEs, which have
+    /// to be within what the QP was created with.
+    pub fn new(
+        cm_id: &RdmaCmId,
+        send_cq: CompletionQueue,
+        queue_depth: u32,
+        (max_write_sge, max_read_sge): (u32, u32),
+    ) -> Result<TransferEngine> {
+        let qp = cm_id.qp().ok_or_else(|| {
+            TransportErrors::OpsFailed("TransferEngine::new".to_string(), "no qp".to_string())
+        })?;
+        let inflight: Inflight = Arc::new(Mutex::new(Ok(HashMap::new())));
+        Ok(TransferEngine {
+            qp,
+            queue_depth,
+            max_write_sge: max_write_sge.max(1),
+            max_read_sge: max_read_sge.max(1),
+            credits: Arc::new(Semaphore::new(queue_depth as usize)),
+            next_wr_id: AtomicU64::new(0),
+            inflight: inflight.clone(),
+            poller: tokio::spawn(poll_completions(send_cq, inflight)),
+        })
+    }
+
+    pub fn write(
+        &self,
+        mr: &IbvMr,
+        conn: &Connection,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<Transfer>> + Send + '_ {
+        self.post(
+            IBV_WR_RDMA_WRITE,
+            (mr.lkey, local_buffer_addr),
+            (conn.get_mr_rkey(), remote_buffer_addr),
+            size,
+            0,
+        )
+    }
+
+    pub fn write_with_imm(
+        &self,
+        mr: &IbvMr,
+        conn: &Connection,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+        imm_data: u32,
+    ) -> impl Future<Output = Result<Transfer>> + Send + '_ {
+        self.post(
+            IBV_WR_RDMA_WRITE_WITH_IMM,
+            (mr.lkey, local_buffer_addr),
+            (conn.get_mr_rkey(), remote_buffer_addr),
+            size,
+            imm_data,
+        )
+    }
+
+    pub fn read(
+        &self,
+        mr: &IbvMr,
+        conn: &Connection,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<Transfer>> + Send + '_ {
+        self.post(
+            IBV_WR_RDMA_READ,
+            (mr.lkey, local_buffer_addr),
+            (conn.get_mr_rkey(), remote_buffer_addr),
+            size,
+            0,
+        )
+    }
+
+    /// Sends `size` bytes into the next receive the peer has posted.
+    pub fn send(
+        &self,
+        mr: &IbvMr,
+        local_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<Transfer>> + Send + '_ {
+        self.post(IBV_WR_SEND, (mr.lkey, local_buffer_addr), (0, 0), size, 0)
+    }
+
+    /// Writes every segment with as few work requests as possible: segments that continue
+    /// the previous remote range become extra SGEs of the same WR, and the WRs are posted as
+    /// chains in which only the last one is signaled. `holds` are kept until every chain has
+    /// completed, whether the [`Transfer`] is still around or not.
+    pub async fn write_many(&self, segments: &[Segment], holds: Vec<CachedMr>) -> Result<Transfer> {
+        self.post_many(IBV_WR_RDMA_WRITE, segments, holds.into())
+            .await
+    }
+
+    pub async fn read_many(&self, segments: &[Segment], holds: Vec<CachedMr>) -> Result<Transfer> {
+        self.post_many(IBV_WR_RDMA_READ, segments, holds.into())
+            .await
+    }
+
+    async fn post(
+        &self,
+        opcode: u32,
+        (lkey, local_buffer_addr): (u32, u64),
+        (rkey, remote_buffer_addr): (u32, u64),
+        size: u32,
+        imm_data: u32,
+    ) -> Result<Transfer> {
+        let mut wrs = [WorkRequest {
+            rkey,
+            remote_buffer_addr,
+            size,
+            sges: vec![ibv_sge {
+                addr: local_buffer_addr,
+                length: size,
+                lkey,
+            }],
+        }];
+        self.post_chain(opcode, &mut wrs, imm_data, Arc::default())
+            .await
+    }
+
+    async fn post_many(
+        &self,
+        opcode: u32,
+        segments: &[Segment],
+        holds: Arc<[CachedMr]>,
+    ) -> Result<Transfer> {
+        let max_sge = if opcode == IBV_WR_RDMA_READ {
+            self.max_read_sge
+        } else {
+            self.max_write_sge
+        };
+        let mut wrs: Vec<WorkRequest> = Vec::new();
+        for segment in segments.iter().filter(|segment| segment.size > 0) {
+            let sge = ibv_sge {
+                addr: segment.local_buffer_addr,
+                length: segment.size,
+                lkey: segment.lkey,
+            };
+            match wrs.last_mut() {
+                Some(wr)
+                    if wr.rkey == segment.rkey
+                        && wr.remote_buffer_addr + wr.size as u64 == segment.remote_buffer_addr
+                        && wr.sges.len() < max_sge as usize
+                        && wr.size.checked_add(segment.size).is_some() =>
+                {
+                    wr.size += segment.size;
+                    wr.sges.push(sge);
+                }
+                _ => wrs.push(WorkRequest {
+                    rkey: segment.rkey,
+                    remote_buffer_addr: segment.remote_buffer_addr,
+                    size: segment.size,
+                    sges: vec![sge],
+                }),
+            }
+        }
+
+        // an RC send queue completes in order and a failed WR flushes everything behind it,
+        // so the last chain's completion stands for the whole batch
+        let mut transfer = None;
+        for chain in wrs.chunks_mut(self.queue_depth as usize) {
+            transfer = Some(self.post_chain(opcode, chain, 0, holds.clone()).await?);
+        }
+        match transfer {
+            Some(transfer) => Ok(transfer),
+            None => Ok(Transfer::completed()),
+        }
+    }
+
+    /// Posts a remote atomic on the 8-byte word at `remote_buffer_addr`; the value it held
+    /// before lands in the 8 bytes at `local_buffer_addr`.
+    pub async fn atomic(
+        &self,
+        op: AtomicOp,
+        (lkey, local_buffer_addr): (u32, u64),
+        (rkey, remote_buffer_addr): (u32, u64),
+    ) -> Result<Transfer> {
+        let (wr_id, rx) = self.reserve(1, Arc::default()).await?;
+        let mut sge = ibv_sge {
+            addr: local_buffer_addr,
+            length: 8,
+            lkey,
+        };
+        let (opcode, compare_add, swap) = match op {
+            AtomicOp::FetchAdd(add) => (IBV_WR_ATOMIC_FETCH_AND_ADD, add, 0),
+            AtomicOp::CompareSwap { compare, swap } => (IBV_WR_ATOMIC_CMP_AND_SWP, compare, swap),
+        };
+        let mut send_wr = ibv_send_wr {
+            sg_list: &mut sge,
+            num_sge: 1,
+            opcode,
+            ..Default::default()
+        };
+        send_wr.wr.atomic = ibv_send_wr__bindgen_ty_2__bindgen_ty_2 {
+            remote_addr: remote_buffer_addr,
+            compare_add,
+            swap,
+            rkey,
+        };
+        self.submit(wr_id, rx, std::slice::from_mut(&mut send_wr))
+    }
+
+    async fn post_chain(
+        &self,
+        opcode: u32,
+        wrs: &mut [WorkRequest],
+        imm_data: u32,
+        holds: Arc<[CachedMr]>,
+    ) -> Result<Transfer> {
+        let (wr_id, rx) = self.reserve(wrs.len(), holds).await?;
+        let mut send_wrs = vec![ibv_send_wr::default(); wrs.len()];
+        for (wr, send_wr) in wrs.iter_mut().zip(send_wrs.iter_mut()) {
+            send_wr.sg_list = wr.sges.as_mut_ptr();
+            send_wr.num_sge = wr.sges.len() as i32;
+            send_wr.opcode = opcode;
+            send_wr.wr.rdma.remote_addr = wr.remote_buffer_addr;
+            send_wr.wr.rdma.rkey = wr.rkey;
+            if opcode == IBV_WR_RDMA_WRITE_WITH_IMM {
+                send_wr.__bindgen_anon_1.imm_data = unsafe { htonl(imm_data) };
+            }
+        }
+        self.submit(wr_id, rx, &mut send_wrs)
+    }
+
+    // Takes a send queue slot for each WR of a chain and the wr_id it completes under.
+    // Unsignaled WRs keep their slots until the signaled tail completes, so all the slots are
+    // released together.
+    async fn reserve(
+        &self,
+        count: usize,
+        holds: Arc<[CachedMr]>,
+    ) -> Result<(u64, oneshot::Receiver<Result<()>>)> {
+        let permit = self
+            .credits
+            .clone()
+            .acquire_many_owned(count as u32)
+            .await
+            .map_err(|e| {
+                TransportErrors::OpsFailed("transfer_engine".to_string(), e.to_string())
+            })?;
+
+        let wr_id = self.next_wr_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.inflight.lock().unwrap().as_mut() {
+            Ok(waiters) => waiters.insert(
+                wr_id,
+                Waiter {
+                    tx,
+                    _permit: permit,
+                    _holds: holds,
+                },
+            ),
+            Err(reason) => {
+                return Err(TransportErrors::OpsFailed(
+                    "transfer_engine".to_string(),
+                    format!("stopped: {}", reason),
+                ))
+            }
+        };
+        Ok((wr_id, rx))
+    }
+
+    // Posts `send_wrs` linked into one list under `wr_id`, only the tail signaled. Nothing is
+    // awaited once the WRs exist, they point into the caller's frame.
+    fn submit(
+        &self,
+        wr_id: u64,
+        rx: oneshot::Receiver<Result<()>>,
+        send_wrs: &mut [ibv_send_wr],
+    ) -> Result<Transfer> {
+        for send_wr in send_wrs.iter_mut() {
+            send_wr.wr_id = wr_id;
+        }
+        for i in 1..send_wrs.len() {
+            send_wrs[i - 1].next = &mut send_wrs[i];
+        }
+        if let Some(last) = send_wrs.last_mut() {
+            last.send_flags = IBV_SEND_SIGNALED;
+        }
+
+        let mut bad: *mut ibv_send_wr = ptr::null_mut();
+        if let Err(e) = ibv_post_send(self.qp.as_ptr(), send_wrs.as_mut_ptr(), &mut bad) {
+            if let Ok(waiters) = self.inflight.lock().unwrap().as_mut() {
+                waiters.remove(&wr_id);
+            }
+            return Err(e.into());
+        }
+
+        Ok(Transfer { wr_id, rx })
+    }
+}
+
+impl Drop for TransferEngine {
+    fn drop(&mut self) {
+        self.poller.abort();
+    }
+}
+
+async fn poll_completions(mut send_cq: CompletionQueue, inflight: Inflight) {
+    loop {
+        let wc = match send_cq.poll().await {
+            Ok(wc) => wc,
+            Err(e) => {
+                let waiters = std::mem::replace(&mut *inflight.lock().unwrap(), Err(e.to_string()));
+                for (_, waiter) in waiters.into_iter().flatten() {
+                    let _ = waiter.tx.send(Err(TransportErrors::OpsFailed(
+                        "transfer_engine".to_string(),
+                        e.to_string(),
+                    )));
+                }
+                return;
+            }
+        };
+
+        let waiter = inflight
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok()
+            .and_then(|waiters| waiters.remove(&wc.wr_id));
+        if let Some(waiter) = waiter {
+            let result = if wc.status == IBV_WC_SUCCESS {
+                Ok(())
+            } else {
+                Err(TransportErrors::OpsFailed(
+                    "transfer".to_string(),
+                    format!("wr {} completed with status: {:?}", wc.wr_id, wc.status),
+                ))
+            };
+            let _ = waiter.tx.send(result);
+        }
+    }
+}
+
+/// A remote atomic on one 8-byte aligned word. Atomics are only atomic with respect to other
+/// atomics through the same device, not to the CPU or other writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicOp {
+    FetchAdd(u64),
+    /// Swaps in `swap` if the word is `compare`.
+    CompareSwap {
+        compare: u64,
+        swap: u64,
+    },
+}
+
+/// One local range of a batched transfer and the remote range it maps to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Segment {
+    pub lkey: u32,
+    pub local_buffer_addr: u64,
+    pub rkey: u32,
+    pub remote_buffer_addr: u64,
+    pub size: u32,
+}
+
+// one work request: a contiguous remote range gathered from / scattered to `sges`
+struct WorkRequest {
+    rkey: u32,
+    remote_buffer_addr: u64,
+    size: u32,
+    sges: Vec<ibv_sge>,
+}
+
+/// A posted write/read; resolves with the status of its work completion.
+pub struct Transfer {
+    wr_id: u64,
+    rx: oneshot::Receiver<Result<()>>,
+}
+
+impl Transfer {
+    // stands in for an empty batch, which posts nothing
+    fn completed() -> Transfer {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(()));
+        Transfer {
+            wr_id: u64::MAX,
+            rx,
+        }
+    }
+
+    pub fn wr_id(&self) -> u64 {
+        self.wr_id
+    }
+}
+
+impl Future for Transfer {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|result| {
+            result.unwrap_or_else(|_| {
+                Err(TransportErrors::OpsFailed(
+                    "transfer".to_string(),
+                    "transfer engine shut down".to_string(),
+                ))
+            })
+        })
+    }
+}
@@ -0,0 +1,426 @@
+use std::{collections::VecDeque, ptr};
+
+use rdma_core::{
+    ibverbs::{ibv_post_recv, ibv_reg_mr, IbvMr},
+    rdma::RdmaCmId,
+};
+use rdma_core_sys::{ibv_recv_wr, ibv_sge, ibv_wc, IBV_ACCESS_LOCAL_WRITE};
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, TransportErrors, CPU_BUFFER_BASE_SIZE};
+
+use super::QpOptions;
+
+/// The most a message sends at once; longer ones are split across as many receives as they
+/// need.
+pub const MESSAGE_FRAGMENT_SIZE: usize = CPU_BUFFER_BASE_SIZE;
+/// Receives each side keeps posted for the peer's message fragments, on top of those for its
+/// notifications: how many fragments may be outstanding.
+pub const MESSAGE_CREDITS: u32 = 16;
+/// Messages longer than one credit window are read by the receiver straight out of the
+/// sender's memory, if both sides support it, instead of being copied through the receives.
+pub const EAGER_LIMIT: usize = MESSAGE_FRAGMENT_SIZE * MESSAGE_CREDITS as usize;
+/// The longest message either side sends or takes in. It is fixed rather than negotiated,
+/// there is no room left for it in the hello.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+// a message's first fragment starts with its length, with this bit set if what follows is a
+// Descriptor rather than the message itself
+const HEADER_SIZE: usize = 8;
+pub(super) const RENDEZVOUS: u64 = 1 << 63;
+
+/// Where the receiver of a rendezvous message reads it from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct Descriptor {
+    pub addr: u64,
+    pub len: u64,
+    pub rkey: u32,
+}
+
+// a message as it came in: the data, or where to read it from
+enum Incoming {
+    Message(Vec<u8>),
+    Rendezvous(Descriptor),
+}
+
+// one receive per notification credit and per message credit
+pub(super) fn recv_depth(options: &QpOptions) -> u32 {
+    options.credits() + MESSAGE_CREDITS
+}
+
+/// Receive buffers of `MESSAGE_FRAGMENT_SIZE` bytes for a QP with a receive queue of its own.
+///
+/// A write with immediate takes whichever receive is at the head of the queue without
+/// touching its buffer, so notifications and message fragments share the same receives. The
+/// queue completes them in the order they were posted, which lets the slots go round as a
+/// ring.
+pub(super) struct RecvPool {
+    buffer: Vec<u8>,
+    mr: IbvMr,
+    depth: usize,
+    posted: u64,
+}
+
+impl RecvPool {
+    pub fn new(cm_id: &RdmaCmId, depth: usize) -> Result<RecvPool> {
+        let pd = cm_id.pd().ok_or_else(|| {
+            TransportErrors::OpsFailed("recv_pool".to_string(), "cm_id has no pd".to_string())
+        })?;
+        let mut buffer = vec![0; depth * MESSAGE_FRAGMENT_SIZE];
+        let mr = ibv_reg_mr(&pd, &mut buffer, IBV_ACCESS_LOCAL_WRITE as i32)?;
+        Ok(RecvPool {
+            buffer,
+            mr,
+            depth,
+            posted: 0,
+        })
+    }
+
+    // The handshake posts the first ones before connect/accept, so the peer's buffer table
+    // never finds an empty receive queue. After that, a slot is only posted again once the
+    // completion that used it has been handled.
+    pub fn post(&mut self, cm_id: &RdmaCmId, count: usize) -> Result<()> {
+        for _ in 0..count {
+            let slot = (self.posted % self.depth as u64) as usize;
+            let mut sge = ibv_sge {
+                addr: self.buffer.as_ptr() as u64 + (slot * MESSAGE_FRAGMENT_SIZE) as u64,
+                length: MESSAGE_FRAGMENT_SIZE as u32,
+                lkey: self.mr.lkey,
+            };
+            let mut wr = ibv_recv_wr {
+                wr_id: slot as u64,
+                sg_list: &mut sge,
+                num_sge: 1,
+                ..Default::default()
+            };
+            let mut bad = ptr::null_mut();
+            ibv_post_recv(cm_id.qp, &mut wr, &mut bad)?;
+            self.posted += 1;
+        }
+        Ok(())
+    }
+
+    /// What the SEND completed by `wc` left in its receive.
+    pub fn fragment(&self, wc: &ibv_wc) -> &[u8] {
+        let start = wc.wr_id as usize * MESSAGE_FRAGMENT_SIZE;
+        &self.buffer[start..start + wc.byte_len as usize]
+    }
+}
+
+/// Where outgoing fragments are staged, one slot per message credit. A slot is only reused
+/// once the peer has handed its credit back, by which time the fragment has arrived.
+///
+/// Generic over the MR so that staging can be tested without a device.
+pub(super) struct SendRing<M = IbvMr> {
+    buffer: Vec<u8>,
+    mr: M,
+}
+
+impl SendRing {
+    pub fn new(cm_id: &RdmaCmId) -> Result<SendRing> {
+        let pd = cm_id.pd().ok_or_else(|| {
+            TransportErrors::OpsFailed("send_ring".to_string(), "cm_id has no pd".to_string())
+        })?;
+        let mut buffer = vec![0; MESSAGE_CREDITS as usize * MESSAGE_FRAGMENT_SIZE];
+        let mr = ibv_reg_mr(&pd, &mut buffer, IBV_ACCESS_LOCAL_WRITE as i32)?;
+        Ok(SendRing { buffer, mr })
+    }
+
+    pub fn mr(&self) -> &IbvMr {
+        &self.mr
+    }
+}
+
+impl<M> SendRing<M> {
+    /// Fills the slot of the `index`th fragment with the next piece of `message`, led by
+    /// `header` if it is the first. Returns the slot's address and how much of it is used, and
+    /// leaves `message` at what is still to be sent.
+    pub fn fill(&mut self, index: u64, header: Option<u64>, message: &mut &[u8]) -> (u64, u32) {
+        let start = (index % MESSAGE_CREDITS as u64) as usize * MESSAGE_FRAGMENT_SIZE;
+        let slot = &mut self.buffer[start..start + MESSAGE_FRAGMENT_SIZE];
+        let mut len = 0;
+        if let Some(header) = header {
+            slot[..HEADER_SIZE].copy_from_slice(&header.to_le_bytes());
+            len = HEADER_SIZE;
+        }
+        let n = message.len().min(MESSAGE_FRAGMENT_SIZE - len);
+        slot[len..len + n].copy_from_slice(&message[..n]);
+        *message = &message[n..];
+        (self.buffer.as_ptr() as u64 + start as u64, (len + n) as u32)
+    }
+}
+
+/// Reassembles the peer's messages from their fragments, which an RC QP delivers in order.
+/// Rendezvous messages keep their place in line until they have been read.
+#[derive(Default)]
+pub(super) struct Inbox {
+    // the header of the message being received and what has arrived of it
+    partial: Option<(u64, Vec<u8>)>,
+    ready: VecDeque<Incoming>,
+}
+
+impl Inbox {
+    pub fn push(&mut self, fragment: &[u8]) -> Result<()> {
+        let (header, mut data) = match self.partial.take() {
+            Some(partial) => partial,
+            None => {
+                let header = fragment.get(..HEADER_SIZE).ok_or_else(|| {
+                    TransportErrors::OpsFailed(
+                        "recv_msg".to_string(),
+                        format!("fragment of {} bytes has no message header", fragment.len()),
+                    )
+                })?;
+                let header = u64::from_le_bytes(header.try_into().unwrap());
+                self.partial = Some((header, Vec::new()));
+                return self.push(&fragment[HEADER_SIZE..]);
+            }
+        };
+        let size = (header & !RENDEZVOUS) as usize;
+        if size > MAX_MESSAGE_SIZE {
+            return Err(TransportErrors::OpsFailed(
+                "recv_msg".to_string(),
+                format!(
+                    "message of {} bytes exceeds the {} byte limit",
+                    size, MAX_MESSAGE_SIZE
+                ),
+            ));
+        }
+        if data.len() + fragment.len() > size {
+            return Err(TransportErrors::OpsFailed(
+                "recv_msg".to_string(),
+                format!("fragments overrun the {} byte message", size),
+            ));
+        }
+        data.extend_from_slice(fragment);
+        if data.len() < size {
+            self.partial = Some((header, data));
+        } else if header & RENDEZVOUS == 0 {
+            self.ready.push_back(Incoming::Message(data));
+        } else {
+            let descriptor = bincode::deserialize(&data)
+                .map_err(|e| TransportErrors::OpsFailed("recv_msg".to_string(), e.to_string()))?;
+            self.ready.push_back(Incoming::Rendezvous(descriptor));
+        }
+        Ok(())
+    }
+
+    /// The next message, unless it still has to be read from the peer.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        match self.ready.pop_front()? {
+            Incoming::Message(data) => Some(data),
+            rendezvous => {
+                self.ready.push_front(rendezvous);
+                None
+            }
+        }
+    }
+
+    /// The first message that still has to be read from the peer.
+    pub fn waiting(&self) -> Option<Descriptor> {
+        self.ready.iter().find_map(|incoming| match incoming {
+            Incoming::Rendezvous(descriptor) => Some(*descriptor),
+            Incoming::Message(_) => None,
+        })
+    }
+
+    /// Puts the data read for [`waiting`](Self::waiting) in its place.
+    pub fn fetched(&mut self, data: Vec<u8>) {
+        if let Some(incoming) = self
+            .ready
+            .iter_mut()
+            .find(|incoming| matches!(incoming, Incoming::Rendezvous(_)))
+        {
+            *incoming = Incoming::Message(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Descriptor, Inbox, SendRing, HEADER_SIZE, MAX_MESSAGE_SIZE, MESSAGE_CREDITS,
+        MESSAGE_FRAGMENT_SIZE, RENDEZVOUS,
+    };
+
+    fn ring() -> SendRing<()> {
+        SendRing {
+            buffer: vec![0; MESSAGE_CREDITS as usize * MESSAGE_FRAGMENT_SIZE],
+            mr: (),
+        }
+    }
+
+    // the fragments `message` goes out in, as the peer receives them
+    fn fragments(ring: &mut SendRing<()>, message: &[u8]) -> Vec<Vec<u8>> {
+        let base = ring.buffer.as_ptr() as u64;
+        let mut rest = message;
+        let mut header = Some(message.len() as u64);
+        let mut fragments = Vec::new();
+        for index in 0.. {
+            let (addr, len) = ring.fill(index, header.take(), &mut rest);
+            let start = (addr - base) as usize;
+            fragments.push(ring.buffer[start..start + len as usize].to_vec());
+            if rest.is_empty() {
+                break;
+            }
+        }
+        fragments
+    }
+
+    // what the peer pushes for a message it registered at `addr` for us to read
+    fn push_rendezvous(inbox: &mut Inbox, addr: u64, len: u64) {
+        let descriptor = Descriptor { addr, len, rkey: 1 };
+        let mut fragment = Vec::new();
+        let data = bincode::serialize(&descriptor).unwrap();
+        fragment.extend_from_slice(&(data.len() as u64 | RENDEZVOUS).to_le_bytes());
+        fragment.extend_from_slice(&data);
+        inbox.push(&fragment).unwrap();
+    }
+
+    fn push_message(inbox: &mut Inbox, message: &[u8]) {
+        for fragment in fragments(&mut ring(), message) {
+            inbox.push(&fragment).unwrap();
+        }
+    }
+
+    #[test]
+    fn fill_leads_the_first_fragment_with_the_header() {
+        let mut ring = ring();
+        let mut message = &b"hello"[..];
+        let (addr, len) = ring.fill(0, Some(5), &mut message);
+        assert_eq!(addr, ring.buffer.as_ptr() as u64);
+        assert_eq!(len as usize, HEADER_SIZE + 5);
+        assert_eq!(ring.buffer[..HEADER_SIZE], 5u64.to_le_bytes());
+        assert_eq!(&ring.buffer[HEADER_SIZE..HEADER_SIZE + 5], b"hello");
+        assert!(message.is_empty());
+    }
+
+    #[test]
+    fn fill_splits_a_long_message_and_goes_round_the_slots() {
+        let mut ring = ring();
+        let data = vec![7; 2 * MESSAGE_FRAGMENT_SIZE];
+        let mut message = &data[..];
+        let index = MESSAGE_CREDITS as u64 - 1;
+        let (first, len) = ring.fill(index, Some(data.len() as u64), &mut message);
+        assert_eq!(len as usize, MESSAGE_FRAGMENT_SIZE);
+        assert_eq!(
+            message.len(),
+            data.len() - (MESSAGE_FRAGMENT_SIZE - HEADER_SIZE)
+        );
+
+        let (second, len) = ring.fill(index + 1, None, &mut message);
+        assert_eq!(len as usize, MESSAGE_FRAGMENT_SIZE);
+        // the last slot is followed by the first
+        assert_eq!(second, ring.buffer.as_ptr() as u64);
+        assert_eq!(
+            first,
+            second + (MESSAGE_CREDITS as usize - 1) as u64 * MESSAGE_FRAGMENT_SIZE as u64
+        );
+
+        // what the header took from the first
+        let (_, len) = ring.fill(index + 2, None, &mut message);
+        assert_eq!(len as usize, HEADER_SIZE);
+        assert!(message.is_empty());
+    }
+
+    #[test]
+    fn fill_sends_a_zero_length_message_as_its_header() {
+        let mut ring = ring();
+        let mut message = &[][..];
+        let (_, len) = ring.fill(3, Some(0), &mut message);
+        assert_eq!(len as usize, HEADER_SIZE);
+    }
+
+    #[test]
+    fn inbox_reassembles_messages_in_order() {
+        let mut ring = ring();
+        let long: Vec<u8> = (0..3 * MESSAGE_FRAGMENT_SIZE).map(|i| i as u8).collect();
+        let mut inbox = Inbox::default();
+        let mut long_fragments = fragments(&mut ring, &long);
+        let last = long_fragments.pop().unwrap();
+        for fragment in long_fragments {
+            inbox.push(&fragment).unwrap();
+            assert!(inbox.pop().is_none());
+        }
+        inbox.push(&last).unwrap();
+        for message in [&b"short"[..], b""] {
+            for fragment in fragments(&mut ring, message) {
+                inbox.push(&fragment).unwrap();
+            }
+        }
+        assert_eq!(inbox.pop().unwrap(), long);
+        assert_eq!(inbox.pop().unwrap(), b"short");
+        assert_eq!(inbox.pop().unwrap(), b"");
+        assert!(inbox.pop().is_none());
+    }
+
+    #[test]
+    fn inbox_rejects_fragments_that_overrun_the_message() {
+        let mut inbox = Inbox::default();
+        let mut fragment = 4u64.to_le_bytes().to_vec();
+        fragment.extend_from_slice(b"hello");
+        assert!(inbox.push(&fragment).is_err());
+
+        let mut inbox = Inbox::default();
+        let mut fragment = 6u64.to_le_bytes().to_vec();
+        fragment.extend_from_slice(b"hel");
+        inbox.push(&fragment).unwrap();
+        assert!(inbox.push(b"lo!!").is_err());
+    }
+
+    #[test]
+    fn inbox_rejects_a_missing_header_and_an_oversized_message() {
+        let mut inbox = Inbox::default();
+        assert!(inbox.push(&[0; HEADER_SIZE - 1]).is_err());
+        let header = (MAX_MESSAGE_SIZE as u64 + 1).to_le_bytes();
+        assert!(inbox.push(&header).is_err());
+        assert!(inbox.pop().is_none());
+    }
+
+    #[test]
+    fn a_rendezvous_holds_back_the_messages_behind_it() {
+        let mut inbox = Inbox::default();
+        push_rendezvous(&mut inbox, 0x1000, 64);
+        push_message(&mut inbox, b"after");
+        assert!(inbox.pop().is_none());
+        let descriptor = inbox.waiting().unwrap();
+        assert_eq!((descriptor.addr, descriptor.len), (0x1000, 64));
+        // still waiting until it is fetched
+        assert!(inbox.pop().is_none());
+
+        inbox.fetched(vec![9; 64]);
+        assert!(inbox.waiting().is_none());
+        assert_eq!(inbox.pop().unwrap(), vec![9; 64]);
+        assert_eq!(inbox.pop().unwrap(), b"after");
+        assert!(inbox.pop().is_none());
+    }
+
+    #[test]
+    fn messages_ahead_of_a_rendezvous_pop_before_it_is_fetched() {
+        let mut inbox = Inbox::default();
+        push_message(&mut inbox, b"before");
+        push_rendezvous(&mut inbox, 0x1000, 64);
+        assert_eq!(inbox.pop().unwrap(), b"before");
+        assert!(inbox.pop().is_none());
+        assert_eq!(inbox.waiting().unwrap().addr, 0x1000);
+    }
+
+    #[test]
+    fn rendezvous_messages_are_fetched_in_order() {
+        let mut inbox = Inbox::default();
+        push_rendezvous(&mut inbox, 0x1000, 64);
+        push_message(&mut inbox, b"between");
+        push_rendezvous(&mut inbox, 0x2000, 128);
+        assert_eq!(inbox.waiting().unwrap().addr, 0x1000);
+
+        inbox.fetched(b"first".to_vec());
+        // the second is only waited on once the first is in place
+        assert_eq!(inbox.waiting().unwrap().addr, 0x2000);
+        assert_eq!(inbox.pop().unwrap(), b"first");
+        assert_eq!(inbox.pop().unwrap(), b"between");
+        assert!(inbox.pop().is_none());
+
+        inbox.fetched(b"second".to_vec());
+        assert_eq!(inbox.pop().unwrap(), b"second");
+        assert!(inbox.waiting().is_none());
+    }
+}
@@ -1,45 +1,149 @@
 mod client;
+mod completion;
+mod connection;
+mod datagram;
+mod device;
+mod engine;
+mod message;
+mod mr_cache;
+mod protocol;
 mod server;
+mod srq;
 
-use std::ops::Deref;
-
-use rdma_core::{
-    ibverbs::{ibv_poll_cq, IbvMr},
-    rdma::{rdma_post_write, rdma_post_write_with_opcode, rdma_post_read, RdmaCmId},
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    ops::Deref,
+    sync::{Mutex, OnceLock},
 };
+
 use serde::{Deserialize, Serialize};
 
-use rdma_core_sys::{ibv_wc, IBV_SEND_SIGNALED, IBV_WC_SUCCESS};
-pub use server::{
-    accept, disconnect as server_disconnect, handle_notification, init as server_init, listen,
-};
+pub use server::{accept, init as server_init, listen};
 
-pub use client::{connect, disconnect as client_disconnect, init as client_init};
+pub use client::{connect, init as client_init};
+pub use completion::CompletionQueue;
+pub use connection::RdmaConnection;
+pub use datagram::{
+    DatagramAddr, DatagramEndpoint, DATAGRAM_QKEY, DATAGRAM_RECV_DEPTH, DATAGRAM_SEND_DEPTH,
+};
+pub use device::RdmaDevice;
+pub use engine::{AtomicOp, Segment, Transfer, TransferEngine, DEFAULT_QUEUE_DEPTH, MAX_SEND_SGE};
+pub use message::{EAGER_LIMIT, MAX_MESSAGE_SIZE, MESSAGE_CREDITS, MESSAGE_FRAGMENT_SIZE};
+pub use mr_cache::{CachedMr, MrCache};
+pub use srq::{SharedIncoming, SharedListener};
+pub use protocol::{
+    negotiate, Hello, Negotiated, FEATURE_MESSAGES, FEATURE_NOTIFY_RING, FEATURE_NOTIFY_WITH_IMM,
+    FEATURE_RDMA_READ, FEATURE_REMOTE_ATOMIC, FEATURE_REMOTE_BUFFER_TABLE, FEATURE_RENDEZVOUS,
+    MAX_ACCEPT_PRIVATE_DATA, MAX_CONNECT_PRIVATE_DATA, MAX_REJECT_PRIVATE_DATA,
+    MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION, REQUIRED_FEATURES,
+    SUPPORTED_FEATURES,
+};
 
-use crate::{
-    buffer::CPU_BUFFER_BASE_SIZE, cuda::cuda_mem_free, GPUMemBuffer, MemBuffer, Result,
-    TransportErrors,
+use rdma_core::{
+    ibverbs::{ibv_modify_qp, ibv_query_device, AtomicCap, DeviceAttr, IbvContext, IbvPd},
+    rdma::RdmaCmId,
 };
+use rdma_core_sys::{
+    ibv_qp_attr, IBV_ACCESS_LOCAL_WRITE, IBV_ACCESS_REMOTE_ATOMIC, IBV_ACCESS_REMOTE_READ,
+    IBV_ACCESS_REMOTE_WRITE, IBV_QP_MIN_RNR_TIMER,
+};
+
+use crate::{cuda::cuda_mem_free, GPUMemBuffer, Result, TransportErrors, OFFSET_SLOTS};
+
+use srq::RecvQueue;
+
+// every local buffer is open to the peer's writes and reads, and to its atomics where the
+// device can do them
+pub(crate) fn mr_access(pd: &IbvPd) -> i32 {
+    (IBV_ACCESS_LOCAL_WRITE | qp_access(pd)) as i32
+}
+
+// what the QP lets the peer do; it has to allow whatever the MRs do
+pub(crate) fn qp_access(pd: &IbvPd) -> u32 {
+    let access = IBV_ACCESS_REMOTE_WRITE | IBV_ACCESS_REMOTE_READ;
+    if supports_atomics(pd) {
+        access | IBV_ACCESS_REMOTE_ATOMIC
+    } else {
+        access
+    }
+}
+
+pub(crate) fn supports_atomics(pd: &IbvPd) -> bool {
+    pd.context()
+        .and_then(|context| device_attr(&context).ok())
+        .is_some_and(|attr| attr.atomic_cap != AtomicCap::None)
+}
+
+// What a device reports does not change while it is open, and rdma_cm keeps its contexts open
+// for the life of the process, so every connection after the first skips the query.
+fn device_attr(context: &IbvContext) -> Result<DeviceAttr> {
+    static ATTRS: OnceLock<Mutex<HashMap<usize, DeviceAttr>>> = OnceLock::new();
+    let key = context.as_ptr() as usize;
+    let attrs = ATTRS.get_or_init(Default::default);
+    if let Some(attr) = attrs.lock().unwrap().get(&key) {
+        return Ok(attr.clone());
+    }
+    let attr = ibv_query_device(context)?;
+    attrs.lock().unwrap().insert(key, attr.clone());
+    Ok(attr)
+}
+
+// the engine merges up to MAX_SEND_SGE segments into a WR and a device may take fewer, for
+// reads fewer still; the SGEs per write and per read the device behind `context` allows
+pub(crate) fn sge_limits(context: Option<IbvContext>) -> Result<(u32, u32)> {
+    let context = context.ok_or_else(|| {
+        TransportErrors::OpsFailed(
+            "ibv_query_device".to_string(),
+            "not bound to a device".to_string(),
+        )
+    })?;
+    let attr = device_attr(&context)?;
+    let max_write_sge = MAX_SEND_SGE.min(attr.max_sge);
+    Ok((max_write_sge, max_write_sge.min(attr.max_sge_rd)))
+}
 
 pub fn free_gpu_membuffer(buffer: &GPUMemBuffer) -> Result<()> {
+    MrCache::invalidate_all(buffer.get_base_ptr(), buffer.get_size() as u64);
     cuda_mem_free(&buffer).map_err(|e| e.into())
 }
 
+/// A buffer registered on one side, as the other side addresses it. It goes over the wire in
+/// the hello and the buffer table, so its fields are part of the protocol; `size` was added
+/// with the first released version, for the peer to bound its transfers by.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Connection {
     base_ptr: u64,
+    size: u64,
     mr_rkey: u32,
 }
 
 impl Connection {
-    pub fn new(base_ptr: u64, mr_rkey: u32) -> Connection {
-        Connection { base_ptr, mr_rkey }
+    pub fn new(base_ptr: u64, size: u64, mr_rkey: u32) -> Connection {
+        Connection {
+            base_ptr,
+            size,
+            mr_rkey,
+        }
     }
 
     pub fn get_base_ptr(&self) -> u64 {
         self.base_ptr
     }
 
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether `addr..addr + size` lies within the buffer. Both come from the peer, so
+    /// neither end may wrap round.
+    pub fn covers(&self, addr: u64, size: u64) -> bool {
+        match (addr.checked_add(size), self.base_ptr.checked_add(self.size)) {
+            (Some(end), Some(limit)) => addr >= self.base_ptr && end <= limit,
+            _ => false,
+        }
+    }
+
     pub fn get_mr_rkey(&self) -> u32 {
         self.mr_rkey
     }
@@ -63,118 +167,84 @@ impl Deref for Connections {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct Notification {
-    pub done: u32, // 1 is done for conn 0 is data
-    pub req_id: Option<Vec<u8>>,
+/// How `client_init`/`server_init` size a connection's QP and how `connect`/`accept` set up
+/// its retries. Given once to the init call and carried from there, see [`RdmaEndpoint`].
+#[derive(Debug, Clone, Copy)]
+pub struct QpOptions {
+    /// Writes and reads that may be outstanding at once.
+    pub queue_depth: u32,
+    /// Receives kept posted for the peer's notifications, i.e. how many it may send before we
+    /// consume them. Capped at the ring's `OFFSET_SLOTS`.
+    pub recv_credits: u32,
+    /// Retries after a transport timeout, up to 7.
+    pub retry_count: u8,
+    /// Retries after the peer answered receiver-not-ready, up to 6, or 7 to retry forever.
+    pub rnr_retry_count: u8,
+    /// How long a peer that found no receive posted backs off, IB encoded: 1 is 0.01 ms, 12 is
+    /// 0.64 ms, 31 is 491.52 ms and 0 is 655.36 ms.
+    pub min_rnr_timer: u8,
+    /// The local address a client connects from, e.g. the one [`RdmaDevice::for_gpu`] opens
+    /// by; the route to the server picks it when `None`. Servers bind the address they are
+    /// given and leave this alone.
+    pub local_addr: Option<IpAddr>,
 }
 
-impl Notification {
-    pub fn complete() -> Self {
-        let mut notification = Notification::default();
-        notification.done = 1;
-        notification
+impl QpOptions {
+    pub(crate) fn credits(&self) -> u32 {
+        self.recv_credits.clamp(1, OFFSET_SLOTS as u32)
     }
 }
 
-pub async fn write_metadata(
-    cm_id: &mut RdmaCmId,
-    conn: &Connection,
-    cpu_mr: &mut IbvMr,
-    cpu_buffer: &mut MemBuffer,
-    offset: u16,
-    size: u16,
-) -> Result<()> {
-    let imm_data = ((offset as u32) << 16) + size as u32;
-    rdma_post_write_with_opcode(
-        cm_id,
-        Some(&mut 1),
-        cpu_buffer.get_ptr() + (offset as u64 * CPU_BUFFER_BASE_SIZE as u64),
-        CPU_BUFFER_BASE_SIZE,
-        Some(cpu_mr),
-        IBV_SEND_SIGNALED,
-        conn.get_base_ptr() + (offset as u64 * CPU_BUFFER_BASE_SIZE as u64),
-        conn.get_mr_rkey(),
-        rdma_core_sys::IBV_WR_RDMA_WRITE_WITH_IMM,
-        imm_data,
-    )?;
-
-    let mut wc = ibv_wc::default();
-    let send_cq = cm_id.send_cq;
-    ibv_poll_cq(send_cq, 1, &mut wc)?;
-
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "write_metadata".to_string(),
-            format!("poll_write_comp failed with status: {:?}", wc.status),
-        ));
-    }
-
+// only takes effect once the QP is connected, so this runs after rdma_connect/rdma_accept
+fn set_min_rnr_timer(cm_id: &RdmaCmId, options: &QpOptions) -> Result<()> {
+    let mut attr = ibv_qp_attr {
+        min_rnr_timer: options.min_rnr_timer,
+        ..Default::default()
+    };
+    ibv_modify_qp(cm_id.qp, &mut attr, IBV_QP_MIN_RNR_TIMER as i32)?;
     Ok(())
 }
 
-pub async fn write(
-    cm_id: &mut RdmaCmId,
-    conn: &Connection,
-    mr: &mut IbvMr,
-    local_buffer_addr: u64,
-    remote_buffer_addr: u64,
-    size: u32,
-) -> Result<()> {
-    rdma_post_write(
-        cm_id,
-        Some(&mut 1),
-        local_buffer_addr,
-        size as usize,
-        Some(mr),
-        IBV_SEND_SIGNALED,
-        remote_buffer_addr,
-        conn.get_mr_rkey(),
-    )?;
-
-    let mut wc = ibv_wc::default();
-    let send_cq = cm_id.send_cq;
-    ibv_poll_cq(send_cq, 1, &mut wc)?;
-
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "write".to_string(),
-            format!("poll_write_comp failed with status: {:?}", wc.status),
-        ));
+impl Default for QpOptions {
+    fn default() -> Self {
+        QpOptions {
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            recv_credits: OFFSET_SLOTS as u32,
+            retry_count: 7,
+            rnr_retry_count: 7,
+            min_rnr_timer: 12,
+            local_addr: None,
+        }
     }
+}
 
-    Ok(())
+/// A listening cm_id from [`server_init`], with the device and the options the QPs of its
+/// connections are created with.
+pub struct RdmaListener {
+    listen_id: RdmaCmId,
+    device: RdmaDevice,
+    options: QpOptions,
 }
 
-pub async fn read(
-    cm_id: &mut RdmaCmId,
-    conn: &Connection,
-    mr: &mut IbvMr,
-    local_buffer_addr: u64,
-    remote_buffer_addr: u64,
-    size: u32,
-) -> Result<()> {
-    rdma_post_read(
-        cm_id,
-        Some(&mut 1),
-        local_buffer_addr,
-        size as usize,
-        Some(mr),
-        IBV_SEND_SIGNALED,
-        remote_buffer_addr,
-        conn.get_mr_rkey(),
-    )?;
-
-    let mut wc = ibv_wc::default();
-    let send_cq = cm_id.send_cq;
-    ibv_poll_cq(send_cq, 1, &mut wc)?;
-
-    if wc.status != IBV_WC_SUCCESS {
-        return Err(TransportErrors::OpsFailed(
-            "read".to_string(),
-            format!("poll_read_comp failed with status: {:?}", wc.status),
-        ));
-    }
+/// A cm_id with a QP that is not connected yet: a client's from [`client_init`] or a request
+/// from [`listen`]. The device and the options its QP was created with travel with it, so
+/// [`connect`] and [`accept`] register on the device's PD and set the connection up to match.
+pub struct RdmaEndpoint {
+    cm_id: RdmaCmId,
+    device: RdmaDevice,
+    options: QpOptions,
+}
 
-    Ok(())
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Notification {
+    pub done: u32, // 1 is done for conn 0 is data
+    pub req_id: Option<Vec<u8>>,
+}
+
+impl Notification {
+    pub fn complete() -> Self {
+        let mut notification = Notification::default();
+        notification.done = 1;
+        notification
+    }
 }
@@ -0,0 +1,281 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, OnceLock, Weak},
+};
+
+use rdma_core::ibverbs::{ibv_reg_mr, IbvMr, IbvPd};
+
+use crate::{GPUMemBuffer, Result};
+
+// one cache per PD and access flags, so every connection on a PD shares its registrations
+type Caches = HashMap<(usize, i32), Weak<MrCache>>;
+
+static CACHES: OnceLock<Mutex<Caches>> = OnceLock::new();
+
+fn caches() -> &'static Mutex<Caches> {
+    CACHES.get_or_init(Default::default)
+}
+
+struct Registration<M> {
+    mr: M,
+    // tells a registration apart from a later one of the same range
+    id: u64,
+    refs: usize,
+}
+
+// The registrations of one cache and the holds on each; generic over the MR so that the
+// bookkeeping does not need a device.
+struct Regions<M> {
+    // keyed by (start, end); ranges may overlap when a buffer only partly covered by an
+    // existing registration is registered on its own
+    map: BTreeMap<(u64, u64), Registration<M>>,
+    next_id: u64,
+}
+
+impl<M> Default for Regions<M> {
+    fn default() -> Self {
+        Regions {
+            map: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<M> Regions<M> {
+    // registrations may overlap, so every one starting at or before `start` is a candidate
+    fn covering(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        self.map
+            .range(..=(start, u64::MAX))
+            .rev()
+            .find(|(&(_, region_end), _)| region_end >= end)
+            .map(|(&key, _)| key)
+    }
+
+    fn insert(&mut self, start: u64, end: u64, mr: M) -> (u64, u64) {
+        self.next_id += 1;
+        let registration = Registration {
+            mr,
+            id: self.next_id,
+            refs: 0,
+        };
+        self.map.insert((start, end), registration);
+        (start, end)
+    }
+
+    fn acquire(&mut self, key: (u64, u64)) -> (&M, u64) {
+        let registration = self.map.get_mut(&key).unwrap();
+        registration.refs += 1;
+        (&registration.mr, registration.id)
+    }
+
+    // Drops a hold from `acquire`, returning the registration once its last hold is gone. The
+    // range may have been invalidated, and maybe registered again, since it was taken.
+    fn release(&mut self, key: (u64, u64), id: u64) -> Option<M> {
+        let registration = self.map.get_mut(&key).filter(|r| r.id == id)?;
+        registration.refs -= 1;
+        if registration.refs > 0 {
+            return None;
+        }
+        self.map.remove(&key).map(|registration| registration.mr)
+    }
+
+    // takes out every registration overlapping `addr..addr + len`, held or not
+    fn invalidate(&mut self, addr: u64, len: u64) -> Vec<M> {
+        let end = addr.saturating_add(len);
+        let keys: Vec<_> = self
+            .map
+            .keys()
+            .filter(|&&(start, region_end)| region_end > addr && start < end)
+            .copied()
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.map.remove(&key))
+            .map(|registration| registration.mr)
+            .collect()
+    }
+}
+
+/// The memory registrations made on one PD, shared by every connection on it.
+///
+/// A registration covering a requested range is handed out again instead of registering the
+/// range a second time, and is deregistered once the last [`CachedMr`] for it is dropped. The
+/// cache holds the PD handle it was created with, which should be an
+/// [`RdmaDevice`](super::RdmaDevice)'s own, not one borrowed from a connection's cm_id.
+pub struct MrCache {
+    pd: IbvPd,
+    access: i32,
+    regions: Mutex<Regions<IbvMr>>,
+}
+
+impl MrCache {
+    /// The cache for registrations on `pd` with `access`, created on first use.
+    pub fn for_pd(pd: &IbvPd, access: i32) -> Arc<MrCache> {
+        let mut caches = caches().lock().unwrap();
+        let key = (pd.as_ptr() as usize, access);
+        if let Some(cache) = caches.get(&key).and_then(Weak::upgrade) {
+            return cache;
+        }
+        caches.retain(|_, cache| cache.strong_count() > 0);
+        let cache = Arc::new(MrCache {
+            pd: pd.clone(),
+            access,
+            regions: Mutex::default(),
+        });
+        caches.insert(key, Arc::downgrade(&cache));
+        cache
+    }
+
+    /// A registration covering `buffer`, registering it if no cached one does.
+    pub fn register(self: &Arc<Self>, buffer: &GPUMemBuffer) -> Result<CachedMr> {
+        let mut buffer = *buffer;
+        let start = buffer.get_base_ptr();
+        let end = start + buffer.get_size() as u64;
+        let mut regions = self.regions.lock().unwrap();
+        let key = match regions.covering(start, end) {
+            Some(key) => key,
+            None => {
+                let mr = ibv_reg_mr(&self.pd, &mut buffer, self.access)?;
+                regions.insert(start, end, mr)
+            }
+        };
+        Ok(self.acquire(&mut regions, key))
+    }
+
+    /// The cached registration covering `addr..addr + len`, if there is one. This is how a
+    /// connection finds the lkey for a transfer, so a range invalidated since it was registered
+    /// is no longer found.
+    pub fn lookup(self: &Arc<Self>, addr: u64, len: u64) -> Option<CachedMr> {
+        let mut regions = self.regions.lock().unwrap();
+        let key = regions.covering(addr, addr.checked_add(len)?)?;
+        Some(self.acquire(&mut regions, key))
+    }
+
+    /// Deregisters everything overlapping `addr..addr + len`, for memory that is about to be
+    /// freed, whether connections still hold it or not. Their transfers from or into the range
+    /// then fail, and so do the peer's with the rkeys it was given.
+    pub fn invalidate(&self, addr: u64, len: u64) {
+        let invalidated = self.regions.lock().unwrap().invalidate(addr, len);
+        // deregistered outside the lock
+        drop(invalidated);
+    }
+
+    /// [`invalidate`](Self::invalidate) in the cache of every PD.
+    pub fn invalidate_all(addr: u64, len: u64) {
+        let caches: Vec<_> = caches()
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for cache in caches {
+            cache.invalidate(addr, len);
+        }
+    }
+
+    fn acquire(self: &Arc<Self>, regions: &mut Regions<IbvMr>, key: (u64, u64)) -> CachedMr {
+        let (mr, id) = regions.acquire(key);
+        CachedMr {
+            lkey: mr.lkey,
+            rkey: mr.rkey,
+            key,
+            id,
+            cache: self.clone(),
+        }
+    }
+
+    fn release(&self, key: (u64, u64), id: u64) {
+        let released = self.regions.lock().unwrap().release(key, id);
+        drop(released);
+    }
+}
+
+/// A connection's hold on a cached registration; the MR is deregistered once the last hold on
+/// it is dropped, or as soon as its range is invalidated.
+pub struct CachedMr {
+    pub lkey: u32,
+    pub rkey: u32,
+    key: (u64, u64),
+    id: u64,
+    cache: Arc<MrCache>,
+}
+
+impl Drop for CachedMr {
+    fn drop(&mut self) {
+        self.cache.release(self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regions;
+
+    fn regions(ranges: &[(u64, u64)]) -> Regions<&'static str> {
+        let mut regions = Regions::default();
+        for &(start, end) in ranges {
+            regions.insert(start, end, "mr");
+        }
+        regions
+    }
+
+    #[test]
+    fn covering_finds_a_registration_around_the_range() {
+        let regions = regions(&[(0x1000, 0x2000), (0x3000, 0x5000)]);
+        assert_eq!(regions.covering(0x1000, 0x2000), Some((0x1000, 0x2000)));
+        assert_eq!(regions.covering(0x3800, 0x4000), Some((0x3000, 0x5000)));
+        assert_eq!(regions.covering(0x1800, 0x1800), Some((0x1000, 0x2000)));
+    }
+
+    #[test]
+    fn covering_misses_ranges_partly_or_not_registered() {
+        let regions = regions(&[(0x1000, 0x2000), (0x3000, 0x5000)]);
+        assert_eq!(regions.covering(0x1800, 0x2800), None);
+        assert_eq!(regions.covering(0x2000, 0x3000), None);
+        assert_eq!(regions.covering(0x0800, 0x1800), None);
+        assert_eq!(regions.covering(0x4000, 0x6000), None);
+    }
+
+    #[test]
+    fn covering_looks_past_a_shorter_overlapping_registration() {
+        // the shorter one starts later, so it is looked at first and does not cover the range
+        let regions = regions(&[(0x1000, 0x9000), (0x2000, 0x3000)]);
+        assert_eq!(regions.covering(0x2000, 0x4000), Some((0x1000, 0x9000)));
+        assert_eq!(regions.covering(0x2000, 0x3000), Some((0x2000, 0x3000)));
+    }
+
+    #[test]
+    fn the_last_release_returns_the_registration() {
+        let mut regions = regions(&[]);
+        let key = regions.insert(0x1000, 0x2000, "mr");
+        let (_, first) = regions.acquire(key);
+        let (_, second) = regions.acquire(key);
+        assert_eq!(first, second);
+        assert_eq!(regions.release(key, first), None);
+        assert!(regions.covering(0x1000, 0x2000).is_some());
+        assert_eq!(regions.release(key, second), Some("mr"));
+        assert!(regions.covering(0x1000, 0x2000).is_none());
+    }
+
+    #[test]
+    fn invalidate_takes_out_held_registrations_that_overlap() {
+        let mut regions = regions(&[(0x1000, 0x2000), (0x2000, 0x3000), (0x3000, 0x4000)]);
+        let (_, id) = regions.acquire((0x2000, 0x3000));
+        assert_eq!(regions.invalidate(0x1800, 0x1000).len(), 2);
+        assert_eq!(regions.covering(0x1000, 0x1800), None);
+        assert_eq!(regions.covering(0x2000, 0x2800), None);
+        assert_eq!(regions.covering(0x3000, 0x4000), Some((0x3000, 0x4000)));
+        // the hold taken before is let go without touching anything
+        assert_eq!(regions.release((0x2000, 0x3000), id), None);
+    }
+
+    #[test]
+    fn a_stale_hold_leaves_a_new_registration_of_its_range_alone() {
+        let mut regions = regions(&[]);
+        let key = regions.insert(0x1000, 0x2000, "old");
+        let (_, old) = regions.acquire(key);
+        assert_eq!(regions.invalidate(0x1000, 0x1000), vec!["old"]);
+        let key = regions.insert(0x1000, 0x2000, "new");
+        let (_, new) = regions.acquire(key);
+        assert_eq!(regions.release(key, old), None);
+        assert_eq!(regions.release(key, new), Some("new"));
+    }
+}
@@ -0,0 +1,496 @@
+use rdma_core::{
+    ibverbs::{ibv_query_qp, ibv_reg_mr, IbvMr, IbvPd},
+    rdma::{rdma_post_read, rdma_post_write_with_opcode, RdmaCmId, RdmaConnParam},
+};
+use rdma_core_sys::{
+    ibv_qp_attr, ntohl, IBV_ACCESS_LOCAL_WRITE, IBV_ACCESS_REMOTE_READ, IBV_QP_CAP,
+    IBV_SEND_SIGNALED, IBV_WC_RECV_RDMA_WITH_IMM, IBV_WC_SUCCESS, IBV_WR_RDMA_WRITE_WITH_IMM,
+    RDMA_MAX_INIT_DEPTH, RDMA_MAX_RESP_RES,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{MemBuffer, Result, TransportErrors, CPU_BUFFER_BASE_SIZE, OFFSET_SLOTS};
+
+use super::{supports_atomics, CompletionQueue, Connection, Connections, QpOptions, RecvQueue};
+
+pub const PROTOCOL_MAGIC: u32 = u32::from_be_bytes(*b"RDTP");
+/// Only moves when a change can neither be offered as a feature nor fit in [`Hello::reserved`].
+/// The hello layout below is the first one released; the ones tried before it during
+/// development are not supported.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest version this build still talks to. It stays put when [`PROTOCOL_VERSION`] moves,
+/// for as long as the older version's code is kept, so that adjacent builds still connect.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Notifications are RDMA writes with immediate into the peer's control buffer.
+pub const FEATURE_NOTIFY_WITH_IMM: u32 = 1;
+/// The registered buffers accept RDMA reads from the peer.
+pub const FEATURE_RDMA_READ: u32 = 1 << 1;
+/// The buffer table is RDMA-read from the peer instead of written into the control buffer, so
+/// it is not limited to the control buffer's size.
+pub const FEATURE_REMOTE_BUFFER_TABLE: u32 = 1 << 2;
+/// Notifications go round a ring of `OFFSET_SLOTS` slots with the consumer count written back,
+/// instead of all landing in slot 0 one at a time.
+pub const FEATURE_NOTIFY_RING: u32 = 1 << 3;
+/// The registered buffers accept RDMA atomics from the peer.
+pub const FEATURE_REMOTE_ATOMIC: u32 = 1 << 4;
+/// Messages go as SENDs into `MESSAGE_CREDITS` receives of `MESSAGE_FRAGMENT_SIZE` bytes,
+/// with the consumer count written back like the notification ring's.
+pub const FEATURE_MESSAGES: u32 = 1 << 5;
+/// Messages over `EAGER_LIMIT` bytes are RDMA-read by the receiver out of the sender's memory.
+pub const FEATURE_RENDEZVOUS: u32 = 1 << 6;
+
+pub const SUPPORTED_FEATURES: u32 = FEATURE_NOTIFY_WITH_IMM
+    | FEATURE_RDMA_READ
+    | FEATURE_REMOTE_BUFFER_TABLE
+    | FEATURE_NOTIFY_RING
+    | FEATURE_REMOTE_ATOMIC
+    | FEATURE_MESSAGES
+    | FEATURE_RENDEZVOUS;
+pub const REQUIRED_FEATURES: u32 = FEATURE_NOTIFY_WITH_IMM;
+
+// private data limits of an RC connection on RDMA_PS_TCP, after the rdma_cm header
+pub const MAX_CONNECT_PRIVATE_DATA: usize = 56;
+pub const MAX_ACCEPT_PRIVATE_DATA: usize = 196;
+pub const MAX_REJECT_PRIVATE_DATA: usize = 148;
+
+/// Carried as the private data of the connect request and of the accept or reject answering
+/// it, so both sides know each other before the QP carries any traffic.
+///
+/// bincode lays the fields out in declaration order, so `magic` and the version range can
+/// be read from any peer. The whole message fills [`MAX_CONNECT_PRIVATE_DATA`], so new
+/// behaviour is offered as a feature bit, and a new field takes bytes out of `reserved`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub magic: u32,
+    pub version: u16,
+    pub min_version: u16,
+    pub features: u32,
+    pub required_features: u32,
+    /// The largest notification the sender can receive in its control buffer.
+    pub max_message_size: u32,
+    pub send_queue_depth: u32,
+    /// Receives the sender keeps posted for notifications: how many may be outstanding.
+    pub recv_credits: u32,
+    /// The control buffer the peer writes notifications and the buffer table into.
+    pub control: Connection,
+    /// Sent as zeros and ignored by this version; a field carved out of them later must take
+    /// zero to mean what a peer that predates it does.
+    pub reserved: [u8; 8],
+}
+
+impl Hello {
+    pub fn new(cm_id: &RdmaCmId, control: Connection, recv_credits: u32) -> Result<Hello> {
+        let mut attr = ibv_qp_attr::default();
+        ibv_query_qp(cm_id.qp, &mut attr, IBV_QP_CAP as i32, None)?;
+        // only offered when the HCA can carry them out on our buffers
+        let mut features = SUPPORTED_FEATURES;
+        if !cm_id.pd().is_some_and(|pd| supports_atomics(&pd)) {
+            features &= !FEATURE_REMOTE_ATOMIC;
+        }
+        // the receives of an SRQ have no room for messages
+        if unsafe { !(*cm_id.qp).srq.is_null() } {
+            features &= !(FEATURE_MESSAGES | FEATURE_RENDEZVOUS);
+        }
+        Ok(Hello {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
+            required_features: REQUIRED_FEATURES,
+            max_message_size: CPU_BUFFER_BASE_SIZE as u32,
+            send_queue_depth: attr.cap.max_send_wr,
+            recv_credits,
+            control,
+            reserved: [0; 8],
+        })
+    }
+
+    pub fn encode(&self, limit: usize) -> Result<Vec<u8>> {
+        let data = bincode::serialize(self)
+            .map_err(|e| TransportErrors::OpsFailed("encode_hello".to_string(), e.to_string()))?;
+        if data.len() > limit {
+            return Err(TransportErrors::OpsFailed(
+                "encode_hello".to_string(),
+                format!(
+                    "hello of {} bytes exceeds {} bytes of private data",
+                    data.len(),
+                    limit
+                ),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Reads the hello out of the id's current connection event.
+    pub fn decode(cm_id: &RdmaCmId) -> Result<Hello> {
+        // peers from before the handshake was versioned send no private data, which an IB CM
+        // pads to the full length with zeros; that decodes, and fails the magic check instead
+        let data = cm_id.event_private_data().ok_or_else(|| {
+            TransportErrors::HandshakeRejected(
+                "no hello in the private data, the peer predates protocol versioning".to_string(),
+            )
+        })?;
+        bincode::deserialize::<Hello>(&data).map_err(|e| {
+            TransportErrors::HandshakeRejected(format!(
+                "undecodable hello of {} bytes: {}",
+                data.len(),
+                e
+            ))
+        })
+    }
+
+    /// The connection parameters for `rdma_connect`/`rdma_accept` carrying `private_data`,
+    /// which must outlive the call.
+    pub fn conn_param(private_data: &[u8], options: &QpOptions) -> RdmaConnParam {
+        let mut param = RdmaConnParam::default();
+        param.private_data = private_data.as_ptr() as *const _;
+        param.private_data_len = private_data.len() as u8;
+        // a param replaces librdmacm's defaults wholesale, so ask for them explicitly
+        param.responder_resources = RDMA_MAX_RESP_RES as u8;
+        param.initiator_depth = RDMA_MAX_INIT_DEPTH as u8;
+        param.retry_count = options.retry_count;
+        param.rnr_retry_count = options.rnr_retry_count;
+        param
+    }
+}
+
+/// What both sides of a connection agreed on.
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: u32,
+    pub max_message_size: u32,
+    pub peer_send_queue_depth: u32,
+    /// Notifications we may have outstanding before the peer reports them consumed.
+    pub send_credits: u32,
+    /// Notifications the peer may have outstanding with us.
+    pub recv_credits: u32,
+}
+
+impl Negotiated {
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+/// Checks the peer's hello against ours. Both sides run the same checks on the same pair of
+/// messages, so they either both accept or both reject.
+pub fn negotiate(local: &Hello, peer: &Hello) -> Result<Negotiated> {
+    if peer.magic != PROTOCOL_MAGIC {
+        return Err(TransportErrors::HandshakeRejected(format!(
+            "bad magic {:#010x}, the peer is not an rdma-transport endpoint",
+            peer.magic
+        )));
+    }
+
+    let version = local.version.min(peer.version);
+    if version < local.min_version.max(peer.min_version) {
+        return Err(TransportErrors::HandshakeRejected(format!(
+            "no common protocol version: local speaks {}..={}, peer speaks {}..={}",
+            local.min_version, local.version, peer.min_version, peer.version
+        )));
+    }
+
+    let missing = local.required_features & !peer.features;
+    if missing != 0 {
+        return Err(TransportErrors::HandshakeRejected(format!(
+            "peer lacks required features {:#x}",
+            missing
+        )));
+    }
+    let missing = peer.required_features & !local.features;
+    if missing != 0 {
+        return Err(TransportErrors::HandshakeRejected(format!(
+            "peer requires unsupported features {:#x}",
+            missing
+        )));
+    }
+
+    let features = local.features & peer.features;
+    Ok(Negotiated {
+        version,
+        features,
+        max_message_size: local.max_message_size.min(peer.max_message_size),
+        peer_send_queue_depth: peer.send_queue_depth,
+        // without the ring nothing reports consumption, so the receive queue is left to RNR
+        // retries as before
+        send_credits: if features & FEATURE_NOTIFY_RING != 0 {
+            peer.recv_credits.clamp(1, OFFSET_SLOTS as u32)
+        } else {
+            1
+        },
+        recv_credits: local.recv_credits,
+    })
+}
+
+/// The serialized local buffer table, registered so the peer can RDMA-read it however many
+/// buffers it lists. The connection keeps it: the peer may still be reading it after our side
+/// of the handshake has returned.
+pub(super) struct BufferTable {
+    data: Vec<u8>,
+    mr: IbvMr,
+}
+
+impl BufferTable {
+    pub(super) fn new(pd: &IbvPd, buffers: &Connections) -> Result<BufferTable> {
+        let mut data = bincode::serialize(buffers)
+            .map_err(|e| TransportErrors::OpsFailed("buffer_table".to_string(), e.to_string()))?;
+        // the peer would refuse to read it, see `read_table`
+        if data.len() as u64 > MAX_TABLE_SIZE {
+            return Err(TransportErrors::OpsFailed(
+                "buffer_table".to_string(),
+                format!("{} buffers are too many to list", buffers.len()),
+            ));
+        }
+        let mr = ibv_reg_mr(pd, &mut data, IBV_ACCESS_REMOTE_READ as i32)?;
+        Ok(BufferTable { data, mr })
+    }
+}
+
+// A table takes some 20 bytes per buffer, so this is millions of buffers. It bounds what a
+// descriptor can make us allocate and register.
+const MAX_TABLE_SIZE: u64 = 64 << 20;
+
+// sent through the control buffer in place of the table when the peer can read it
+#[derive(Debug, Serialize, Deserialize)]
+struct TableDescriptor {
+    table: Connection,
+    len: u64,
+}
+
+pub(super) async fn send_buffers(
+    cm_id: &mut RdmaCmId,
+    send_cq: &mut CompletionQueue,
+    cpu_mr: &mut IbvMr,
+    cpu_buffer: &mut MemBuffer,
+    peer: &Hello,
+    protocol: &Negotiated,
+    table: &BufferTable,
+) -> Result<()> {
+    if !protocol.has(FEATURE_REMOTE_BUFFER_TABLE) {
+        return send_control(
+            cm_id,
+            send_cq,
+            cpu_mr,
+            cpu_buffer,
+            peer,
+            protocol,
+            &table.data,
+        )
+        .await;
+    }
+
+    let descriptor = TableDescriptor {
+        table: Connection::new(
+            table.data.as_ptr() as u64,
+            table.data.len() as u64,
+            table.mr.rkey,
+        ),
+        len: table.data.len() as u64,
+    };
+    let data = bincode::serialize(&descriptor)
+        .map_err(|e| TransportErrors::OpsFailed("send_buffers".to_string(), e.to_string()))?;
+    send_control(cm_id, send_cq, cpu_mr, cpu_buffer, peer, protocol, &data).await
+}
+
+// Reads the peer's table off the send CQ, so it must run before the transfer engine takes
+// that CQ over.
+pub(super) async fn recv_buffers(
+    cm_id: &mut RdmaCmId,
+    send_cq: &mut CompletionQueue,
+    recv: &mut RecvQueue,
+    cpu_buffer: &MemBuffer,
+    protocol: &Negotiated,
+) -> Result<Connections> {
+    let mut data = recv_control(recv, cpu_buffer, protocol).await?;
+    if protocol.has(FEATURE_REMOTE_BUFFER_TABLE) {
+        let descriptor = bincode::deserialize::<TableDescriptor>(&data)
+            .map_err(|e| TransportErrors::OpsFailed("recv_buffers".to_string(), e.to_string()))?;
+        data = read_table(cm_id, send_cq, &descriptor).await?;
+    }
+    bincode::deserialize::<Connections>(&data)
+        .map_err(|e| TransportErrors::OpsFailed("recv_buffers".to_string(), e.to_string()))
+}
+
+async fn read_table(
+    cm_id: &mut RdmaCmId,
+    send_cq: &mut CompletionQueue,
+    descriptor: &TableDescriptor,
+) -> Result<Vec<u8>> {
+    let pd = cm_id.pd().ok_or_else(|| {
+        TransportErrors::OpsFailed("read_table".to_string(), "cm_id has no pd".to_string())
+    })?;
+    if descriptor.len == 0
+        || descriptor.len > MAX_TABLE_SIZE
+        || descriptor.len > descriptor.table.get_size()
+    {
+        return Err(TransportErrors::OpsFailed(
+            "read_table".to_string(),
+            format!(
+                "table of {} bytes is empty, above the {} byte maximum or past its buffer",
+                descriptor.len, MAX_TABLE_SIZE
+            ),
+        ));
+    }
+    let mut data = vec![0; descriptor.len as usize];
+    let mut mr = ibv_reg_mr(&pd, &mut data, IBV_ACCESS_LOCAL_WRITE as i32)?;
+    rdma_post_read(
+        cm_id,
+        None::<&mut u32>,
+        data.as_mut_ptr() as u64,
+        data.len(),
+        Some(&mut mr),
+        IBV_SEND_SIGNALED,
+        descriptor.table.get_base_ptr(),
+        descriptor.table.get_mr_rkey(),
+    )?;
+    let wc = send_cq.poll().await?;
+    if wc.status != IBV_WC_SUCCESS {
+        return Err(TransportErrors::OpsFailed(
+            "read_table".to_string(),
+            format!("poll_read_comp failed with status: {:?}", wc.status),
+        ));
+    }
+    Ok(data)
+}
+
+// Written from the same buffer the peer's message is received into. That is safe because the
+// server only writes after the client's message has arrived, i.e. after it left this buffer.
+async fn send_control(
+    cm_id: &mut RdmaCmId,
+    send_cq: &mut CompletionQueue,
+    cpu_mr: &mut IbvMr,
+    cpu_buffer: &mut MemBuffer,
+    peer: &Hello,
+    protocol: &Negotiated,
+    data: &[u8],
+) -> Result<()> {
+    // the peer rejects anything above the negotiated size, see `recv_control`
+    if data.len() > protocol.max_message_size as usize {
+        return Err(TransportErrors::OpsFailed(
+            "send_control".to_string(),
+            format!(
+                "message of {} bytes exceeds the negotiated {} byte maximum",
+                data.len(),
+                protocol.max_message_size
+            ),
+        ));
+    }
+    cpu_buffer[0..data.len()].copy_from_slice(data);
+
+    rdma_post_write_with_opcode(
+        cm_id,
+        None::<&mut u32>,
+        cpu_buffer.get_ptr(),
+        data.len(),
+        Some(cpu_mr),
+        IBV_SEND_SIGNALED,
+        peer.control.get_base_ptr(),
+        peer.control.get_mr_rkey(),
+        IBV_WR_RDMA_WRITE_WITH_IMM,
+        data.len() as u32,
+    )?;
+    let wc = send_cq.poll().await?;
+    if wc.status != IBV_WC_SUCCESS {
+        return Err(TransportErrors::OpsFailed(
+            "send_control".to_string(),
+            format!("poll_write_comp failed with status: {:?}", wc.status),
+        ));
+    }
+    Ok(())
+}
+
+async fn recv_control(
+    recv: &mut RecvQueue,
+    cpu_buffer: &MemBuffer,
+    protocol: &Negotiated,
+) -> Result<Vec<u8>> {
+    let wc = recv.poll().await?;
+    if wc.status != IBV_WC_SUCCESS {
+        return Err(TransportErrors::OpsFailed(
+            "recv_control".to_string(),
+            format!("poll_recv_comp failed with status: {:?}", wc.status),
+        ));
+    }
+    if wc.opcode != IBV_WC_RECV_RDMA_WITH_IMM {
+        return Err(TransportErrors::OpsFailed(
+            "recv_control".to_string(),
+            "expect IBV_WR_RDMA_WRITE_WITH_IMM opcode".to_string(),
+        ));
+    }
+
+    // the size is the peer's word, so it is checked before it indexes the buffer
+    let size = unsafe { ntohl(wc.__bindgen_anon_1.imm_data) } as usize;
+    if size > cpu_buffer.len() || size > protocol.max_message_size as usize {
+        return Err(TransportErrors::OpsFailed(
+            "recv_control".to_string(),
+            format!(
+                "message of {} bytes exceeds the {} byte maximum",
+                size,
+                protocol.max_message_size.min(cpu_buffer.len() as u32)
+            ),
+        ));
+    }
+    Ok(cpu_buffer[0..size].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        negotiate, Hello, FEATURE_MESSAGES, FEATURE_NOTIFY_RING, FEATURE_NOTIFY_WITH_IMM,
+        MAX_CONNECT_PRIVATE_DATA, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION,
+        REQUIRED_FEATURES, SUPPORTED_FEATURES,
+    };
+    use crate::rdma::Connection;
+
+    fn hello(min_version: u16, version: u16, features: u32) -> Hello {
+        Hello {
+            magic: PROTOCOL_MAGIC,
+            version,
+            min_version,
+            features,
+            required_features: REQUIRED_FEATURES,
+            max_message_size: 4096,
+            send_queue_depth: 128,
+            recv_credits: 16,
+            control: Connection::new(u64::MAX, u64::MAX, u32::MAX),
+            reserved: [0; 8],
+        }
+    }
+
+    #[test]
+    fn a_hello_fits_the_connect_private_data() {
+        let hello = hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SUPPORTED_FEATURES);
+        let data = hello.encode(MAX_CONNECT_PRIVATE_DATA).unwrap();
+        assert_eq!(data.len(), MAX_CONNECT_PRIVATE_DATA);
+        // the spare room is at the end, for fields added later
+        assert!(data[data.len() - 8..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn adjacent_versions_settle_on_the_older() {
+        let older = hello(1, 1, SUPPORTED_FEATURES);
+        let newer = hello(1, 2, SUPPORTED_FEATURES);
+        assert_eq!(negotiate(&newer, &older).unwrap().version, 1);
+        assert_eq!(negotiate(&older, &newer).unwrap().version, 1);
+
+        let dropped_v1 = hello(2, 3, SUPPORTED_FEATURES);
+        assert!(negotiate(&older, &dropped_v1).is_err());
+    }
+
+    #[test]
+    fn features_are_those_both_sides_offer() {
+        let local = hello(1, 1, FEATURE_NOTIFY_WITH_IMM | FEATURE_NOTIFY_RING);
+        let peer = hello(1, 1, FEATURE_NOTIFY_WITH_IMM | FEATURE_MESSAGES);
+        let negotiated = negotiate(&local, &peer).unwrap();
+        assert!(negotiated.has(FEATURE_NOTIFY_WITH_IMM));
+        assert!(!negotiated.has(FEATURE_NOTIFY_RING));
+        assert!(!negotiated.has(FEATURE_MESSAGES));
+        // one credit, as nothing reports consumption without the ring
+        assert_eq!(negotiated.send_credits, 1);
+
+        let lacking = hello(1, 1, FEATURE_MESSAGES);
+        assert!(negotiate(&local, &lacking).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    net::SocketAddr,
+    ptr::null_mut,
+    sync::{Arc, Mutex, Weak},
+};
+
+use rdma_core::{
+    ibverbs::{
+        ibv_create_comp_channel, ibv_create_cq, ibv_create_srq, ibv_post_srq_recv, IbvCq, IbvPd,
+        IbvSrq,
+    },
+    rdma::RdmaCmId,
+};
+use rdma_core_sys::{ibv_recv_wr, ibv_wc};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{GPUMemBuffer, Result, TransportErrors};
+
+use super::{
+    message::{recv_depth, RecvPool},
+    server, sge_limits, CompletionQueue, QpOptions, RdmaConnection, RdmaDevice,
+};
+
+/// Where a connection's receive completions come from: a receive queue and CQ of its own, or
+/// its share of a [`SharedListener`]'s SRQ.
+pub(super) enum RecvQueue {
+    Own(CompletionQueue, RecvPool),
+    Shared(SharedRoute),
+}
+
+impl RecvQueue {
+    /// The receive queue and CQ rdma_cm created for `cm_id`.
+    pub fn own(cm_id: &RdmaCmId, options: &QpOptions) -> Result<RecvQueue> {
+        Ok(RecvQueue::Own(
+            CompletionQueue::new(cm_id, cm_id.recv_cq)?,
+            RecvPool::new(cm_id, recv_depth(options) as usize)?,
+        ))
+    }
+
+    pub async fn poll(&mut self) -> Result<ibv_wc> {
+        match self {
+            RecvQueue::Own(cq, _) => cq.poll().await,
+            RecvQueue::Shared(route) => route.completions.recv().await.ok_or_else(|| {
+                TransportErrors::OpsFailed(
+                    "recv_queue".to_string(),
+                    "the shared receive queue is gone".to_string(),
+                )
+            }),
+        }
+    }
+
+    /// Posts `count` receives. The SRQ is topped up as its receives are consumed, so there is
+    /// nothing to post for a shared one.
+    pub fn post(&mut self, cm_id: &RdmaCmId, count: usize) -> Result<()> {
+        match self {
+            RecvQueue::Own(_, pool) => pool.post(cm_id, count),
+            RecvQueue::Shared(_) => Ok(()),
+        }
+    }
+
+    /// The message fragment a SEND left in the receive `wc` completed. The SRQ's receives are
+    /// zero-length, so a connection on one cannot take messages.
+    pub fn fragment(&self, wc: &ibv_wc) -> Result<&[u8]> {
+        match self {
+            RecvQueue::Own(_, pool) => Ok(pool.fragment(wc)),
+            RecvQueue::Shared(_) => Err(TransportErrors::OpsFailed(
+                "recv_queue".to_string(),
+                "a shared receive queue has no buffers for messages".to_string(),
+            )),
+        }
+    }
+}
+
+// one SRQ and one recv CQ for every connection of a listener; a task drains the CQ and hands
+// each completion to the connection of the QP it came in on
+struct SharedRecv {
+    srq: IbvSrq,
+    cq: IbvCq,
+    routes: Mutex<Routes>,
+    dispatcher: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Routes {
+    senders: HashMap<u32, UnboundedSender<ibv_wc>>,
+    // the credits granted to the connections attached, and the receives posted to cover them
+    granted: usize,
+    posted: usize,
+}
+
+impl SharedRecv {
+    fn new(pd: &IbvPd, depth: u32) -> Result<Arc<SharedRecv>> {
+        let channel = ibv_create_comp_channel(pd)?;
+        let cq = ibv_create_cq(&channel, depth as i32)?;
+        let srq = ibv_create_srq(pd, depth, 1)?;
+        let completions = CompletionQueue::from_cq(cq.clone())?;
+        Ok(Arc::new_cyclic(|shared| SharedRecv {
+            srq,
+            cq,
+            routes: Mutex::default(),
+            dispatcher: tokio::spawn(dispatch(completions, shared.clone())),
+        }))
+    }
+
+    // the route has to be in place before the QP can see its first write with immediate
+    fn attach(self: &Arc<Self>, cm_id: &RdmaCmId, credits: usize) -> Result<RecvQueue> {
+        let qp_num = unsafe { (*cm_id.qp).qp_num };
+        let (tx, rx) = unbounded_channel();
+        let mut routes = self.routes.lock().unwrap();
+        routes.granted += credits;
+        if let Err(e) = self.replenish(&mut routes) {
+            routes.granted -= credits;
+            return Err(e);
+        }
+        routes.senders.insert(qp_num, tx);
+        Ok(RecvQueue::Shared(SharedRoute {
+            shared: self.clone(),
+            qp_num,
+            credits,
+            completions: rx,
+        }))
+    }
+
+    // receives beyond what is granted are left to drain when a connection goes away
+    fn replenish(&self, routes: &mut Routes) -> Result<()> {
+        while routes.posted < routes.granted {
+            let mut wr = ibv_recv_wr::default();
+            let mut bad = null_mut();
+            ibv_post_srq_recv(self.srq.as_ptr(), &mut wr, &mut bad)?;
+            routes.posted += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedRecv {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+async fn dispatch(mut completions: CompletionQueue, shared: Weak<SharedRecv>) {
+    while let Ok(wc) = completions.poll().await {
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        let mut routes = shared.routes.lock().unwrap();
+        routes.posted -= 1;
+        // a connection that failed to keep up gets RNR retries until the next completion
+        // replenishes the queue
+        let _ = shared.replenish(&mut routes);
+        if let Some(tx) = routes.senders.get(&wc.qp_num) {
+            let _ = tx.send(wc);
+        }
+    }
+}
+
+/// A connection's share of the SRQ; its credits are given back when it is dropped.
+pub(super) struct SharedRoute {
+    shared: Arc<SharedRecv>,
+    qp_num: u32,
+    credits: usize,
+    completions: UnboundedReceiver<ibv_wc>,
+}
+
+impl Drop for SharedRoute {
+    fn drop(&mut self) {
+        let mut routes = self.shared.routes.lock().unwrap();
+        routes.senders.remove(&self.qp_num);
+        routes.granted -= self.credits;
+    }
+}
+
+/// A listener whose connections share one SRQ and one receive CQ instead of holding a receive
+/// queue and CQ each, for servers with many mostly idle clients.
+///
+/// The SRQ holds `srq_depth` receives and every connection is granted
+/// [`QpOptions::recv_credits`] of them, which bounds how many can be connected at once. Send
+/// queues and CQs are still per connection.
+pub struct SharedListener {
+    listen_id: RdmaCmId,
+    device: RdmaDevice,
+    shared: Arc<SharedRecv>,
+    options: QpOptions,
+}
+
+impl SharedListener {
+    /// Binds and listens on `bind_addr`; must be called from within a tokio runtime.
+    pub fn bind(
+        bind_addr: &SocketAddr,
+        device: &RdmaDevice,
+        options: &QpOptions,
+        srq_depth: u32,
+    ) -> Result<SharedListener> {
+        let shared = SharedRecv::new(device.pd(), srq_depth)?;
+
+        let mut qp_init_attr = server::qp_init_attr(options, 0);
+        qp_init_attr.srq = shared.srq.as_ptr();
+        qp_init_attr.recv_cq = shared.cq.as_ptr();
+        qp_init_attr.cap.max_recv_wr = 0;
+        qp_init_attr.cap.max_recv_sge = 0;
+        qp_init_attr.cap.max_send_sge = sge_limits(device.pd().context())?.0;
+
+        // every cm_id from this listener holds the PD through this handle, so the SRQ and CQ
+        // its QP uses outlive it
+        let owner: Arc<dyn Any> = shared.clone();
+        let pd = unsafe { IbvPd::borrowed(device.pd().as_ptr(), owner) }.ok_or_else(|| {
+            TransportErrors::OpsFailed("SharedListener::bind".to_string(), "no pd".to_string())
+        })?;
+        let listen_id = server::bind(bind_addr, &pd, qp_init_attr)?;
+        Ok(SharedListener {
+            listen_id,
+            device: device.clone(),
+            shared,
+            options: *options,
+        })
+    }
+
+    pub async fn listen(&mut self) -> Result<SharedIncoming> {
+        Ok(SharedIncoming {
+            cm_id: server::get_request(&mut self.listen_id).await?,
+            device: self.device.clone(),
+            shared: self.shared.clone(),
+            options: self.options,
+        })
+    }
+}
+
+/// A connection request from [`SharedListener::listen`], to be accepted on a task of its own.
+pub struct SharedIncoming {
+    cm_id: RdmaCmId,
+    device: RdmaDevice,
+    shared: Arc<SharedRecv>,
+    options: QpOptions,
+}
+
+impl SharedIncoming {
+    /// [`accept`](super::accept) for a connection receiving through the listener's SRQ.
+    pub async fn accept(
+        self,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<RdmaConnection> {
+        let recv = self
+            .shared
+            .attach(&self.cm_id, self.options.credits() as usize)?;
+        server::accept_with(
+            self.cm_id,
+            &self.device,
+            recv,
+            gpu_ordinal,
+            gpu_buffers,
+            &self.options,
+        )
+        .await
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{rdma::RdmaConnection, Result, TransportErrors};
+
+use super::{decode, encode, Frame, Messages};
+
+// request id -> the call waiting for its response
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<std::result::Result<Vec<u8>, String>>>>>;
+
+/// Calls the handlers of an [`RpcServer`](super::RpcServer) on the other end of a connection.
+///
+/// A background task owns the connection and matches responses to calls by request id, so
+/// any number of calls can be outstanding. Clones share the connection, which is closed once
+/// the last of them is dropped.
+#[derive(Clone)]
+pub struct RpcClient {
+    outgoing: UnboundedSender<Frame>,
+    pub(super) pending: Pending,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RpcClient {
+    /// Takes `conn` over; must be called from within a tokio runtime.
+    ///
+    /// The connection carries nothing but calls from then on: no transfers can be posted on
+    /// it, and the notifications the peer sends on it are dropped as they come in.
+    pub fn new(conn: RdmaConnection) -> RpcClient {
+        RpcClient::over(conn)
+    }
+
+    pub(super) fn over<M: Messages + 'static>(conn: M) -> RpcClient {
+        let (outgoing, rx) = unbounded_channel();
+        let pending = Pending::default();
+        tokio::spawn(drive(conn, rx, pending.clone()));
+        RpcClient {
+            outgoing,
+            pending,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Calls `method` with `request` and waits for its response. Dropping the future cancels
+    /// the call, and the server aborts the handler if it is still running.
+    pub async fn call<Req, Resp>(&self, method: u32, request: &Req) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let payload = encode(request)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        let _cancel = CancelOnDrop { client: self, id };
+
+        self.outgoing
+            .send(Frame::Request {
+                id,
+                method,
+                payload,
+            })
+            .map_err(|_| {
+                TransportErrors::OpsFailed("call".to_string(), "the connection is gone".to_string())
+            })?;
+        match rx.await {
+            Ok(Ok(response)) => decode(&response),
+            Ok(Err(e)) => Err(TransportErrors::OpsFailed(
+                format!("call of method {}", method),
+                e,
+            )),
+            Err(_) => Err(TransportErrors::OpsFailed(
+                "call".to_string(),
+                "the connection is gone".to_string(),
+            )),
+        }
+    }
+
+    /// [`call`](Self::call), cancelled if no response came in within `timeout`.
+    pub async fn call_timeout<Req, Resp>(
+        &self,
+        method: u32,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        tokio::time::timeout(timeout, self.call(method, request))
+            .await
+            .map_err(|_| {
+                TransportErrors::TimedOut(format!("call of method {} after {:?}", method, timeout))
+            })?
+    }
+}
+
+// tells the server about a call dropped before its response came in
+struct CancelOnDrop<'a> {
+    client: &'a RpcClient,
+    id: u64,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        let waiting = self.client.pending.lock().unwrap().remove(&self.id);
+        if waiting.is_some() {
+            let _ = self.client.outgoing.send(Frame::Cancel { id: self.id });
+        }
+    }
+}
+
+async fn drive<M: Messages>(mut conn: M, mut outgoing: UnboundedReceiver<Frame>, pending: Pending) {
+    if let Err(e) = exchange(&mut conn, &mut outgoing, &pending).await {
+        // first, so a call made from now on fails to send instead of waiting for a response
+        // that cannot come
+        outgoing.close();
+        for (_, tx) in pending.lock().unwrap().drain() {
+            let _ = tx.send(Err(e.to_string()));
+        }
+    }
+}
+
+async fn exchange<M: Messages>(
+    conn: &mut M,
+    outgoing: &mut UnboundedReceiver<Frame>,
+    pending: &Pending,
+) -> Result<()> {
+    loop {
+        // only the receives race: a frame is sent from the body of its arm, which select! does
+        // not cancel, so no send_msg is cut short
+        tokio::select! {
+            frame = outgoing.recv() => match frame {
+                Some(frame) => conn.send_msg(&encode(&frame)?).await?,
+                None => {
+                    conn.send_msg(&encode(&Frame::Close)?).await?;
+                    return conn.close().await;
+                }
+            },
+            message = conn.recv_msg() => match decode::<Frame>(&message?)? {
+                Frame::Response { id, result } => {
+                    // nobody can ask for them, so they would only pile up
+                    conn.discard_notifications();
+                    // gone if the call was cancelled meanwhile
+                    if let Some(tx) = pending.lock().unwrap().remove(&id) {
+                        let _ = tx.send(result);
+                    }
+                }
+                _ => {
+                    return Err(TransportErrors::OpsFailed(
+                        "call".to_string(),
+                        "the server sent something other than a response".to_string(),
+                    ))
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,277 @@
+mod client;
+mod server;
+
+pub use client::RpcClient;
+pub use server::RpcServer;
+
+use std::future::Future;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{rdma::RdmaConnection, Result, TransportErrors};
+
+// one message on the connection
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Request {
+        id: u64,
+        method: u32,
+        payload: Vec<u8>,
+    },
+    // a handler's error travels as its message
+    Response {
+        id: u64,
+        result: std::result::Result<Vec<u8>, String>,
+    },
+    // the caller gave up waiting for the response
+    Cancel {
+        id: u64,
+    },
+    // the last client handle was dropped
+    Close,
+}
+
+// what the client and server need of a connection, so they can be tested without a device
+trait Messages: Send {
+    fn send_msg(&mut self, message: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    fn recv_msg(&mut self) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    fn close(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    // drops the notifications taken in while waiting for messages
+    fn discard_notifications(&mut self);
+}
+
+impl Messages for RdmaConnection {
+    async fn send_msg(&mut self, message: &[u8]) -> Result<()> {
+        RdmaConnection::send_msg(self, message).await
+    }
+
+    async fn recv_msg(&mut self) -> Result<Vec<u8>> {
+        RdmaConnection::recv_msg(self).await
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        RdmaConnection::close(self).await
+    }
+
+    fn discard_notifications(&mut self) {
+        self.drain_notifications().for_each(drop);
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value)
+        .map_err(|e| TransportErrors::OpsFailed("rpc_encode".to_string(), e.to_string()))
+}
+
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    bincode::deserialize(data)
+        .map_err(|e| TransportErrors::OpsFailed("rpc_decode".to_string(), e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    };
+
+    use super::{decode, encode, Frame, Messages, RpcClient, RpcServer};
+    use crate::{Result, TransportErrors};
+
+    // one end of an in-memory connection
+    struct Pipe {
+        tx: UnboundedSender<Vec<u8>>,
+        rx: UnboundedReceiver<Vec<u8>>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a_tx, b_rx) = unbounded_channel();
+        let (b_tx, a_rx) = unbounded_channel();
+        (Pipe { tx: a_tx, rx: a_rx }, Pipe { tx: b_tx, rx: b_rx })
+    }
+
+    impl Messages for Pipe {
+        async fn send_msg(&mut self, message: &[u8]) -> Result<()> {
+            self.tx.send(message.to_vec()).map_err(|_| {
+                TransportErrors::OpsFailed("send_msg".to_string(), "closed".to_string())
+            })
+        }
+
+        async fn recv_msg(&mut self) -> Result<Vec<u8>> {
+            self.rx.recv().await.ok_or_else(|| {
+                TransportErrors::OpsFailed("recv_msg".to_string(), "closed".to_string())
+            })
+        }
+
+        async fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn discard_notifications(&mut self) {}
+    }
+
+    // tells whoever holds the receiver that it was dropped
+    struct DropSignal(Option<oneshot::Sender<()>>);
+
+    impl Drop for DropSignal {
+        fn drop(&mut self) {
+            let _ = self.0.take().unwrap().send(());
+        }
+    }
+
+    const DOUBLE: u32 = 1;
+    const FAIL: u32 = 2;
+    const PANIC: u32 = 3;
+
+    fn server() -> RpcServer {
+        let mut server = RpcServer::new();
+        server
+            .register(DOUBLE, |n: u64| async move {
+                // the larger, the sooner, so responses come back out of order
+                tokio::time::sleep(Duration::from_millis(50 - n)).await;
+                Ok(n * 2)
+            })
+            .register(FAIL, |_: ()| async move {
+                Err::<(), _>(TransportErrors::OpsFailed(
+                    "handler".to_string(),
+                    "refused".to_string(),
+                ))
+            })
+            .register(PANIC, |_: ()| async move {
+                if true {
+                    panic!("handler gave up");
+                }
+                Ok(())
+            });
+        server
+    }
+
+    // a client and a task serving it, which returns what `serve` did
+    fn connected(server: RpcServer) -> (RpcClient, tokio::task::JoinHandle<Result<()>>) {
+        let (client_end, mut server_end) = pipe();
+        let serving = tokio::spawn(async move { server.serve_on(&mut server_end).await });
+        (RpcClient::over(client_end), serving)
+    }
+
+    #[test]
+    fn frames_survive_encoding() {
+        let frames = [
+            Frame::Request {
+                id: 7,
+                method: DOUBLE,
+                payload: vec![1, 2, 3],
+            },
+            Frame::Response {
+                id: 7,
+                result: Ok(vec![4]),
+            },
+            Frame::Response {
+                id: 8,
+                result: Err("refused".to_string()),
+            },
+            Frame::Cancel { id: 9 },
+            Frame::Close,
+        ];
+        for frame in frames {
+            let decoded = decode::<Frame>(&encode(&frame).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", frame));
+        }
+        assert!(decode::<Frame>(&[0xff; 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn responses_find_their_calls() {
+        let (client, _serving) = connected(server());
+        let (a, b, c) = tokio::join!(
+            client.call::<_, u64>(DOUBLE, &1u64),
+            client.call::<_, u64>(DOUBLE, &20u64),
+            client.call::<_, u64>(DOUBLE, &40u64),
+        );
+        assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (2, 40, 80));
+        assert!(client.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_unknown_method_fails_the_call() {
+        let (client, _serving) = connected(server());
+        let e = client.call::<_, ()>(42, &()).await.unwrap_err();
+        assert!(e.to_string().contains("no handler for method 42"), "{}", e);
+        assert_eq!(client.call::<_, u64>(DOUBLE, &3u64).await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn a_handler_error_reaches_the_caller() {
+        let (client, _serving) = connected(server());
+        let e = client.call::<_, ()>(FAIL, &()).await.unwrap_err();
+        assert!(e.to_string().contains("refused"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_fails_its_call() {
+        let (client, _serving) = connected(server());
+        let e = tokio::time::timeout(Duration::from_secs(5), client.call::<_, ()>(PANIC, &()))
+            .await
+            .expect("the call hung")
+            .unwrap_err();
+        assert!(e.to_string().contains("panicked"), "{}", e);
+        assert_eq!(client.call::<_, u64>(DOUBLE, &3u64).await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_call_is_cancelled_on_the_server() {
+        let (dropped_tx, dropped) = oneshot::channel();
+        let dropped_tx = std::sync::Mutex::new(Some(dropped_tx));
+        let mut server = server();
+        server.register(4, move |_: ()| {
+            let signal = DropSignal(dropped_tx.lock().unwrap().take());
+            async move {
+                let _signal = signal;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            }
+        });
+        let (client, _serving) = connected(server);
+
+        let e = client
+            .call_timeout::<_, ()>(4, &(), Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert!(matches!(e, TransportErrors::TimedOut(_)), "{}", e);
+        assert!(client.pending.lock().unwrap().is_empty());
+        tokio::time::timeout(Duration::from_secs(5), dropped)
+            .await
+            .expect("the handler was not aborted")
+            .unwrap();
+        assert_eq!(client.call::<_, u64>(DOUBLE, &3u64).await.unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_client_ends_serve() {
+        let (client, serving) = connected(server());
+        let clone = client.clone();
+        drop(client);
+        assert_eq!(clone.call::<_, u64>(DOUBLE, &3u64).await.unwrap(), 6);
+        drop(clone);
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_lost_connection_fails_calls_in_flight_and_after() {
+        let (client_end, server_end) = pipe();
+        let client = RpcClient::over(client_end);
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.call::<_, ()>(DOUBLE, &()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(server_end);
+        assert!(call.await.unwrap().is_err());
+        let later = tokio::time::timeout(Duration::from_secs(5), client.call::<_, ()>(DOUBLE, &()));
+        assert!(later.await.expect("the call hung").is_err());
+    }
+}
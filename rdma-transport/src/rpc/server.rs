@@ -0,0 +1,122 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::task::{AbortHandle, Id, JoinSet};
+
+use crate::{rdma::RdmaConnection, Result, TransportErrors};
+
+use super::{decode, encode, Frame, Messages};
+
+type Handler =
+    Arc<dyn Fn(Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>> + Send + Sync>;
+
+/// Handlers by method id, to answer an [`RpcClient`](super::RpcClient) on the other end of a
+/// connection. Requests and responses are bincode-encoded.
+#[derive(Clone, Default)]
+pub struct RpcServer {
+    handlers: HashMap<u32, Handler>,
+}
+
+impl RpcServer {
+    pub fn new() -> RpcServer {
+        RpcServer::default()
+    }
+
+    /// Registers `handler` for `method`, replacing the one registered before. An error it
+    /// returns reaches the caller as its message, and so does a panic.
+    pub fn register<Req, Resp, F, Fut>(&mut self, method: u32, handler: F) -> &mut Self
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.handlers.insert(
+            method,
+            Arc::new(move |payload: Vec<u8>| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let response = handler(decode::<Req>(&payload)?);
+                    encode(&response.await?)
+                }) as Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>
+            }),
+        );
+        self
+    }
+
+    /// Answers requests on `conn` until the client closes it. Each request runs on a task of
+    /// its own, so a slow handler does not hold up the others; a cancelled one is aborted.
+    pub async fn serve(&self, conn: &mut RdmaConnection) -> Result<()> {
+        self.serve_on(conn).await
+    }
+
+    pub(super) async fn serve_on<M: Messages>(&self, conn: &mut M) -> Result<()> {
+        let mut tasks = JoinSet::new();
+        let result = self.answer(conn, &mut tasks).await;
+        // nobody is left to take their responses
+        tasks.abort_all();
+        result
+    }
+
+    async fn answer<M: Messages>(
+        &self,
+        conn: &mut M,
+        tasks: &mut JoinSet<Result<Vec<u8>>>,
+    ) -> Result<()> {
+        // the task of each request still running, and the request each task answers
+        let mut running: HashMap<u64, AbortHandle> = HashMap::new();
+        let mut requests: HashMap<Id, u64> = HashMap::new();
+        loop {
+            // responses are sent from the arms' bodies, which select! does not cancel
+            tokio::select! {
+                message = conn.recv_msg() => match decode::<Frame>(&message?)? {
+                    Frame::Request { id, method, payload } => {
+                        let Some(handler) = self.handlers.get(&method).cloned() else {
+                            let response = Frame::Response {
+                                id,
+                                result: Err(format!("no handler for method {}", method)),
+                            };
+                            conn.send_msg(&encode(&response)?).await?;
+                            continue;
+                        };
+                        let task = tasks.spawn(handler(payload));
+                        requests.insert(task.id(), id);
+                        running.insert(id, task);
+                    }
+                    Frame::Cancel { id } => {
+                        if let Some(task) = running.remove(&id) {
+                            task.abort();
+                        }
+                    }
+                    Frame::Close => return Ok(()),
+                    Frame::Response { id, .. } => {
+                        return Err(TransportErrors::OpsFailed(
+                            "serve".to_string(),
+                            format!("unexpected response to request {}", id),
+                        ))
+                    }
+                },
+                Some(joined) = tasks.join_next_with_id() => {
+                    let (task, result) = match joined {
+                        Ok((task, result)) => (task, result.map_err(|e| e.to_string())),
+                        // aborted by a Cancel, which already took it out of `running`
+                        Err(e) if e.is_cancelled() => {
+                            requests.remove(&e.id());
+                            continue;
+                        }
+                        Err(e) => (e.id(), Err(e.to_string())),
+                    };
+                    let Some(id) = requests.remove(&task) else {
+                        continue;
+                    };
+                    // a request cancelled after its handler finished gets no response either
+                    if running.remove(&id).is_none() {
+                        continue;
+                    }
+                    conn.send_msg(&encode(&Frame::Response { id, result })?).await?;
+                }
+            }
+        }
+    }
+}
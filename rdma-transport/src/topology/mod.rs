@@ -0,0 +1,381 @@
+use std::{
+    ffi::CStr,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libc::{sockaddr_in, sockaddr_in6, AF_INET, AF_INET6};
+
+use crate::{cuda::cuda_device_pci_bus_id, Result, TransportErrors};
+
+/// An RDMA device and where it sits on the PCI bus.
+#[derive(Debug, Clone)]
+pub struct Nic {
+    pub name: String,
+    /// The device's resolved sysfs path, one component per bridge from the root complex down.
+    pub pci_path: PathBuf,
+    pub numa_node: Option<i32>,
+    /// The network interfaces on the device, for RoCE and IPoIB addresses.
+    pub netdevs: Vec<String>,
+}
+
+impl Nic {
+    /// The addresses configured on the device's network interfaces, IPv4 first. IPv6
+    /// link-local addresses are left out, rdma_cm cannot bind them without a scope.
+    pub fn local_addrs(&self) -> Result<Vec<IpAddr>> {
+        let mut addrs = interface_addrs()?
+            .into_iter()
+            .filter(|(ifname, addr)| {
+                self.netdevs.contains(ifname)
+                    && !addr.is_loopback()
+                    && !matches!(addr, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80)
+            })
+            .map(|(_, addr)| addr)
+            .collect::<Vec<_>>();
+        addrs.sort_by_key(|addr| addr.is_ipv6());
+        Ok(addrs)
+    }
+}
+
+/// The RDMA devices of a host as sysfs describes them.
+///
+/// [`read_from`](Self::read_from) takes the sysfs mount point, so the selection logic can run
+/// against a copy of another host's tree.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    root: PathBuf,
+    nics: Vec<Nic>,
+}
+
+impl Topology {
+    pub fn read() -> Result<Topology> {
+        Self::read_from("/sys")
+    }
+
+    pub fn read_from(root: impl AsRef<Path>) -> Result<Topology> {
+        let root = root.as_ref().to_path_buf();
+        let mut nics = Vec::new();
+        for entry in fs::read_dir(root.join("class/infiniband"))? {
+            let entry = entry?;
+            let device = entry.path().join("device");
+            // a device going away, or one not on PCI, is left out rather than failing the scan
+            let Ok(pci_path) = fs::canonicalize(&device) else {
+                continue;
+            };
+            let netdevs = match fs::read_dir(device.join("net")) {
+                Ok(dir) => dir
+                    .filter_map(|netdev| netdev.ok())
+                    .map(|netdev| netdev.file_name().to_string_lossy().into_owned())
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            nics.push(Nic {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                pci_path,
+                numa_node: numa_node(&device),
+                netdevs,
+            });
+        }
+        nics.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Topology { root, nics })
+    }
+
+    pub fn nics(&self) -> &[Nic] {
+        &self.nics
+    }
+
+    pub fn nic(&self, name: &str) -> Option<&Nic> {
+        self.nics.iter().find(|nic| nic.name == name)
+    }
+
+    /// The NIC with the fewest PCI hops to the device at `bus_id`, preferring one on the same
+    /// NUMA node when two are as close.
+    pub fn closest_to(&self, bus_id: &str) -> Result<&Nic> {
+        let device = self
+            .root
+            .join("bus/pci/devices")
+            .join(normalize_bus_id(bus_id)?);
+        let pci_path = fs::canonicalize(&device)?;
+        let numa = numa_node(&device);
+        self.nics
+            .iter()
+            .min_by_key(|nic| {
+                (
+                    pci_distance(&pci_path, &nic.pci_path),
+                    numa.is_none() || nic.numa_node != numa,
+                )
+            })
+            .ok_or_else(|| {
+                TransportErrors::OpsFailed(
+                    "closest_to".to_string(),
+                    "no RDMA devices in sysfs".to_string(),
+                )
+            })
+    }
+
+    /// The NIC for `gpu_ordinal`: `device_override` if given, the closest to the GPU otherwise.
+    pub fn nic_for_gpu(&self, gpu_ordinal: i32, device_override: Option<&str>) -> Result<&Nic> {
+        match device_override {
+            Some(name) => self.nic(name).ok_or_else(|| {
+                TransportErrors::OpsFailed(
+                    "nic_for_gpu".to_string(),
+                    format!("no RDMA device named {}", name),
+                )
+            }),
+            None => self.closest_to(&cuda_device_pci_bus_id(gpu_ordinal)?),
+        }
+    }
+}
+
+/// The RDMA device and local address to use for `gpu_ordinal`, picked from the NIC closest to
+/// the GPU unless `device_override` names one.
+pub fn select_for_gpu(gpu_ordinal: i32, device_override: Option<&str>) -> Result<(Nic, IpAddr)> {
+    let topology = Topology::read()?;
+    let nic = topology.nic_for_gpu(gpu_ordinal, device_override)?;
+    let addr = nic.local_addrs()?.into_iter().next().ok_or_else(|| {
+        TransportErrors::OpsFailed(
+            "select_for_gpu".to_string(),
+            format!("{} has no address configured", nic.name),
+        )
+    })?;
+    Ok((nic.clone(), addr))
+}
+
+// sysfs names PCI devices `dddd:bb:dd.f` in lower case; the driver may print an 8 digit
+// domain and upper case
+fn normalize_bus_id(bus_id: &str) -> Result<String> {
+    let invalid = || {
+        TransportErrors::OpsFailed(
+            "normalize_bus_id".to_string(),
+            format!("invalid PCI bus id {}", bus_id),
+        )
+    };
+    let (domain, rest) = bus_id.trim().split_once(':').ok_or_else(invalid)?;
+    let domain = u32::from_str_radix(domain, 16).map_err(|_| invalid())?;
+    Ok(format!("{:04x}:{}", domain, rest.to_ascii_lowercase()))
+}
+
+fn numa_node(device: &Path) -> Option<i32> {
+    let node = fs::read_to_string(device.join("numa_node")).ok()?;
+    // -1 when the platform does not say
+    node.trim().parse().ok().filter(|&node: &i32| node >= 0)
+}
+
+// bridges between the two devices and their closest common ancestor; devices under different
+// root complexes only meet at the top of the tree and come out farthest apart
+fn pci_distance(a: &Path, b: &Path) -> usize {
+    let common = a
+        .components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    a.components().count() + b.components().count() - 2 * common
+}
+
+fn interface_addrs() -> Result<Vec<(String, IpAddr)>> {
+    let mut ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut addrs = Vec::new();
+    let mut ifa = ifaddrs;
+    while let Some(entry) = unsafe { ifa.as_ref() } {
+        ifa = entry.ifa_next;
+        let Some(sockaddr) = (unsafe { entry.ifa_addr.as_ref() }) else {
+            continue;
+        };
+        let addr = match sockaddr.sa_family as i32 {
+            AF_INET => {
+                let sin = unsafe { &*(entry.ifa_addr as *const sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            AF_INET6 => {
+                let sin6 = unsafe { &*(entry.ifa_addr as *const sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        addrs.push((name.to_string_lossy().into_owned(), addr));
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::{normalize_bus_id, pci_distance, Topology};
+
+    // a PCI device under `devices/`, with its bus id linked from `bus/pci/devices`
+    fn pci_device(root: &Path, path: &str, numa_node: i32) {
+        let device = root.join("devices").join(path);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("numa_node"), format!("{}\n", numa_node)).unwrap();
+        let bus_id = path.rsplit('/').next().unwrap();
+        fs::create_dir_all(root.join("bus/pci/devices")).unwrap();
+        symlink(&device, root.join("bus/pci/devices").join(bus_id)).unwrap();
+    }
+
+    fn nic(root: &Path, name: &str, path: &str, numa_node: i32, netdevs: &[&str]) {
+        pci_device(root, path, numa_node);
+        let device = root.join("devices").join(path);
+        for netdev in netdevs {
+            fs::create_dir_all(device.join("net").join(netdev)).unwrap();
+        }
+        let class = root.join("class/infiniband").join(name);
+        fs::create_dir_all(&class).unwrap();
+        symlink(&device, class.join("device")).unwrap();
+    }
+
+    // two root complexes: a GPU and mlx5_0 behind one switch, mlx5_1 and mlx5_2 on the other
+    // root, equally far from the GPU there
+    fn sysfs() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        pci_device(root, "pci0000:00/0000:00:01.0/0000:01:00.0", 0);
+        nic(
+            root,
+            "mlx5_0",
+            "pci0000:00/0000:00:01.0/0000:02:00.0",
+            0,
+            &["ib0"],
+        );
+        pci_device(root, "pci0000:80/0000:80:01.0/0000:81:00.0", 1);
+        nic(
+            root,
+            "mlx5_1",
+            "pci0000:80/0000:80:02.0/0000:82:00.0",
+            0,
+            &[],
+        );
+        nic(
+            root,
+            "mlx5_2",
+            "pci0000:80/0000:80:03.0/0000:83:00.0",
+            1,
+            &["eth2", "eth3"],
+        );
+        dir
+    }
+
+    #[test]
+    fn read_from_lists_devices_by_name() {
+        let dir = sysfs();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        let names: Vec<_> = topology
+            .nics()
+            .iter()
+            .map(|nic| nic.name.as_str())
+            .collect();
+        assert_eq!(names, ["mlx5_0", "mlx5_1", "mlx5_2"]);
+
+        let nic = topology.nic("mlx5_2").unwrap();
+        let expected = dir
+            .path()
+            .join("devices/pci0000:80/0000:80:03.0/0000:83:00.0");
+        assert_eq!(nic.pci_path, fs::canonicalize(expected).unwrap());
+        assert_eq!(nic.numa_node, Some(1));
+        let mut netdevs = nic.netdevs.clone();
+        netdevs.sort();
+        assert_eq!(netdevs, ["eth2", "eth3"]);
+        assert!(topology.nic("mlx5_1").unwrap().netdevs.is_empty());
+    }
+
+    #[test]
+    fn read_from_skips_a_device_that_does_not_resolve() {
+        let dir = sysfs();
+        let class = dir.path().join("class/infiniband/mlx5_3");
+        fs::create_dir_all(&class).unwrap();
+        symlink(dir.path().join("devices/gone"), class.join("device")).unwrap();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert_eq!(topology.nics().len(), 3);
+        assert!(topology.nic("mlx5_3").is_none());
+    }
+
+    #[test]
+    fn read_from_leaves_out_an_unknown_numa_node() {
+        let dir = sysfs();
+        let device = dir
+            .path()
+            .join("devices/pci0000:00/0000:00:01.0/0000:02:00.0");
+        fs::write(device.join("numa_node"), "-1\n").unwrap();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert_eq!(topology.nic("mlx5_0").unwrap().numa_node, None);
+    }
+
+    #[test]
+    fn closest_to_picks_the_device_behind_the_same_switch() {
+        let dir = sysfs();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert_eq!(topology.closest_to("0000:01:00.0").unwrap().name, "mlx5_0");
+        // as the CUDA driver prints it
+        assert_eq!(
+            topology.closest_to("00000000:01:00.0").unwrap().name,
+            "mlx5_0"
+        );
+    }
+
+    #[test]
+    fn closest_to_prefers_the_same_numa_node_between_equals() {
+        let dir = sysfs();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert_eq!(topology.closest_to("0000:81:00.0").unwrap().name, "mlx5_2");
+    }
+
+    #[test]
+    fn closest_to_fails_without_devices_or_for_an_unknown_bus_id() {
+        let dir = sysfs();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert!(topology.closest_to("0000:42:00.0").is_err());
+
+        let empty = TempDir::new().unwrap();
+        pci_device(empty.path(), "pci0000:00/0000:00:01.0/0000:01:00.0", 0);
+        fs::create_dir_all(empty.path().join("class/infiniband")).unwrap();
+        let topology = Topology::read_from(empty.path()).unwrap();
+        assert!(topology.nics().is_empty());
+        assert!(topology.closest_to("0000:01:00.0").is_err());
+    }
+
+    #[test]
+    fn nic_for_gpu_takes_the_override_by_name() {
+        let dir = sysfs();
+        let topology = Topology::read_from(dir.path()).unwrap();
+        assert_eq!(
+            topology.nic_for_gpu(0, Some("mlx5_1")).unwrap().name,
+            "mlx5_1"
+        );
+        assert!(topology.nic_for_gpu(0, Some("mlx5_9")).is_err());
+    }
+
+    #[test]
+    fn normalize_bus_id_pads_the_domain_and_lowers_the_case() {
+        assert_eq!(
+            normalize_bus_id("00000000:1A:00.0").unwrap(),
+            "0000:1a:00.0"
+        );
+        assert_eq!(normalize_bus_id("0000:1a:00.0").unwrap(), "0000:1a:00.0");
+        assert_eq!(normalize_bus_id("1:C3:00.1\n").unwrap(), "0001:c3:00.1");
+        assert!(normalize_bus_id("1a00.0").is_err());
+        assert!(normalize_bus_id("zz:1a:00.0").is_err());
+    }
+
+    #[test]
+    fn pci_distance_counts_the_bridges_in_between() {
+        let gpu = Path::new("/sys/devices/pci0000:00/0000:00:01.0/0000:01:00.0");
+        let sibling = Path::new("/sys/devices/pci0000:00/0000:00:01.0/0000:02:00.0");
+        let cousin = Path::new("/sys/devices/pci0000:00/0000:00:02.0/0000:03:00.0");
+        let remote = Path::new("/sys/devices/pci0000:80/0000:80:01.0/0000:81:00.0");
+        assert_eq!(pci_distance(gpu, gpu), 0);
+        assert_eq!(pci_distance(gpu, sibling), 2);
+        assert_eq!(pci_distance(gpu, cousin), 4);
+        assert_eq!(pci_distance(gpu, remote), 6);
+        assert_eq!(pci_distance(remote, gpu), 6);
+    }
+}
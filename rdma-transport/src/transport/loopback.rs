@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    future::{self, Ready},
+    net::SocketAddr,
+    ptr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{
+    rdma::{Connection, Connections, Notification},
+    GPUMemBuffer, Result, TransportErrors,
+};
+
+use super::{coalesce, find_local_buffer, find_remote_buffer, Transport};
+
+// In-process stand-in for the rdma_cm address space: bind address -> pending connections.
+static LISTENERS: OnceLock<Mutex<HashMap<SocketAddr, UnboundedSender<LoopbackIncoming>>>> =
+    OnceLock::new();
+
+fn listeners() -> &'static Mutex<HashMap<SocketAddr, UnboundedSender<LoopbackIncoming>>> {
+    LISTENERS.get_or_init(Default::default)
+}
+
+// The half of a connection that is visible to the peer: the memory it may access by rkey
+// and the queue its notifications land in.
+struct Endpoint {
+    buffers: HashMap<u32, GPUMemBuffer>,
+    notifications: UnboundedSender<Vec<u8>>,
+}
+
+impl Endpoint {
+    fn new(gpu_buffers: &[GPUMemBuffer]) -> (Endpoint, UnboundedReceiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let buffers = gpu_buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| (i as u32 + 1, *buffer))
+            .collect();
+        let endpoint = Endpoint {
+            buffers,
+            notifications: tx,
+        };
+        (endpoint, rx)
+    }
+
+    fn local_buffers(&self) -> HashMap<u64, (u32, GPUMemBuffer)> {
+        self.buffers
+            .iter()
+            .map(|(rkey, buffer)| (buffer.get_base_ptr(), (*rkey, *buffer)))
+            .collect()
+    }
+
+    fn connections(&self) -> Connections {
+        let mut conns = Connections::default();
+        for (rkey, buffer) in self.buffers.iter() {
+            conns.add(Connection::new(
+                buffer.get_base_ptr(),
+                buffer.get_size() as u64,
+                *rkey,
+            ));
+        }
+        conns
+    }
+
+    fn resolve(&self, rkey: u32, addr: u64, size: u32) -> Result<u64> {
+        let buffer = self.buffers.get(&rkey).ok_or(TransportErrors::OpsFailed(
+            "loopback".to_string(),
+            format!("invalid rkey {}", rkey),
+        ))?;
+        let end = buffer.get_base_ptr() + buffer.get_size() as u64;
+        // the peer picks `addr`, so its end must not wrap round
+        if addr < buffer.get_base_ptr()
+            || addr.checked_add(size as u64).is_none_or(|last| last > end)
+        {
+            return Err(TransportErrors::OpsFailed(
+                "loopback".to_string(),
+                format!("{:#x}+{} out of range for rkey {}", addr, size, rkey),
+            ));
+        }
+        Ok(addr)
+    }
+}
+
+pub struct LoopbackListener {
+    bind_addr: SocketAddr,
+    incoming: UnboundedReceiver<LoopbackIncoming>,
+}
+
+impl Drop for LoopbackListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = listeners().lock() {
+            listeners.remove(&self.bind_addr);
+        }
+    }
+}
+
+pub struct LoopbackIncoming {
+    client: Arc<Endpoint>,
+    reply: oneshot::Sender<(Arc<Endpoint>, Vec<u8>)>,
+}
+
+/// Pure-software transport between two endpoints of the same process.
+///
+/// Buffers are plain host memory and "one-sided" operations are `memcpy`s checked against
+/// the peer's registered ranges, so the handshake and notification flow can run without a NIC.
+pub struct LoopbackTransport {
+    is_client: bool,
+    peer: Arc<Endpoint>,
+    local_buffers: HashMap<u64, (u32, GPUMemBuffer)>,
+    remote_buffers: HashMap<u64, Connection>,
+    notifications: UnboundedReceiver<Vec<u8>>,
+}
+
+impl LoopbackTransport {
+    fn copy(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+        write: bool,
+    ) -> Result<()> {
+        find_local_buffer(&mut self.local_buffers, local_buffer_addr, size)?;
+        let conn = find_remote_buffer(&self.remote_buffers, remote_buffer_addr, size)?;
+        let remote_buffer_addr = self
+            .peer
+            .resolve(conn.get_mr_rkey(), remote_buffer_addr, size)?;
+
+        let (src, dst) = if write {
+            (local_buffer_addr, remote_buffer_addr)
+        } else {
+            (remote_buffer_addr, local_buffer_addr)
+        };
+        unsafe { ptr::copy(src as *const u8, dst as *mut u8, size as usize) };
+        Ok(())
+    }
+}
+
+impl Transport for LoopbackTransport {
+    type Options = ();
+    type Listener = LoopbackListener;
+    type Incoming = LoopbackIncoming;
+    type Transfer = Ready<Result<()>>;
+
+    fn bind(bind_addr: &SocketAddr, _options: ()) -> Result<LoopbackListener> {
+        let mut listeners = listeners()
+            .lock()
+            .map_err(|e| TransportErrors::OpsFailed("bind".to_string(), e.to_string()))?;
+        if listeners.contains_key(bind_addr) {
+            return Err(TransportErrors::OpsFailed(
+                "bind".to_string(),
+                format!("address {} already in use", bind_addr),
+            ));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        listeners.insert(*bind_addr, tx);
+        Ok(LoopbackListener {
+            bind_addr: *bind_addr,
+            incoming: rx,
+        })
+    }
+
+    async fn listen(listener: &mut LoopbackListener) -> Result<LoopbackIncoming> {
+        listener
+            .incoming
+            .recv()
+            .await
+            .ok_or(TransportErrors::OpsFailed(
+                "listen".to_string(),
+                "listener closed".to_string(),
+            ))
+    }
+
+    async fn accept(
+        incoming: LoopbackIncoming,
+        _gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<Self> {
+        let (endpoint, notifications) = Endpoint::new(&gpu_buffers);
+        let local_buffers = endpoint.local_buffers();
+        let conns = bincode::serialize(&endpoint.connections())
+            .map_err(|e| TransportErrors::OpsFailed("accept".to_string(), e.to_string()))?;
+
+        incoming
+            .reply
+            .send((Arc::new(endpoint), conns))
+            .map_err(|_| {
+                TransportErrors::OpsFailed("accept".to_string(), "client went away".to_string())
+            })?;
+
+        Ok(LoopbackTransport {
+            is_client: false,
+            peer: incoming.client,
+            local_buffers,
+            remote_buffers: HashMap::new(),
+            notifications,
+        })
+    }
+
+    async fn connect(
+        server_addr: SocketAddr,
+        _gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+        _options: (),
+    ) -> Result<Self> {
+        let server = listeners()
+            .lock()
+            .map_err(|e| TransportErrors::OpsFailed("connect".to_string(), e.to_string()))?
+            .get(&server_addr)
+            .cloned()
+            .ok_or(TransportErrors::OpsFailed(
+                "connect".to_string(),
+                format!("connection refused by {}", server_addr),
+            ))?;
+
+        let (endpoint, notifications) = Endpoint::new(&gpu_buffers);
+        let local_buffers = endpoint.local_buffers();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        server
+            .send(LoopbackIncoming {
+                client: Arc::new(endpoint),
+                reply: reply_tx,
+            })
+            .map_err(|_| {
+                TransportErrors::OpsFailed("connect".to_string(), "listener closed".to_string())
+            })?;
+
+        let (peer, conns) = reply_rx.await.map_err(|_| {
+            TransportErrors::OpsFailed("connect".to_string(), "connection rejected".to_string())
+        })?;
+        let server_conns = bincode::deserialize::<Connections>(&conns)
+            .map_err(|e| TransportErrors::OpsFailed("connect".to_string(), e.to_string()))?;
+        let remote_buffers = server_conns
+            .iter()
+            .map(|conn| (conn.get_base_ptr(), conn.to_owned()))
+            .collect();
+
+        Ok(LoopbackTransport {
+            is_client: true,
+            peer,
+            local_buffers,
+            remote_buffers,
+            notifications,
+        })
+    }
+
+    fn remote_buffers(&self) -> &HashMap<u64, Connection> {
+        &self.remote_buffers
+    }
+
+    // copies complete synchronously, so a posted transfer is already resolved
+    async fn post_write_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Self::Transfer> {
+        for (local_buffer_addr, remote_buffer_addr, size) in coalesce(blocks) {
+            self.copy(local_buffer_addr, remote_buffer_addr, size, true)?;
+        }
+        Ok(future::ready(Ok(())))
+    }
+
+    async fn post_read_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Self::Transfer> {
+        for (local_buffer_addr, remote_buffer_addr, size) in coalesce(blocks) {
+            self.copy(local_buffer_addr, remote_buffer_addr, size, false)?;
+        }
+        Ok(future::ready(Ok(())))
+    }
+
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        let data = bincode::serialize(notification)
+            .map_err(|e| TransportErrors::OpsFailed("notify".to_string(), e.to_string()))?;
+        self.peer.notifications.send(data).map_err(|_| {
+            TransportErrors::OpsFailed("notify".to_string(), "peer disconnected".to_string())
+        })
+    }
+
+    async fn recv_notification(&mut self) -> Result<Notification> {
+        let data = self
+            .notifications
+            .recv()
+            .await
+            .ok_or(TransportErrors::OpsFailed(
+                "recv_notification".to_string(),
+                "peer disconnected".to_string(),
+            ))?;
+        bincode::deserialize::<Notification>(&data)
+            .map_err(|e| TransportErrors::OpsFailed("recv_notification".to_string(), e.to_string()))
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if self.is_client {
+            self.notify(&Notification::complete()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(memory: &mut [u8]) -> GPUMemBuffer {
+        GPUMemBuffer::new(memory.as_mut_ptr() as u64, memory.len())
+    }
+
+    // every test listens on a port of its own, the listeners are process-wide
+    async fn pair(
+        port: u16,
+        server_buffers: Vec<GPUMemBuffer>,
+        client_buffers: Vec<GPUMemBuffer>,
+    ) -> (LoopbackTransport, LoopbackTransport) {
+        let bind_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut listener = LoopbackTransport::bind(&bind_addr, ()).unwrap();
+        let server = tokio::spawn(async move {
+            let incoming = LoopbackTransport::listen(&mut listener).await.unwrap();
+            LoopbackTransport::accept(incoming, 0, server_buffers)
+                .await
+                .unwrap()
+        });
+        let client = LoopbackTransport::connect(bind_addr, 0, client_buffers, ())
+            .await
+            .unwrap();
+        (server.await.unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn handshake_exchanges_buffers() {
+        let mut first = vec![0u8; 4096];
+        let mut second = vec![0u8; 1024];
+        let buffers = vec![buffer(&mut first), buffer(&mut second)];
+        let (_server, client) = pair(24001, buffers.clone(), vec![]).await;
+
+        let remote = client.remote_buffers();
+        assert_eq!(remote.len(), 2);
+        for buffer in buffers {
+            let conn = &remote[&buffer.get_base_ptr()];
+            assert_eq!(conn.get_size(), buffer.get_size() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_and_read_land_in_the_peer_buffer() {
+        let mut server_memory = vec![0u8; 4096];
+        let mut client_memory = vec![7u8; 4096];
+        let (server_buffer, client_buffer) =
+            (buffer(&mut server_memory), buffer(&mut client_memory));
+        let (_server, mut client) = pair(24002, vec![server_buffer], vec![client_buffer]).await;
+
+        let (local, remote) = (client_buffer.get_base_ptr(), server_buffer.get_base_ptr());
+        client.write(local, remote + 1024, 512).await.unwrap();
+        client_memory[..512].fill(0);
+        client.read(local, remote + 1024, 512).await.unwrap();
+
+        assert!(server_memory[1024..1536].iter().all(|&b| b == 7));
+        assert!(server_memory[..1024].iter().all(|&b| b == 0));
+        assert!(client_memory[..512].iter().all(|&b| b == 7));
+    }
+
+    #[tokio::test]
+    async fn transfers_past_the_remote_buffer_are_rejected() {
+        let mut server_memory = vec![0u8; 4096];
+        let mut client_memory = vec![0u8; 8192];
+        let (server_buffer, client_buffer) =
+            (buffer(&mut server_memory), buffer(&mut client_memory));
+        let (_server, mut client) = pair(24003, vec![server_buffer], vec![client_buffer]).await;
+
+        let (local, remote) = (client_buffer.get_base_ptr(), server_buffer.get_base_ptr());
+        assert!(client.write(local, remote + 4000, 200).await.is_err());
+        assert!(client.write(local, remote + 8192, 1).await.is_err());
+        assert!(client.read(local, remote - 1, 1).await.is_err());
+        assert!(client.write(local, remote + 3896, 200).await.is_ok());
+    }
+
+    #[test]
+    fn ranges_whose_end_wraps_round_are_not_covered() {
+        let mut memory = vec![0u8; 4096];
+        let base = memory.as_ptr() as u64;
+        let (endpoint, _notifications) = Endpoint::new(&[buffer(&mut memory)]);
+        assert!(endpoint.resolve(1, u64::MAX - 8, 16).is_err());
+        assert!(endpoint.resolve(1, base, 4096).is_ok());
+        assert!(find_local_buffer(&mut endpoint.local_buffers(), u64::MAX - 8, 16).is_err());
+
+        assert!(!Connection::new(base, 4096, 1).covers(u64::MAX - 8, 16));
+        assert!(!Connection::new(u64::MAX - 16, 32, 1).covers(u64::MAX - 8, 4));
+        assert!(Connection::new(base, 4096, 1).covers(base, 4096));
+    }
+
+    #[tokio::test]
+    async fn notifications_arrive_in_order_then_complete() {
+        let (mut server, mut client) = pair(24004, vec![], vec![]).await;
+
+        for i in 0..4u8 {
+            client
+                .notify(&Notification {
+                    done: 0,
+                    req_id: Some(vec![i]),
+                })
+                .await
+                .unwrap();
+        }
+        client.disconnect().await.unwrap();
+
+        for i in 0..4u8 {
+            let notification = server.recv_notification().await.unwrap();
+            assert_eq!(notification.done, 0);
+            assert_eq!(notification.req_id, Some(vec![i]));
+        }
+        assert_eq!(server.recv_notification().await.unwrap().done, 1);
+    }
+
+    #[tokio::test]
+    async fn connect_without_listener_is_refused() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 24005));
+        assert!(LoopbackTransport::connect(addr, 0, vec![], ())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_twice_fails() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 24006));
+        let _listener = LoopbackTransport::bind(&addr, ()).unwrap();
+        assert!(LoopbackTransport::bind(&addr, ()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+mod loopback;
+mod rdma;
+mod tcp;
+
+use std::{collections::HashMap, future::Future, net::SocketAddr, str::FromStr};
+
+pub use loopback::{LoopbackIncoming, LoopbackListener, LoopbackTransport};
+pub use rdma::{RdmaSrqTransport, RdmaTransport};
+pub use tcp::{TcpTransfer, TcpTransport};
+
+use crate::{
+    rdma::{Connection, Notification},
+    GPUMemBuffer, Result, TransportErrors,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Rdma,
+    /// RDMA with the server's connections sharing one receive queue.
+    RdmaSrq,
+    Tcp,
+    Loopback,
+}
+
+impl FromStr for Backend {
+    type Err = TransportErrors;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rdma" => Ok(Backend::Rdma),
+            "rdma-srq" => Ok(Backend::RdmaSrq),
+            "tcp" => Ok(Backend::Tcp),
+            "loopback" => Ok(Backend::Loopback),
+            _ => Err(TransportErrors::OpsFailed(
+                "backend".to_string(),
+                format!("unknown transport backend: {}", s),
+            )),
+        }
+    }
+}
+
+/// A connected endpoint able to move data between local and remote buffers.
+///
+/// `bind`/`listen`/`accept` mirror `rdma::server_init`/`rdma::listen`/`rdma::accept`,
+/// so a server can hand every incoming connection to its own task before the handshake.
+pub trait Transport: Sized + Send + 'static {
+    /// What a caller can tune about the backend's connections, e.g. `QpOptions` for RDMA.
+    /// The listener keeps what it was bound with for the connections it accepts.
+    type Options: Default + Clone + Send + Sync + 'static;
+    type Listener: Send;
+    type Incoming: Send;
+
+    fn bind(bind_addr: &SocketAddr, options: Self::Options) -> Result<Self::Listener>;
+
+    fn listen(listener: &mut Self::Listener)
+        -> impl Future<Output = Result<Self::Incoming>> + Send;
+
+    fn accept(
+        incoming: Self::Incoming,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> impl Future<Output = Result<Self>> + Send;
+
+    fn connect(
+        server_addr: SocketAddr,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+        options: Self::Options,
+    ) -> impl Future<Output = Result<Self>> + Send;
+
+    fn remote_buffers(&self) -> &HashMap<u64, Connection>;
+
+    /// Resolves once a posted write/read has completed.
+    type Transfer: Future<Output = Result<()>> + Send + 'static;
+
+    /// Starts writing a batch of `(local_buffer_addr, remote_buffer_addr, size)` blocks and
+    /// returns as soon as it is queued, so many transfers can be in flight at once. The
+    /// transfer resolves when every block has landed. Posting may wait for a free slot when
+    /// the queue is full.
+    fn post_write_many(
+        &mut self,
+        blocks: &[(u64, u64, u32)],
+    ) -> impl Future<Output = Result<Self::Transfer>> + Send;
+
+    fn post_read_many(
+        &mut self,
+        blocks: &[(u64, u64, u32)],
+    ) -> impl Future<Output = Result<Self::Transfer>> + Send;
+
+    fn post_write(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<Self::Transfer>> + Send {
+        async move {
+            self.post_write_many(&[(local_buffer_addr, remote_buffer_addr, size)])
+                .await
+        }
+    }
+
+    fn post_read(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<Self::Transfer>> + Send {
+        async move {
+            self.post_read_many(&[(local_buffer_addr, remote_buffer_addr, size)])
+                .await
+        }
+    }
+
+    fn write(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.post_write(local_buffer_addr, remote_buffer_addr, size)
+                .await?
+                .await
+        }
+    }
+
+    fn read(
+        &mut self,
+        local_buffer_addr: u64,
+        remote_buffer_addr: u64,
+        size: u32,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.post_read(local_buffer_addr, remote_buffer_addr, size)
+                .await?
+                .await
+        }
+    }
+
+    fn notify(&mut self, notification: &Notification) -> impl Future<Output = Result<()>> + Send;
+
+    fn recv_notification(&mut self) -> impl Future<Output = Result<Notification>> + Send;
+
+    fn disconnect(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+// merges neighbouring blocks that are contiguous both locally and remotely
+pub(crate) fn coalesce(blocks: &[(u64, u64, u32)]) -> Vec<(u64, u64, u32)> {
+    let mut merged: Vec<(u64, u64, u32)> = Vec::with_capacity(blocks.len());
+    for &(local_buffer_addr, remote_buffer_addr, size) in blocks.iter().filter(|b| b.2 > 0) {
+        match merged.last_mut() {
+            Some((local, remote, merged_size))
+                if *local + *merged_size as u64 == local_buffer_addr
+                    && *remote + *merged_size as u64 == remote_buffer_addr
+                    && merged_size.checked_add(size).is_some() =>
+            {
+                *merged_size += size
+            }
+            _ => merged.push((local_buffer_addr, remote_buffer_addr, size)),
+        }
+    }
+    merged
+}
+
+pub(crate) fn find_local_buffer<V>(
+    buffers: &mut HashMap<u64, (V, GPUMemBuffer)>,
+    addr: u64,
+    size: u32,
+) -> Result<&mut (V, GPUMemBuffer)> {
+    buffers
+        .values_mut()
+        .find(|(_, buffer)| {
+            addr >= buffer.get_base_ptr()
+                && addr.checked_add(size as u64).is_some_and(|end| {
+                    end <= buffer.get_base_ptr() + buffer.get_size() as u64
+                })
+        })
+        .ok_or(TransportErrors::OpsFailed(
+            "find_local_buffer".to_string(),
+            format!("no registered buffer covers {:#x}+{}", addr, size),
+        ))
+}
+
+pub(crate) fn find_remote_buffer(
+    buffers: &HashMap<u64, Connection>,
+    addr: u64,
+    size: u32,
+) -> Result<&Connection> {
+    buffers
+        .values()
+        .find(|conn| conn.covers(addr, size as u64))
+        .ok_or(TransportErrors::OpsFailed(
+            "find_remote_buffer".to_string(),
+            format!("no remote buffer covers {:#x}+{}", addr, size),
+        ))
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::{
+    rdma::{
+        self, Connection, Notification, QpOptions, RdmaConnection, RdmaDevice, RdmaEndpoint,
+        RdmaListener, SharedIncoming, SharedListener, Transfer,
+    },
+    GPUMemBuffer, Result,
+};
+
+use super::Transport;
+
+pub struct RdmaTransport {
+    conn: RdmaConnection,
+}
+
+impl Transport for RdmaTransport {
+    type Options = QpOptions;
+    type Listener = RdmaListener;
+    type Incoming = RdmaEndpoint;
+    type Transfer = Transfer;
+
+    fn bind(bind_addr: &SocketAddr, options: QpOptions) -> Result<RdmaListener> {
+        // a wildcard address picks no device, so it cannot be bound
+        let device = RdmaDevice::for_local(bind_addr)?;
+        rdma::server_init(bind_addr, &device, &options)
+    }
+
+    async fn listen(listener: &mut RdmaListener) -> Result<RdmaEndpoint> {
+        rdma::listen(listener).await
+    }
+
+    async fn accept(
+        endpoint: RdmaEndpoint,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<Self> {
+        let conn = rdma::accept(endpoint, gpu_ordinal, gpu_buffers).await?;
+        Ok(RdmaTransport { conn })
+    }
+
+    async fn connect(
+        server_addr: SocketAddr,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+        options: QpOptions,
+    ) -> Result<Self> {
+        let device = match options.local_addr {
+            Some(addr) => RdmaDevice::for_local(&SocketAddr::new(addr, 0))?,
+            None => RdmaDevice::for_peer(&server_addr)?,
+        };
+        let endpoint = rdma::client_init(server_addr, &device, &options)?;
+        let conn = rdma::connect(endpoint, gpu_ordinal, gpu_buffers).await?;
+        Ok(RdmaTransport { conn })
+    }
+
+    fn remote_buffers(&self) -> &HashMap<u64, Connection> {
+        self.conn.remote_buffers()
+    }
+
+    async fn post_write_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        self.conn.write_many(blocks).await
+    }
+
+    async fn post_read_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        self.conn.read_many(blocks).await
+    }
+
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        self.conn.notify(notification).await
+    }
+
+    async fn recv_notification(&mut self) -> Result<Notification> {
+        self.conn.recv_notification().await
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.conn.close().await
+    }
+}
+
+// 256 connections at the default 16 credits each
+const SHARED_SRQ_DEPTH: u32 = 4096;
+
+/// [`RdmaTransport`] with the server's connections receiving through one shared SRQ and CQ.
+/// Clients connect the same way as to an [`RdmaTransport`] server.
+pub struct RdmaSrqTransport(RdmaTransport);
+
+impl Transport for RdmaSrqTransport {
+    type Options = QpOptions;
+    type Listener = SharedListener;
+    type Incoming = SharedIncoming;
+    type Transfer = Transfer;
+
+    fn bind(bind_addr: &SocketAddr, options: QpOptions) -> Result<SharedListener> {
+        // the SRQ lives on one PD, so the address has to name the device
+        let device = RdmaDevice::for_local(bind_addr)?;
+        SharedListener::bind(bind_addr, &device, &options, SHARED_SRQ_DEPTH)
+    }
+
+    async fn listen(listener: &mut SharedListener) -> Result<SharedIncoming> {
+        listener.listen().await
+    }
+
+    async fn accept(
+        incoming: SharedIncoming,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<Self> {
+        let conn = incoming.accept(gpu_ordinal, gpu_buffers).await?;
+        Ok(RdmaSrqTransport(RdmaTransport { conn }))
+    }
+
+    async fn connect(
+        server_addr: SocketAddr,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+        options: QpOptions,
+    ) -> Result<Self> {
+        RdmaTransport::connect(server_addr, gpu_ordinal, gpu_buffers, options)
+            .await
+            .map(RdmaSrqTransport)
+    }
+
+    fn remote_buffers(&self) -> &HashMap<u64, Connection> {
+        self.0.remote_buffers()
+    }
+
+    async fn post_write_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        self.0.post_write_many(blocks).await
+    }
+
+    async fn post_read_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<Transfer> {
+        self.0.post_read_many(blocks).await
+    }
+
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        self.0.notify(notification).await
+    }
+
+    async fn recv_notification(&mut self) -> Result<Notification> {
+        self.0.recv_notification().await
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.0.disconnect().await
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, Mutex as AsyncMutex,
+    },
+    task::{self, JoinHandle},
+};
+
+use crate::{
+    cuda::{cuda_memcpy, PrimaryCtx},
+    rdma::{Connection, Connections, Notification},
+    GPUMemBuffer, Result, TransportErrors,
+};
+
+use super::{coalesce, find_local_buffer, find_remote_buffer, Transport};
+
+// One-sided operations are shipped to the peer's agent, which applies them to its own
+// registered memory and answers with an `Ack` carrying the read data or the failure.
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Hello(Connections),
+    // (remote_addr, rkey, data) per block
+    Write {
+        id: u64,
+        blocks: Vec<(u64, u32, Vec<u8>)>,
+    },
+    // (remote_addr, rkey, size) per block, answered with the blocks' data back to back
+    Read {
+        id: u64,
+        blocks: Vec<(u64, u32, u32)>,
+    },
+    Ack {
+        id: u64,
+        data: Vec<u8>,
+        error: Option<String>,
+    },
+    Notify(Vec<u8>),
+}
+
+// a read's data is scattered over `dst` by the agent before the waiter is woken
+struct Waiter {
+    dst: Vec<(u64, u32)>,
+    tx: oneshot::Sender<std::result::Result<(), String>>,
+}
+
+// None once the agent has stopped, as nothing would take the acks then
+type Pending = Arc<Mutex<Option<HashMap<u64, Waiter>>>>;
+
+// Frames are read whole into memory, so the peer cannot make us allocate more than this. A
+// batch of writes or reads whose data is larger has to be split by the caller.
+const MAX_FRAME_SIZE: usize = 1 << 30;
+
+// what the `Ack` of a read can carry, leaving room for the rest of the frame
+const MAX_READ_SIZE: usize = MAX_FRAME_SIZE - 64;
+
+async fn write_frame(writer: &AsyncMutex<OwnedWriteHalf>, frame: &Frame) -> Result<()> {
+    let data = bincode::serialize(frame)
+        .map_err(|e| TransportErrors::OpsFailed("write_frame".to_string(), e.to_string()))?;
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(TransportErrors::OpsFailed(
+            "write_frame".to_string(),
+            format!(
+                "frame of {} bytes exceeds the {} byte limit",
+                data.len(),
+                MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut writer = writer.lock().await;
+    writer.write_u32(data.len() as u32).await?;
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> Result<Frame> {
+    let size = reader.read_u32().await? as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(TransportErrors::OpsFailed(
+            "read_frame".to_string(),
+            format!(
+                "frame of {} bytes exceeds the {} byte limit",
+                size, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+    let mut data = vec![0; size];
+    reader.read_exact(&mut data).await?;
+    bincode::deserialize::<Frame>(&data)
+        .map_err(|e| TransportErrors::OpsFailed("read_frame".to_string(), e.to_string()))
+}
+
+// CUDA copies block their thread, so they run off the runtime's
+async fn copy_blocking<T: Send + 'static>(
+    cu_ctx: Arc<PrimaryCtx>,
+    copy: impl FnOnce() -> Result<T> + Send + 'static,
+) -> std::result::Result<T, String> {
+    task::spawn_blocking(move || {
+        cu_ctx.set_current()?;
+        copy()
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+fn local_buffers(gpu_buffers: &[GPUMemBuffer]) -> HashMap<u64, (u32, GPUMemBuffer)> {
+    gpu_buffers
+        .iter()
+        .enumerate()
+        .map(|(i, buffer)| (buffer.get_base_ptr(), (i as u32 + 1, *buffer)))
+        .collect()
+}
+
+struct Agent {
+    reader: OwnedReadHalf,
+    writer: Arc<AsyncMutex<OwnedWriteHalf>>,
+    pending: Pending,
+    notifications: UnboundedSender<Vec<u8>>,
+    buffers: HashMap<u32, GPUMemBuffer>,
+    cu_ctx: Arc<PrimaryCtx>,
+}
+
+impl Agent {
+    fn resolve(&self, rkey: u32, addr: u64, size: u32) -> std::result::Result<u64, String> {
+        let buffer = self
+            .buffers
+            .get(&rkey)
+            .ok_or(format!("invalid rkey {}", rkey))?;
+        let end = buffer.get_base_ptr() + buffer.get_size() as u64;
+        // the peer picks `addr`, so its end must not wrap round
+        if addr < buffer.get_base_ptr()
+            || addr.checked_add(size as u64).is_none_or(|last| last > end)
+        {
+            return Err(format!(
+                "{:#x}+{} out of range for rkey {}",
+                addr, size, rkey
+            ));
+        }
+        Ok(addr)
+    }
+
+    async fn apply_write(
+        &self,
+        blocks: Vec<(u64, u32, Vec<u8>)>,
+    ) -> std::result::Result<(), String> {
+        for (remote_addr, rkey, data) in &blocks {
+            self.resolve(*rkey, *remote_addr, data.len() as u32)?;
+        }
+        copy_blocking(self.cu_ctx.clone(), move || {
+            for (addr, _, data) in blocks {
+                cuda_memcpy(addr, data.as_ptr() as u64, data.len())?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // Every block is checked before anything is allocated, and a read too large for its ack
+    // fails on its own rather than the write of the ack taking the connection down.
+    async fn apply_read(
+        &self,
+        blocks: Vec<(u64, u32, u32)>,
+    ) -> std::result::Result<Vec<u8>, String> {
+        let mut total = 0;
+        for (remote_addr, rkey, size) in &blocks {
+            self.resolve(*rkey, *remote_addr, *size)?;
+            total += *size as usize;
+        }
+        if total > MAX_READ_SIZE {
+            return Err(format!(
+                "read of {} bytes exceeds the {} byte limit",
+                total, MAX_READ_SIZE
+            ));
+        }
+        copy_blocking(self.cu_ctx.clone(), move || {
+            let mut data = vec![0; total];
+            let mut copied = 0;
+            for (addr, _, size) in blocks {
+                cuda_memcpy(data[copied..].as_mut_ptr() as u64, addr, size as usize)?;
+                copied += size as usize;
+            }
+            Ok(data)
+        })
+        .await
+    }
+
+    async fn apply_ack(
+        &self,
+        dst: Vec<(u64, u32)>,
+        data: Vec<u8>,
+    ) -> std::result::Result<(), String> {
+        if data.len() < dst.iter().map(|(_, size)| *size as usize).sum() {
+            return Err("short read ack".to_string());
+        }
+        copy_blocking(self.cu_ctx.clone(), move || {
+            let mut copied = 0;
+            for (local_addr, size) in dst {
+                cuda_memcpy(local_addr, data[copied..].as_ptr() as u64, size as usize)?;
+                copied += size as usize;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    // Once the agent stops, whatever is still waiting for an ack fails with the reason, and so
+    // does every transfer posted after.
+    async fn run(mut self) -> Result<()> {
+        let result = self.serve().await;
+        let reason = match &result {
+            Ok(()) => "the connection was closed".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let waiters = self.pending.lock().unwrap().take();
+        for (_, waiter) in waiters.into_iter().flatten() {
+            let _ = waiter
+                .tx
+                .send(Err(format!("peer disconnected: {}", reason)));
+        }
+        result
+    }
+
+    async fn serve(&mut self) -> Result<()> {
+        loop {
+            match read_frame(&mut self.reader).await? {
+                Frame::Write { id, blocks } => {
+                    let error = self.apply_write(blocks).await.err();
+                    let ack = Frame::Ack {
+                        id,
+                        data: Vec::new(),
+                        error,
+                    };
+                    write_frame(&self.writer, &ack).await?;
+                }
+                Frame::Read { id, blocks } => {
+                    let ack = match self.apply_read(blocks).await {
+                        Ok(data) => Frame::Ack {
+                            id,
+                            data,
+                            error: None,
+                        },
+                        Err(e) => Frame::Ack {
+                            id,
+                            data: Vec::new(),
+                            error: Some(e),
+                        },
+                    };
+                    write_frame(&self.writer, &ack).await?;
+                }
+                Frame::Ack { id, data, error } => {
+                    let waiter = self
+                        .pending
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|waiters| waiters.remove(&id));
+                    if let Some(waiter) = waiter {
+                        let result = match error {
+                            Some(e) => Err(e),
+                            None => self.apply_ack(waiter.dst, data).await,
+                        };
+                        let _ = waiter.tx.send(result);
+                    }
+                }
+                Frame::Notify(data) => {
+                    let _ = self.notifications.send(data);
+                }
+                Frame::Hello(_) => {
+                    return Err(TransportErrors::OpsFailed(
+                        "tcp_agent".to_string(),
+                        "unexpected hello after handshake".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Transport for nodes without RoCE/IB: RDMA semantics emulated over a single `TcpStream`.
+pub struct TcpTransport {
+    is_client: bool,
+    writer: Arc<AsyncMutex<OwnedWriteHalf>>,
+    pending: Pending,
+    next_id: u64,
+    cu_ctx: Arc<PrimaryCtx>,
+    local_buffers: HashMap<u64, (u32, GPUMemBuffer)>,
+    remote_buffers: HashMap<u64, Connection>,
+    notifications: UnboundedReceiver<Vec<u8>>,
+    agent: JoinHandle<Result<()>>,
+}
+
+impl TcpTransport {
+    async fn establish(
+        stream: TcpStream,
+        is_client: bool,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(AsyncMutex::new(writer));
+
+        let local_buffers = local_buffers(&gpu_buffers);
+        let mut conns = Connections::default();
+        for (rkey, buffer) in local_buffers.values() {
+            conns.add(Connection::new(
+                buffer.get_base_ptr(),
+                buffer.get_size() as u64,
+                *rkey,
+            ));
+        }
+        write_frame(&writer, &Frame::Hello(conns)).await?;
+        let remote_buffers = match read_frame(&mut reader).await? {
+            Frame::Hello(conns) => conns
+                .iter()
+                .map(|conn| (conn.get_base_ptr(), conn.to_owned()))
+                .collect(),
+            frame => {
+                return Err(TransportErrors::OpsFailed(
+                    "establish".to_string(),
+                    format!("expect hello, got {:?}", frame),
+                ))
+            }
+        };
+
+        // shared with the agent, released once both are gone
+        let cu_ctx = Arc::new(PrimaryCtx::retain(gpu_ordinal)?);
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let agent = Agent {
+            reader,
+            writer: writer.clone(),
+            pending: pending.clone(),
+            notifications: tx,
+            buffers: local_buffers
+                .values()
+                .map(|(rkey, buffer)| (*rkey, *buffer))
+                .collect(),
+            cu_ctx: cu_ctx.clone(),
+        };
+
+        Ok(TcpTransport {
+            is_client,
+            writer,
+            pending,
+            next_id: 0,
+            cu_ctx,
+            local_buffers,
+            remote_buffers,
+            notifications: rx,
+            agent: tokio::spawn(agent.run()),
+        })
+    }
+
+    async fn post(
+        &mut self,
+        dst: Vec<(u64, u32)>,
+        frame: impl FnOnce(u64) -> Frame,
+    ) -> Result<TcpTransfer> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.insert(id, Waiter { dst, tx }),
+            None => {
+                return Err(TransportErrors::OpsFailed(
+                    "post".to_string(),
+                    "peer disconnected".to_string(),
+                ))
+            }
+        };
+
+        if let Err(e) = write_frame(&self.writer, &frame(id)).await {
+            if let Some(waiters) = self.pending.lock().unwrap().as_mut() {
+                waiters.remove(&id);
+            }
+            return Err(e);
+        }
+        Ok(TcpTransfer { rx })
+    }
+}
+
+/// A write/read shipped to the peer; resolves when its `Ack` has been handled.
+pub struct TcpTransfer {
+    rx: oneshot::Receiver<std::result::Result<(), String>>,
+}
+
+impl Future for TcpTransfer {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|result| match result {
+            Ok(result) => result.map_err(|e| TransportErrors::OpsFailed("transfer".to_string(), e)),
+            Err(_) => Err(TransportErrors::OpsFailed(
+                "transfer".to_string(),
+                "peer disconnected".to_string(),
+            )),
+        })
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.agent.abort();
+    }
+}
+
+impl Transport for TcpTransport {
+    type Options = ();
+    type Listener = TcpListener;
+    type Incoming = TcpStream;
+    type Transfer = TcpTransfer;
+
+    fn bind(bind_addr: &SocketAddr, _options: ()) -> Result<TcpListener> {
+        let listener = std::net::TcpListener::bind(bind_addr)?;
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener).map_err(Into::into)
+    }
+
+    async fn listen(listener: &mut TcpListener) -> Result<TcpStream> {
+        let (stream, _) = listener.accept().await?;
+        Ok(stream)
+    }
+
+    async fn accept(
+        incoming: TcpStream,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+    ) -> Result<Self> {
+        TcpTransport::establish(incoming, false, gpu_ordinal, gpu_buffers).await
+    }
+
+    async fn connect(
+        server_addr: SocketAddr,
+        gpu_ordinal: i32,
+        gpu_buffers: Vec<GPUMemBuffer>,
+        _options: (),
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(server_addr).await?;
+        TcpTransport::establish(stream, true, gpu_ordinal, gpu_buffers).await
+    }
+
+    fn remote_buffers(&self) -> &HashMap<u64, Connection> {
+        &self.remote_buffers
+    }
+
+    async fn post_write_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<TcpTransfer> {
+        let mut sources = Vec::with_capacity(blocks.len());
+        for (local_buffer_addr, remote_buffer_addr, size) in coalesce(blocks) {
+            find_local_buffer(&mut self.local_buffers, local_buffer_addr, size)?;
+            let rkey =
+                find_remote_buffer(&self.remote_buffers, remote_buffer_addr, size)?.get_mr_rkey();
+            sources.push((local_buffer_addr, remote_buffer_addr, rkey, size));
+        }
+        let frame_blocks = copy_blocking(self.cu_ctx.clone(), move || {
+            let mut frame_blocks = Vec::with_capacity(sources.len());
+            for (local_buffer_addr, remote_buffer_addr, rkey, size) in sources {
+                let mut data = vec![0; size as usize];
+                cuda_memcpy(data.as_mut_ptr() as u64, local_buffer_addr, data.len())?;
+                frame_blocks.push((remote_buffer_addr, rkey, data));
+            }
+            Ok(frame_blocks)
+        })
+        .await
+        .map_err(|e| TransportErrors::OpsFailed("post_write_many".to_string(), e))?;
+
+        self.post(Vec::new(), |id| Frame::Write {
+            id,
+            blocks: frame_blocks,
+        })
+        .await
+    }
+
+    async fn post_read_many(&mut self, blocks: &[(u64, u64, u32)]) -> Result<TcpTransfer> {
+        let mut frame_blocks = Vec::with_capacity(blocks.len());
+        let mut dst = Vec::with_capacity(blocks.len());
+        for (local_buffer_addr, remote_buffer_addr, size) in coalesce(blocks) {
+            find_local_buffer(&mut self.local_buffers, local_buffer_addr, size)?;
+            let rkey =
+                find_remote_buffer(&self.remote_buffers, remote_buffer_addr, size)?.get_mr_rkey();
+            frame_blocks.push((remote_buffer_addr, rkey, size));
+            dst.push((local_buffer_addr, size));
+        }
+        // the peer would refuse it anyway; failing here keeps it from failing anything else
+        let total: usize = dst.iter().map(|(_, size)| *size as usize).sum();
+        if total > MAX_READ_SIZE {
+            return Err(TransportErrors::OpsFailed(
+                "post_read_many".to_string(),
+                format!(
+                    "read of {} bytes exceeds the {} byte limit",
+                    total, MAX_READ_SIZE
+                ),
+            ));
+        }
+
+        self.post(dst, |id| Frame::Read {
+            id,
+            blocks: frame_blocks,
+        })
+        .await
+    }
+
+    async fn notify(&mut self, notification: &Notification) -> Result<()> {
+        let data = bincode::serialize(notification)
+            .map_err(|e| TransportErrors::OpsFailed("notify".to_string(), e.to_string()))?;
+        write_frame(&self.writer, &Frame::Notify(data)).await
+    }
+
+    async fn recv_notification(&mut self) -> Result<Notification> {
+        let data = self
+            .notifications
+            .recv()
+            .await
+            .ok_or(TransportErrors::OpsFailed(
+                "recv_notification".to_string(),
+                "peer disconnected".to_string(),
+            ))?;
+        bincode::deserialize::<Notification>(&data)
+            .map_err(|e| TransportErrors::OpsFailed("recv_notification".to_string(), e.to_string()))
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if self.is_client {
+            self.notify(&Notification::complete()).await?;
+        }
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}